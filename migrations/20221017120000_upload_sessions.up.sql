@@ -0,0 +1,30 @@
+-- Resumable chunked uploads
+CREATE TABLE upload_sessions
+(
+    id                sonyflake    PRIMARY KEY  NOT NULL UNIQUE,
+    uploader          sonyflake                 NOT NULL,
+    -- Name the file will be stored as once finalized
+    name              VARCHAR(32)               NOT NULL UNIQUE,
+    original_name     VARCHAR(256)              NOT NULL,
+    size              BIGINT                    NOT NULL,
+    chunk_size        BIGINT                    NOT NULL,
+    -- Expected SHA-256 hash of the assembled file, if provided by the client
+    hash              VARCHAR(64),
+    -- Upload ID given by the storage provider
+    storage_upload_id VARCHAR(1024)             NOT NULL,
+    created           timestamptz               NOT NULL DEFAULT CURRENT_TIMESTAMP,
+
+    FOREIGN KEY (uploader) REFERENCES users (id) ON DELETE CASCADE
+);
+
+CREATE TABLE upload_chunks
+(
+    session_id  sonyflake      NOT NULL,
+    number      INTEGER        NOT NULL,
+    size        BIGINT         NOT NULL,
+    -- Provider specific identifier of the chunk (ETag for S3)
+    tag         VARCHAR(1024)  NOT NULL,
+
+    PRIMARY KEY (session_id, number),
+    FOREIGN KEY (session_id) REFERENCES upload_sessions (id) ON DELETE CASCADE
+);
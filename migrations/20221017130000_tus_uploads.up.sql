@@ -0,0 +1,16 @@
+-- Uploads made through the tus protocol
+CREATE TABLE tus_uploads
+(
+    id             sonyflake     PRIMARY KEY  NOT NULL UNIQUE,
+    uploader       sonyflake                  NOT NULL,
+    -- Name the file will be stored as once completed
+    name           VARCHAR(32)                NOT NULL UNIQUE,
+    original_name  VARCHAR(256)               NOT NULL,
+    length         BIGINT                     NOT NULL,
+    upload_offset  BIGINT                     NOT NULL DEFAULT 0,
+    -- Every PATCH request is stored as a numbered part until the upload completes
+    parts          INTEGER                    NOT NULL DEFAULT 0,
+    created        timestamptz                NOT NULL DEFAULT CURRENT_TIMESTAMP,
+
+    FOREIGN KEY (uploader) REFERENCES users (id) ON DELETE CASCADE
+);
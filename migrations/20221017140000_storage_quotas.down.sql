@@ -0,0 +1,2 @@
+ALTER TABLE users DROP COLUMN quota;
+ALTER TABLE settings DROP COLUMN default_quota;
\ No newline at end of file
@@ -0,0 +1,5 @@
+-- Quota in bytes, NULL uses the default quota from settings
+ALTER TABLE users ADD COLUMN quota BIGINT;
+
+-- Default quota in bytes for users without a quota, NULL is unlimited
+ALTER TABLE settings ADD COLUMN default_quota BIGINT;
@@ -0,0 +1,5 @@
+DROP INDEX files_expires_index;
+
+ALTER TABLE files DROP COLUMN expires;
+ALTER TABLE files DROP COLUMN max_downloads;
+ALTER TABLE files DROP COLUMN downloads;
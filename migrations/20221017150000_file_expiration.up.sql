@@ -0,0 +1,11 @@
+-- Time after which the file is deleted, NULL never expires
+ALTER TABLE files ADD COLUMN expires TIMESTAMPTZ;
+
+-- Amount of downloads after which the file is deleted, NULL is unlimited
+ALTER TABLE files ADD COLUMN max_downloads INTEGER;
+ALTER TABLE files ADD COLUMN downloads     INTEGER NOT NULL DEFAULT 0;
+
+-- The purge worker regularly looks for expired files
+CREATE INDEX files_expires_index
+    ON files (expires)
+    WHERE expires IS NOT NULL;
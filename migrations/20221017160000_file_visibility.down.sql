@@ -0,0 +1,4 @@
+ALTER TABLE files DROP COLUMN visibility;
+ALTER TABLE files DROP COLUMN password;
+
+DROP TYPE visibility;
@@ -0,0 +1,9 @@
+-- Visibility enum
+-- public and unlisted files can be read directly from the storage URL
+-- private and password files are only readable through the download route or a signed URL
+CREATE TYPE visibility AS ENUM ('public', 'unlisted', 'private', 'password');
+
+ALTER TABLE files ADD COLUMN visibility visibility   NOT NULL DEFAULT 'public'::visibility;
+
+-- Argon2 hash, only set when visibility is password
+ALTER TABLE files ADD COLUMN password   VARCHAR(128);
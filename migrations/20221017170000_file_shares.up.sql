@@ -0,0 +1,14 @@
+-- Signed links giving temporary access to a file
+-- Deleting the row revokes the link even if the signature is still valid
+CREATE TABLE file_shares
+(
+    id             sonyflake    PRIMARY KEY  NOT NULL UNIQUE,
+    file_id        sonyflake                 NOT NULL,
+    expires        timestamptz               NOT NULL,
+    -- NULL allows unlimited downloads until the link expires
+    max_downloads  INTEGER,
+    downloads      INTEGER                   NOT NULL DEFAULT 0,
+    created        timestamptz               NOT NULL DEFAULT CURRENT_TIMESTAMP,
+
+    FOREIGN KEY (file_id) REFERENCES files (id) ON DELETE CASCADE
+);
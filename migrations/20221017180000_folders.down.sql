@@ -0,0 +1,3 @@
+ALTER TABLE files DROP COLUMN folder;
+
+DROP TABLE folders;
@@ -0,0 +1,24 @@
+-- Folders can be nested, public folders are viewable as an album
+CREATE TABLE folders
+(
+    id       sonyflake    PRIMARY KEY  NOT NULL UNIQUE,
+    owner    sonyflake                 NOT NULL,
+    -- NULL is a top level folder
+    parent   VARCHAR(20),
+    name     VARCHAR(64)               NOT NULL,
+    public   BOOLEAN                   NOT NULL DEFAULT false,
+    created  timestamptz               NOT NULL DEFAULT CURRENT_TIMESTAMP,
+
+    FOREIGN KEY (owner) REFERENCES users (id) ON DELETE CASCADE,
+    FOREIGN KEY (parent) REFERENCES folders (id) ON DELETE CASCADE
+);
+
+-- Two folders with the same name can not be in the same folder
+CREATE UNIQUE INDEX folders_name_uindex
+    ON folders (owner, COALESCE(parent, ''), name);
+
+-- NULL is not in a folder, files are moved out when their folder is deleted
+ALTER TABLE files ADD COLUMN folder VARCHAR(20) REFERENCES folders (id) ON DELETE SET NULL;
+
+CREATE INDEX files_folder_index
+    ON files (folder);
@@ -0,0 +1,13 @@
+DROP INDEX files_search_index;
+
+DROP TRIGGER file_tags_search_update ON file_tags;
+DROP FUNCTION file_tags_search_trigger();
+
+DROP TRIGGER files_search_update ON files;
+DROP FUNCTION files_search_trigger();
+DROP FUNCTION file_search_vector(VARCHAR, VARCHAR);
+
+ALTER TABLE files DROP COLUMN mime_type;
+ALTER TABLE files DROP COLUMN search_vector;
+
+DROP TABLE file_tags;
@@ -0,0 +1,53 @@
+-- User defined tags on files
+CREATE TABLE file_tags
+(
+    file_id  sonyflake    NOT NULL,
+    tag      VARCHAR(32)  NOT NULL,
+
+    PRIMARY KEY (file_id, tag),
+    FOREIGN KEY (file_id) REFERENCES files (id) ON DELETE CASCADE
+);
+
+CREATE INDEX file_tags_tag_index
+    ON file_tags (tag);
+
+ALTER TABLE files ADD COLUMN mime_type     VARCHAR(255);
+ALTER TABLE files ADD COLUMN search_vector tsvector;
+
+-- Full text search over the original name and tags
+-- The simple configuration is used since names are rarely natural language
+CREATE FUNCTION file_search_vector(target VARCHAR, original_name VARCHAR) RETURNS tsvector AS $$
+    SELECT setweight(to_tsvector('simple', original_name), 'A') ||
+           setweight(to_tsvector('simple', COALESCE(string_agg(tag, ' '), '')), 'B')
+    FROM file_tags
+    WHERE file_id = target
+$$ LANGUAGE SQL STABLE;
+
+CREATE FUNCTION files_search_trigger() RETURNS trigger AS $$
+BEGIN
+    NEW.search_vector := file_search_vector(NEW.id, NEW.original_name);
+    RETURN NEW;
+END
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER files_search_update
+    BEFORE INSERT OR UPDATE OF original_name ON files
+    FOR EACH ROW EXECUTE PROCEDURE files_search_trigger();
+
+CREATE FUNCTION file_tags_search_trigger() RETURNS trigger AS $$
+DECLARE
+    target VARCHAR := CASE WHEN TG_OP = 'DELETE' THEN OLD.file_id ELSE NEW.file_id END;
+BEGIN
+    UPDATE files SET search_vector = file_search_vector(id, original_name) WHERE id = target;
+    RETURN NULL;
+END
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER file_tags_search_update
+    AFTER INSERT OR UPDATE OR DELETE ON file_tags
+    FOR EACH ROW EXECUTE PROCEDURE file_tags_search_trigger();
+
+UPDATE files SET search_vector = file_search_vector(id, original_name);
+
+CREATE INDEX files_search_index
+    ON files USING GIN (search_vector);
@@ -0,0 +1,3 @@
+ALTER TABLE files DROP COLUMN width;
+ALTER TABLE files DROP COLUMN height;
+ALTER TABLE files DROP COLUMN duration;
@@ -0,0 +1,6 @@
+-- Detected from the file contents, NULL if not an image or video
+ALTER TABLE files ADD COLUMN width    INTEGER;
+ALTER TABLE files ADD COLUMN height   INTEGER;
+
+-- Length in seconds, NULL if not audio or video
+ALTER TABLE files ADD COLUMN duration DOUBLE PRECISION;
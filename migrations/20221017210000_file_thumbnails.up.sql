@@ -0,0 +1,7 @@
+-- Thumbnails can fail or depend on external tools so whether one exists is stored
+ALTER TABLE files ADD COLUMN has_thumbnail BOOLEAN NOT NULL DEFAULT false;
+
+-- Images uploaded before this were always thumbnailed
+UPDATE files SET has_thumbnail = true
+    WHERE mime_type LIKE 'image/%'
+       OR (mime_type IS NULL AND name ~* '\.(png|jpe?g|gif|webp|jfif|pjpeg|pjp)$');
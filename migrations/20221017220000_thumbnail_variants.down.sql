@@ -0,0 +1,5 @@
+ALTER TABLE files ADD COLUMN has_thumbnail BOOLEAN NOT NULL DEFAULT false;
+UPDATE files SET has_thumbnail = true
+WHERE id IN (SELECT file_id FROM file_thumbnails WHERE size = 0);
+
+DROP TABLE file_thumbnails;
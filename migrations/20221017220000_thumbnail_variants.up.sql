@@ -0,0 +1,16 @@
+-- Every size and format a thumbnail was generated in
+CREATE TABLE file_thumbnails
+(
+    file_id  sonyflake   NOT NULL,
+    size     INTEGER     NOT NULL,
+    format   VARCHAR(8)  NOT NULL,
+
+    PRIMARY KEY (file_id, size, format),
+    FOREIGN KEY (file_id) REFERENCES files (id) ON DELETE CASCADE
+);
+
+-- Existing single PNG thumbnails stay at thumb/{name}, recorded with the size 0 until --generate-thumbnails replaces them
+INSERT INTO file_thumbnails (file_id, size, format)
+SELECT id, 0, 'png' FROM files WHERE has_thumbnail;
+
+ALTER TABLE files DROP COLUMN has_thumbnail;
@@ -0,0 +1,10 @@
+-- Transformed images cached in the storage provider so they are deleted with the file
+CREATE TABLE file_transforms
+(
+    file_id  sonyflake     NOT NULL,
+    name     VARCHAR(255)  NOT NULL,
+    created  TIMESTAMPTZ   NOT NULL DEFAULT now(),
+
+    PRIMARY KEY (file_id, name),
+    FOREIGN KEY (file_id) REFERENCES files (id) ON DELETE CASCADE
+);
@@ -0,0 +1,2 @@
+DROP TABLE file_daily_views;
+DROP TABLE file_views;
@@ -0,0 +1,29 @@
+-- Every time a file was served, removed after the retention period
+CREATE TABLE file_views
+(
+    file_id     sonyflake     NOT NULL,
+    viewed      TIMESTAMPTZ   NOT NULL DEFAULT now(),
+    referrer    VARCHAR(255),
+    user_agent  VARCHAR(16)   NOT NULL,
+    bytes       BIGINT        NOT NULL,
+
+    FOREIGN KEY (file_id) REFERENCES files (id) ON DELETE CASCADE
+);
+
+CREATE INDEX file_views_file_viewed_index
+    ON file_views (file_id, viewed);
+
+CREATE INDEX file_views_viewed_index
+    ON file_views (viewed);
+
+-- Views aggregated per day, kept for as long as the file exists
+CREATE TABLE file_daily_views
+(
+    file_id  sonyflake  NOT NULL,
+    day      DATE       NOT NULL,
+    views    INTEGER    NOT NULL DEFAULT 0,
+    bytes    BIGINT     NOT NULL DEFAULT 0,
+
+    PRIMARY KEY (file_id, day),
+    FOREIGN KEY (file_id) REFERENCES files (id) ON DELETE CASCADE
+);
@@ -0,0 +1,6 @@
+DROP TRIGGER files_blob_refs ON files;
+DROP FUNCTION files_blob_refs_trigger();
+
+ALTER TABLE files DROP COLUMN object;
+
+DROP TABLE blobs;
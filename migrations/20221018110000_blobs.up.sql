@@ -0,0 +1,47 @@
+-- Stored objects shared by every file with the same contents
+CREATE TABLE blobs
+(
+    -- Storage provider key
+    name     VARCHAR(255)  PRIMARY KEY  NOT NULL,
+    hash     VARCHAR(64)                NOT NULL,
+    size     BIGINT                     NOT NULL,
+
+    -- Files pointing at the blob, maintained by a trigger on files
+    refs     INTEGER                    NOT NULL DEFAULT 0,
+    created  TIMESTAMPTZ                NOT NULL DEFAULT now()
+);
+
+CREATE INDEX blobs_hash_index
+    ON blobs (hash);
+
+-- Every existing file has its own object
+INSERT INTO blobs (name, hash, size, refs, created)
+    SELECT name, hash, size, 1, uploaded FROM files;
+
+ALTER TABLE files ADD COLUMN object VARCHAR(255);
+UPDATE files SET object = name;
+ALTER TABLE files ALTER COLUMN object SET NOT NULL;
+
+-- A blob can't be removed while a file points at it
+ALTER TABLE files ADD FOREIGN KEY (object) REFERENCES blobs (name);
+
+CREATE INDEX files_object_index
+    ON files (object);
+
+CREATE FUNCTION files_blob_refs_trigger() RETURNS trigger AS $$
+BEGIN
+    IF TG_OP IN ('UPDATE', 'DELETE') THEN
+        UPDATE blobs SET refs = refs - 1 WHERE name = OLD.object;
+    END IF;
+
+    IF TG_OP IN ('INSERT', 'UPDATE') THEN
+        UPDATE blobs SET refs = refs + 1 WHERE name = NEW.object;
+    END IF;
+
+    RETURN NULL;
+END
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER files_blob_refs
+    AFTER INSERT OR DELETE OR UPDATE OF object ON files
+    FOR EACH ROW EXECUTE FUNCTION files_blob_refs_trigger();
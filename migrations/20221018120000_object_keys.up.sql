@@ -0,0 +1,12 @@
+-- Data keys of objects encrypted at rest, wrapped by the instance master key
+-- Every object is written with its own key, objects without one are stored in plaintext
+CREATE TABLE object_keys
+(
+    -- Storage provider key
+    name        VARCHAR(255) NOT NULL PRIMARY KEY,
+    wrapped_key BYTEA        NOT NULL,
+
+    -- Random prefix of the nonce of every encrypted segment
+    nonce       BYTEA        NOT NULL
+);
+
@@ -0,0 +1,15 @@
+-- Client encrypted files can't be kept without the column, they have to be deleted first
+DO $$
+BEGIN
+    IF EXISTS (SELECT 1 FROM files WHERE client_encrypted) THEN
+        RAISE EXCEPTION 'Client encrypted files exist, delete them before reverting';
+    END IF;
+END
+$$;
+
+DROP INDEX files_user_hash_uindex;
+CREATE UNIQUE INDEX files_user_hash_uindex
+    ON files (uploader, hash);
+
+ALTER TABLE files DROP COLUMN client_metadata;
+ALTER TABLE files DROP COLUMN client_encrypted;
@@ -0,0 +1,11 @@
+-- Contents were encrypted by the client, the server only ever sees ciphertext
+ALTER TABLE files ADD COLUMN client_encrypted BOOLEAN NOT NULL DEFAULT false;
+
+-- Opaque metadata sent by the client along with the ciphertext, such as the encrypted file name
+ALTER TABLE files ADD COLUMN client_metadata TEXT;
+
+-- Encrypted uploads are never deduplicated
+DROP INDEX files_user_hash_uindex;
+CREATE UNIQUE INDEX files_user_hash_uindex
+    ON files (uploader, hash)
+    WHERE NOT client_encrypted;
@@ -0,0 +1,2 @@
+-- Contents are missing from storage or don't match the hash, found by a consistency check
+ALTER TABLE files ADD COLUMN broken BOOLEAN NOT NULL DEFAULT false;
@@ -0,0 +1,3 @@
+DROP TABLE object_replicas;
+
+DROP TYPE replica_operation;
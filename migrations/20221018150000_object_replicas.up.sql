@@ -0,0 +1,22 @@
+CREATE TYPE replica_operation AS ENUM ('write', 'delete');
+
+-- Operations a replica of a mirrored storage provider missed, removed once they were resynced
+CREATE TABLE object_replicas
+(
+    -- Storage provider key
+    object     VARCHAR(255)       NOT NULL,
+
+    -- Name of the replica in MIRROR_REPLICAS
+    replica    VARCHAR(64)        NOT NULL,
+    operation  replica_operation  NOT NULL,
+
+    -- Last error of the replica, from the operation or a failed resync
+    error      TEXT               NOT NULL,
+    attempts   INTEGER            NOT NULL DEFAULT 0,
+    updated    TIMESTAMPTZ        NOT NULL DEFAULT now(),
+
+    PRIMARY KEY (object, replica)
+);
+
+CREATE INDEX object_replicas_updated_index
+    ON object_replicas (updated);
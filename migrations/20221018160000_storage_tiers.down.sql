@@ -0,0 +1,4 @@
+ALTER TABLE files DROP COLUMN last_accessed;
+ALTER TABLE files DROP COLUMN tier;
+
+DROP TYPE storage_tier;
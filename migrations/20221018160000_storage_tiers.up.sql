@@ -0,0 +1,7 @@
+CREATE TYPE storage_tier AS ENUM ('hot', 'cold');
+
+-- Storage tier holding the object, files sharing an object are moved together
+ALTER TABLE files ADD COLUMN tier          storage_tier  NOT NULL DEFAULT 'hot'::storage_tier;
+
+-- Last read through the application, reads directly from the storage URL are not known
+ALTER TABLE files ADD COLUMN last_accessed TIMESTAMPTZ;
@@ -7,6 +7,8 @@ use std::{
     str::FromStr,
 };
 
+use crate::{storage::encrypted::KEY_SIZE, util::thumbnail::ThumbnailFormat};
+
 #[derive(Clone)]
 pub struct Config {
     pub port: u16,
@@ -19,6 +21,27 @@ pub struct Config {
     pub storage_provider: StorageConfig,
     pub smtp_config: Option<SMTPConfig>,
     pub invite_only: bool,
+
+    // Seconds between runs of the expired file purge worker
+    pub purge_interval: u64,
+
+    // Largest side of each thumbnail variant in pixels
+    pub thumbnail_sizes: Vec<u32>,
+
+    // Preferred thumbnail format, PNG is always generated as a fallback
+    pub thumbnail_format: ThumbnailFormat,
+
+    // Remove image metadata on upload unless the upload opts out
+    pub strip_metadata: bool,
+
+    // Days single views are kept for analytics breakdowns, daily counters are kept forever
+    pub analytics_retention: i32,
+
+    // Master key wrapping the data keys of objects encrypted at rest, objects are stored in plaintext without it
+    pub encryption_key: Option<Vec<u8>>,
+
+    // Provider rarely read files are moved to, every file stays on the storage provider without it
+    pub tiering: Option<TieringConfig>,
 }
 
 #[derive(Clone)]
@@ -38,6 +61,36 @@ pub struct LocalConfig {
     pub serve: bool,
 }
 
+#[derive(Clone)]
+pub struct WebDavConfig {
+    pub url: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+}
+
+#[derive(Clone)]
+pub struct MirrorConfig {
+    // Replica names with their providers, reads try them in this order
+    pub replicas: Vec<(String, StorageConfig)>,
+
+    // Replicas which must complete a write for it to succeed
+    pub write_quorum: usize,
+}
+
+#[derive(Clone)]
+pub struct TieringConfig {
+    pub cold_storage: StorageConfig,
+
+    // Files are moved to cold storage once they are this many days old
+    pub min_age_days: i32,
+
+    // Files read within this many days stay hot, reading a cold file moves it back
+    pub idle_days: i32,
+
+    // Seconds between runs of the tiering worker
+    pub interval: u64,
+}
+
 #[derive(Clone)]
 pub struct SMTPConfig {
     pub username: String,
@@ -48,7 +101,83 @@ pub struct SMTPConfig {
 #[derive(Clone)]
 pub enum StorageConfig {
     Local(LocalConfig),
+    // Local directory with objects spread over nested directories
+    Sharded(LocalConfig),
     S3(S3Config),
+    WebDav(WebDavConfig),
+    // Objects are lost on restart
+    Memory,
+    // Every object is written to several providers
+    Mirrored(MirrorConfig),
+}
+
+impl StorageConfig {
+    /// Read a storage provider from environment variables starting with `prefix`
+    pub fn from_env(prefix: &str) -> Self {
+        match get_env::<String>(&format!("{}STORAGE_PROVIDER", prefix)).as_str() {
+            "local" => StorageConfig::Local(LocalConfig {
+                path: Path::new(get_env::<String>(&format!("{}LOCAL_PATH", prefix)).as_str())
+                    .to_path_buf(),
+                serve: get_env_or(&format!("{}LOCAL_SERVE", prefix), true),
+            }),
+            "sharded" => StorageConfig::Sharded(LocalConfig {
+                path: Path::new(get_env::<String>(&format!("{}LOCAL_PATH", prefix)).as_str())
+                    .to_path_buf(),
+                serve: get_env_or(&format!("{}LOCAL_SERVE", prefix), true),
+            }),
+            "s3" => StorageConfig::S3(S3Config {
+                bucket: get_env(&format!("{}S3_BUCKET", prefix)),
+                access_key: get_env(&format!("{}S3_ACCESS_KEY", prefix)),
+                secret_key: get_env(&format!("{}S3_SECRET_KEY", prefix)),
+                region: Region::Custom {
+                    name: get_env(&format!("{}S3_REGION", prefix)),
+                    endpoint: get_env(&format!("{}S3_ENDPOINT", prefix)),
+                },
+            }),
+            "webdav" => StorageConfig::WebDav(WebDavConfig {
+                url: get_env(&format!("{}WEBDAV_URL", prefix)),
+                username: env::var(&format!("{}WEBDAV_USERNAME", prefix)).ok(),
+                password: env::var(&format!("{}WEBDAV_PASSWORD", prefix)).ok(),
+            }),
+            "memory" => StorageConfig::Memory,
+            // Replicas are configured by variables starting with their name, PRIMARY_STORAGE_PROVIDER etc.
+            "mirrored" => {
+                let replicas: Vec<(String, StorageConfig)> =
+                    get_env::<String>(&format!("{}MIRROR_REPLICAS", prefix))
+                        .split(',')
+                        .map(|name| {
+                            let name = name.trim().to_uppercase();
+                            let replica = StorageConfig::from_env(&format!("{}{}_", prefix, name));
+                            if let StorageConfig::Mirrored(_) = replica {
+                                panic!("Replica {} can't be mirrored itself", name);
+                            }
+
+                            (name, replica)
+                        })
+                        .collect();
+
+                let write_quorum =
+                    get_env_or(&format!("{}MIRROR_WRITE_QUORUM", prefix), replicas.len());
+                if write_quorum == 0 || write_quorum > replicas.len() {
+                    panic!(
+                        "{}MIRROR_WRITE_QUORUM must be between 1 and the number of replicas",
+                        prefix
+                    );
+                }
+
+                StorageConfig::Mirrored(MirrorConfig {
+                    replicas,
+                    write_quorum,
+                })
+            }
+            _ => {
+                panic!(
+                    "Invalid storage provider for environment variable {}STORAGE_PROVIDER",
+                    prefix
+                )
+            }
+        }
+    }
 }
 
 impl Config {
@@ -63,25 +192,41 @@ impl Config {
             file_size_limit: get_env_or("FILE_SIZE_LIMIT", 100),
             worker_id: get_env::<u16>("WORKER_ID"),
             invite_only: get_env_or("INVITE_ONLY", false),
-            storage_provider: {
-                match get_env::<String>("STORAGE_PROVIDER").as_str() {
-                    "local" => StorageConfig::Local(LocalConfig {
-                        path: Path::new(get_env::<String>("LOCAL_PATH").as_str()).to_path_buf(),
-                        serve: get_env_or("LOCAL_SERVE", true),
-                    }),
-                    "s3" => StorageConfig::S3(S3Config {
-                        bucket: get_env("S3_BUCKET"),
-                        access_key: get_env("S3_ACCESS_KEY"),
-                        secret_key: get_env("S3_SECRET_KEY"),
-                        region: Region::Custom {
-                            name: get_env("S3_REGION"),
-                            endpoint: get_env("S3_ENDPOINT"),
-                        },
-                    }),
+            purge_interval: get_env_or("PURGE_INTERVAL", 60),
+            thumbnail_sizes: get_env_or("THUMBNAIL_SIZES", "128,500,1600".to_string())
+                .split(',')
+                .map(|size| match size.trim().parse::<u32>() {
+                    Ok(size) if (16..=4096).contains(&size) => size,
                     _ => {
-                        panic!("Invalid storage provider for environment variable STORAGE_PROVIDER")
+                        log::error!(
+                            "THUMBNAIL_SIZES must be comma separated sizes between 16 and 4096 pixels, `{}` is not one",
+                            size.trim()
+                        );
+                        std::process::exit(1);
                     }
+                })
+                .collect(),
+            thumbnail_format: get_env_or("THUMBNAIL_FORMAT", ThumbnailFormat::Webp),
+            strip_metadata: get_env_or("STRIP_METADATA", false),
+            analytics_retention: get_env_or("ANALYTICS_RETENTION", 90),
+            encryption_key: env::var("ENCRYPTION_KEY").ok().map(|key| {
+                match base64::decode(key.trim()) {
+                    Ok(key) if key.len() == KEY_SIZE => key,
+                    _ => panic!(
+                        "ENCRYPTION_KEY must be {} bytes encoded as base64",
+                        KEY_SIZE
+                    ),
                 }
+            }),
+            storage_provider: StorageConfig::from_env(""),
+            tiering: match env::var("COLD_STORAGE_PROVIDER") {
+                Ok(_) => Some(TieringConfig {
+                    cold_storage: StorageConfig::from_env("COLD_"),
+                    min_age_days: get_env_or("TIER_MIN_AGE_DAYS", 30),
+                    idle_days: get_env_or("TIER_IDLE_DAYS", 7),
+                    interval: get_env_or("TIER_INTERVAL", 60 * 60),
+                }),
+                Err(_) => None,
             },
             smtp_config: {
                 match get_env_or("SMTP_ENABLED", false) {
@@ -2,7 +2,10 @@
 
 use sea_orm::{entity::prelude::*, Set};
 
-use super::DB_SONYFLAKE;
+use super::{
+    sea_orm_active_enums::{StorageTier, Visibility},
+    DB_SONYFLAKE,
+};
 
 #[derive(Clone, Debug, PartialEq, DeriveEntityModel)]
 #[sea_orm(table_name = "files")]
@@ -16,10 +19,36 @@ pub struct Model {
     pub hash: String,
     pub uploaded: DateTimeWithTimeZone,
     pub size: i64,
+    pub expires: Option<DateTimeWithTimeZone>,
+    pub max_downloads: Option<i32>,
+    pub downloads: i32,
+    pub visibility: Visibility,
+    pub password: Option<String>,
+    pub folder: Option<String>,
+    pub mime_type: Option<String>,
+    pub width: Option<i32>,
+    pub height: Option<i32>,
+    #[sea_orm(column_type = "Double", nullable)]
+    pub duration: Option<f64>,
+    pub object: String,
+    pub client_encrypted: bool,
+    #[sea_orm(column_type = "Text", nullable)]
+    pub client_metadata: Option<String>,
+    pub broken: bool,
+    pub tier: StorageTier,
+    pub last_accessed: Option<DateTimeWithTimeZone>,
 }
 
 #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
 pub enum Relation {
+    #[sea_orm(
+        belongs_to = "super::blobs::Entity",
+        from = "Column::Object",
+        to = "super::blobs::Column::Name",
+        on_update = "NoAction",
+        on_delete = "NoAction"
+    )]
+    Blobs,
     #[sea_orm(
         belongs_to = "super::users::Entity",
         from = "Column::Uploader",
@@ -28,6 +57,28 @@ pub enum Relation {
         on_delete = "Cascade"
     )]
     Users,
+    #[sea_orm(has_many = "super::file_shares::Entity")]
+    FileShares,
+    #[sea_orm(has_many = "super::file_tags::Entity")]
+    FileTags,
+    #[sea_orm(has_many = "super::file_thumbnails::Entity")]
+    FileThumbnails,
+    #[sea_orm(has_many = "super::file_transforms::Entity")]
+    FileTransforms,
+    #[sea_orm(
+        belongs_to = "super::folders::Entity",
+        from = "Column::Folder",
+        to = "super::folders::Column::Id",
+        on_update = "NoAction",
+        on_delete = "SetNull"
+    )]
+    Folders,
+}
+
+impl Related<super::blobs::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::Blobs.def()
+    }
 }
 
 impl Related<super::users::Entity> for Entity {
@@ -36,6 +87,36 @@ impl Related<super::users::Entity> for Entity {
     }
 }
 
+impl Related<super::file_shares::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::FileShares.def()
+    }
+}
+
+impl Related<super::file_tags::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::FileTags.def()
+    }
+}
+
+impl Related<super::file_thumbnails::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::FileThumbnails.def()
+    }
+}
+
+impl Related<super::file_transforms::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::FileTransforms.def()
+    }
+}
+
+impl Related<super::folders::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::Folders.def()
+    }
+}
+
 impl ActiveModelBehavior for ActiveModel {
     fn new() -> Self {
         Self {
@@ -5,10 +5,21 @@ use crate::database::sonyflake::Sonyflake;
 pub mod prelude;
 
 pub mod applications;
+pub mod blobs;
+pub mod file_shares;
+pub mod file_tags;
+pub mod file_thumbnails;
+pub mod file_transforms;
 pub mod files;
+pub mod folders;
+pub mod object_keys;
+pub mod object_replicas;
 pub mod registration_keys;
 pub mod sea_orm_active_enums;
 pub mod settings;
+pub mod tus_uploads;
+pub mod upload_chunks;
+pub mod upload_sessions;
 pub mod users;
 pub mod verifications;
 
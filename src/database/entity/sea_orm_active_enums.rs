@@ -3,6 +3,16 @@
 use sea_orm::entity::prelude::*;
 use serde::Serialize;
 
+#[derive(Debug, Clone, PartialEq, EnumIter, DeriveActiveEnum, Serialize)]
+#[sea_orm(rs_type = "String", db_type = "Enum", enum_name = "replica_operation")]
+#[serde(rename_all = "lowercase")]
+pub enum ReplicaOperation {
+    #[sea_orm(string_value = "delete")]
+    Delete,
+    #[sea_orm(string_value = "write")]
+    Write,
+}
+
 #[derive(Debug, Clone, PartialEq, EnumIter, DeriveActiveEnum)]
 #[sea_orm(rs_type = "String", db_type = "Enum", enum_name = "role")]
 pub enum Role {
@@ -12,6 +22,16 @@ pub enum Role {
     User,
 }
 
+#[derive(Debug, Clone, PartialEq, EnumIter, DeriveActiveEnum, Serialize)]
+#[sea_orm(rs_type = "String", db_type = "Enum", enum_name = "storage_tier")]
+#[serde(rename_all = "lowercase")]
+pub enum StorageTier {
+    #[sea_orm(string_value = "cold")]
+    Cold,
+    #[sea_orm(string_value = "hot")]
+    Hot,
+}
+
 #[derive(Debug, Clone, PartialEq, EnumIter, DeriveActiveEnum, Serialize)]
 #[sea_orm(rs_type = "String", db_type = "Enum", enum_name = "theme_color")]
 pub enum ThemeColor {
@@ -36,3 +56,16 @@ pub enum ThemeColor {
     #[sea_orm(string_value = "yellow")]
     Yellow,
 }
+
+#[derive(Debug, Clone, PartialEq, EnumIter, DeriveActiveEnum)]
+#[sea_orm(rs_type = "String", db_type = "Enum", enum_name = "visibility")]
+pub enum Visibility {
+    #[sea_orm(string_value = "password")]
+    Password,
+    #[sea_orm(string_value = "private")]
+    Private,
+    #[sea_orm(string_value = "public")]
+    Public,
+    #[sea_orm(string_value = "unlisted")]
+    Unlisted,
+}
@@ -12,6 +12,7 @@ pub struct Model {
     #[sea_orm(column_type = "Text")]
     pub app_description: String,
     pub color: ThemeColor,
+    pub default_quota: Option<i64>,
 }
 
 #[derive(Copy, Clone, Debug, EnumIter)]
@@ -17,6 +17,7 @@ pub struct Model {
     pub password: String,
     pub verified: bool,
     pub role: Role,
+    pub quota: Option<i64>,
 }
 
 #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
@@ -27,6 +28,12 @@ pub enum Relation {
     Verifications,
     #[sea_orm(has_many = "super::files::Entity")]
     Files,
+    #[sea_orm(has_many = "super::upload_sessions::Entity")]
+    UploadSessions,
+    #[sea_orm(has_many = "super::tus_uploads::Entity")]
+    TusUploads,
+    #[sea_orm(has_many = "super::folders::Entity")]
+    Folders,
 }
 
 impl Related<super::applications::Entity> for Entity {
@@ -47,6 +54,24 @@ impl Related<super::files::Entity> for Entity {
     }
 }
 
+impl Related<super::upload_sessions::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::UploadSessions.def()
+    }
+}
+
+impl Related<super::tus_uploads::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::TusUploads.def()
+    }
+}
+
+impl Related<super::folders::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::Folders.def()
+    }
+}
+
 impl ActiveModelBehavior for ActiveModel {
     fn new() -> Self {
         Self {
@@ -1,19 +1,38 @@
-use crate::{database::entity::files, util::GIT_VERSION};
+use crate::{
+    database::entity::{file_shares, files, object_keys},
+    util::GIT_VERSION,
+};
 use actix_http::Uri;
+use chrono::Utc;
 use clap::Parser;
 use colored::*;
-use config::StorageConfig;
+use config::{StorageConfig, TieringConfig};
 use figlet_rs::FIGfont;
 use indicatif::{ProgressBar, ProgressStyle};
-use models::MessageResponse;
-use sea_orm::{ConnectOptions, Database, EntityTrait};
+use models::{admin::fsck::FsckParams, MessageResponse};
+use sea_orm::{
+    sea_query::Expr, ColumnTrait, ConnectOptions, Database, DatabaseConnection, EntityTrait,
+    QueryFilter,
+};
 use sqlx::{migrate::Migrator, postgres::PgPoolOptions};
 use state::State;
 use tokio::fs;
 
-use util::file::IMAGE_EXTS;
+use util::{
+    analytics::prune_views,
+    blob::{collect_blobs, recover_pending_uploads},
+    encryption::DatabaseKeys,
+    expiry::{expire_upload_sessions, expired, purge_file},
+    fsck::check_storage,
+    metadata::{detect_metadata, mime_from_name},
+    migrate::{copy_object, orphaned_blobs, referenced_objects, CopyOutcome, MigratedObject},
+    replicas::{resync_replicas, ReplicaStatus},
+    serve::serve_local_object,
+    thumbnail::{regenerate_thumbnails, supports_thumbnail},
+    tiering::tier_objects,
+};
 
-use std::{convert::TryInto, ffi::OsStr, path::Path, time::Duration};
+use std::{collections::HashSet, convert::TryInto, path::Path, time::Duration};
 
 use actix_web::{
     http::StatusCode,
@@ -22,11 +41,20 @@ use actix_web::{
     App, HttpRequest, HttpServer,
 };
 
-use actix_files::NamedFile;
-
 use lettre::{transport::smtp::authentication::Credentials, AsyncSmtpTransport, Tokio1Executor};
 
-use storage::{local::LocalProvider, s3::S3Provider, StorageProvider};
+use storage::{
+    conformance::check_provider,
+    encrypted::EncryptedProvider,
+    local::LocalProvider,
+    memory::MemoryProvider,
+    mirrored::{MirroredProvider, Replica},
+    s3::S3Provider,
+    sharded::ShardedLocalProvider,
+    tiered::TieredProvider,
+    webdav::WebDavProvider,
+    StorageProvider,
+};
 
 #[macro_use]
 extern crate lazy_static;
@@ -45,9 +73,38 @@ mod util;
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
 struct Args {
-    /// Regenerate image thumbnails
+    /// Regenerate thumbnails of images, videos and PDFs
     #[clap(short, long, takes_value = false)]
     generate_thumbnails: bool,
+
+    /// Detect the type, dimensions and duration of files again from their contents
+    #[clap(long, takes_value = false)]
+    detect_metadata: bool,
+
+    /// Encrypt objects which were stored before encryption at rest was enabled
+    #[clap(long, takes_value = false)]
+    encrypt_objects: bool,
+
+    /// Copy every object to the storage provider configured by MIGRATE_ prefixed variables,
+    /// such as MIGRATE_STORAGE_PROVIDER. Objects which were already copied are skipped.
+    #[clap(long, takes_value = false)]
+    migrate_storage: bool,
+
+    /// Compare the objects on the storage provider with the database and report inconsistencies
+    #[clap(long, takes_value = false)]
+    fsck: bool,
+
+    /// Also read every object to verify its hash, used with --fsck
+    #[clap(long, takes_value = false)]
+    verify_hashes: bool,
+
+    /// Delete orphaned objects, regenerate missing thumbnails and flag broken files, used with --fsck
+    #[clap(long, takes_value = false)]
+    repair: bool,
+
+    /// Check that the configured storage provider reads and writes objects correctly
+    #[clap(long, takes_value = false)]
+    check_storage: bool,
 }
 
 #[actix_web::main]
@@ -94,33 +151,21 @@ async fn main() -> std::io::Result<()> {
     let database = Database::connect(opt).await.unwrap();
 
     log::info!("Connected to the database");
-    let storage: Box<dyn StorageProvider> = match &config.storage_provider {
-        StorageConfig::Local(v) => {
-            if !v.path.exists() {
-                fs::create_dir(&v.path).await.expect(&format!(
-                    "Unable to create {} directory",
-                    v.path.to_str().unwrap_or("storage")
-                ));
-            }
-
-            // Thumbnail directory
-            let mut thumb_path = v.path.clone();
-            thumb_path.push("thumb");
-
-            if !thumb_path.exists() {
-                fs::create_dir(&thumb_path)
-                    .await
-                    .expect("Unable to create thumbnail directory");
-            }
-
-            Box::new(LocalProvider::new(v.path.clone()))
-        }
-        StorageConfig::S3(v) => Box::new(S3Provider::new(
-            &v.bucket,
-            &v.access_key,
-            &v.secret_key,
-            v.region.clone(),
+    let storage = create_tiered_storage(
+        &config.storage_provider,
+        config.tiering.as_ref().map(|tiering| &tiering.cold_storage),
+        &database,
+    )
+    .await;
+
+    // Objects are encrypted before they reach the configured provider
+    let storage: Box<dyn StorageProvider> = match &config.encryption_key {
+        Some(key) => Box::new(EncryptedProvider::new(
+            storage,
+            key,
+            Box::new(DatabaseKeys::new(database.clone())),
         )),
+        None => storage,
     };
 
     let smtp_client = match config.smtp_config {
@@ -152,6 +197,10 @@ async fn main() -> std::io::Result<()> {
         // Convert MB to bytes
         file_size_limit: config.file_size_limit * 1000 * 1000,
         invite_only: config.invite_only,
+        thumbnail_sizes: config.thumbnail_sizes,
+        thumbnail_format: config.thumbnail_format,
+        strip_metadata: config.strip_metadata,
+        encrypted_at_rest: config.encryption_key.is_some(),
     });
 
     // If the generate thumbnails flag is enabled
@@ -160,8 +209,72 @@ async fn main() -> std::io::Result<()> {
         return Ok(());
     }
 
-    let storage_path = match &config.storage_provider {
-        StorageConfig::Local(v) => {
+    if args.detect_metadata {
+        detect_file_metadata(&api_state).await.unwrap();
+        return Ok(());
+    }
+
+    if args.encrypt_objects {
+        if config.encryption_key.is_none() {
+            panic!("ENCRYPTION_KEY must be set to encrypt objects");
+        }
+
+        encrypt_objects(&api_state).await.unwrap();
+        return Ok(());
+    }
+
+    if args.migrate_storage {
+        let destination = StorageConfig::from_env("MIGRATE_");
+        migrate_storage(
+            &api_state,
+            &config.storage_provider,
+            config.tiering.as_ref().map(|tiering| &tiering.cold_storage),
+            &destination,
+            config.encryption_key.as_deref(),
+        )
+        .await
+        .unwrap();
+        return Ok(());
+    }
+
+    if args.fsck {
+        let params = FsckParams {
+            verify_hashes: args.verify_hashes,
+            repair: args.repair,
+        };
+
+        fsck(&api_state, &params).await.unwrap();
+        return Ok(());
+    }
+
+    if args.check_storage {
+        match check_provider(api_state.storage.as_ref()).await {
+            Ok(_) => log::info!("Storage provider passed every check"),
+            Err(err) => log::error!("Storage provider check failed: {}", err),
+        }
+
+        return Ok(());
+    }
+
+    // Expired files are unavailable immediately, this only cleans them up
+    tokio::spawn(purge_expired_files(
+        api_state.clone(),
+        Duration::from_secs(config.purge_interval),
+        config.analytics_retention,
+    ));
+
+    if let Some(tiering) = config.tiering.clone() {
+        tokio::spawn(tier_files(api_state.clone(), tiering));
+    }
+
+    // Mirrored objects are served from the first replica when it is a local directory
+    let served = match &config.storage_provider {
+        StorageConfig::Mirrored(v) => &v.replicas[0].1,
+        v => v,
+    };
+    let sharded = matches!(served, StorageConfig::Sharded(_));
+    let storage_path = match served {
+        StorageConfig::Local(v) | StorageConfig::Sharded(v) => {
             if v.serve {
                 Some(v.path.clone())
             } else {
@@ -187,6 +300,10 @@ async fn main() -> std::io::Result<()> {
                     .service(routes::auth::get_routes())
                     .service(routes::application::get_routes())
                     .service(routes::file::get_routes())
+                    .service(routes::folder::get_routes())
+                    .service(routes::upload::get_routes())
+                    .service(routes::tus::get_routes())
+                    .service(routes::share::get_routes())
                     .service(routes::admin::get_routes(invite_only))
                     .service(routes::get_routes()),
             )
@@ -194,23 +311,17 @@ async fn main() -> std::io::Result<()> {
             .app_data(web::JsonConfig::default().error_handler(|_, _| {
                 actix_web::Error::from(models::MessageResponse::bad_request())
             }))
-            .default_service(web::to(move |req: HttpRequest| {
+            .default_service(web::to(move |req: HttpRequest, state: Data<State>| {
                 let storage_path = base_storage_path.clone();
                 async move {
                     if let Some(v) = &storage_path {
-                        let mut file_path = v.clone();
-
                         // Request path after the root
                         let path_end = req.path().trim_start_matches('/');
 
                         // Make sure request path isn't empty
                         // This would attempt to send the directory (and fail) otherwise
                         if !path_end.eq("") {
-                            // Sanitize the path to prevent walking to another directory
-                            file_path.push(path_end.replace("..", ""));
-                            if let Ok(v) = NamedFile::open(&file_path) {
-                                return v.into_response(&req);
-                            }
+                            return serve_local_object(&req, &state, v, sharded, path_end).await;
                         }
                     }
 
@@ -224,32 +335,214 @@ async fn main() -> std::io::Result<()> {
     .await
 }
 
+/// Create the storage provider for a configuration, local directories are created if missing
+async fn create_storage(
+    config: &StorageConfig,
+    database: &DatabaseConnection,
+) -> Box<dyn StorageProvider> {
+    match config {
+        StorageConfig::Mirrored(v) => {
+            let mut replicas = Vec::new();
+            for (name, replica) in &v.replicas {
+                replicas.push(Replica {
+                    name: name.to_owned(),
+                    provider: create_replica(replica).await,
+                });
+            }
+
+            Box::new(MirroredProvider::new(
+                replicas,
+                v.write_quorum,
+                Box::new(ReplicaStatus::new(database.clone())),
+            ))
+        }
+        _ => create_replica(config).await,
+    }
+}
+
+/// Create the storage provider with a cold tier if one is configured, reads fall back to it
+async fn create_tiered_storage(
+    config: &StorageConfig,
+    cold: Option<&StorageConfig>,
+    database: &DatabaseConnection,
+) -> Box<dyn StorageProvider> {
+    let hot = create_storage(config, database).await;
+
+    match cold {
+        Some(cold) => Box::new(TieredProvider::new(
+            hot,
+            create_storage(cold, database).await,
+        )),
+        None => hot,
+    }
+}
+
+/// Create a storage provider which is not mirrored itself
+async fn create_replica(config: &StorageConfig) -> Box<dyn StorageProvider> {
+    match config {
+        StorageConfig::Local(v) | StorageConfig::Sharded(v) => {
+            if !v.path.exists() {
+                fs::create_dir(&v.path).await.expect(&format!(
+                    "Unable to create {} directory",
+                    v.path.to_str().unwrap_or("storage")
+                ));
+            }
+
+            // Thumbnail directory
+            let mut thumb_path = v.path.clone();
+            thumb_path.push("thumb");
+
+            if !thumb_path.exists() {
+                fs::create_dir(&thumb_path)
+                    .await
+                    .expect("Unable to create thumbnail directory");
+            }
+
+            match config {
+                StorageConfig::Sharded(_) => Box::new(ShardedLocalProvider::new(v.path.clone())),
+                _ => Box::new(LocalProvider::new(v.path.clone())),
+            }
+        }
+        StorageConfig::S3(v) => Box::new(S3Provider::new(
+            &v.bucket,
+            &v.access_key,
+            &v.secret_key,
+            v.region.clone(),
+        )),
+        StorageConfig::WebDav(v) => Box::new(WebDavProvider::new(
+            &v.url,
+            v.username.clone(),
+            v.password.clone(),
+        )),
+        StorageConfig::Memory => Box::new(MemoryProvider::new()),
+        StorageConfig::Mirrored(_) => panic!("Replicas can't be mirrored themselves"),
+    }
+}
+
+/// Periodically delete files which expired by time or download count and expired share links
+async fn purge_expired_files(state: Data<State>, period: Duration, analytics_retention: i32) {
+    let mut interval = tokio::time::interval(period);
+
+    loop {
+        interval.tick().await;
+
+        // Expired share links can no longer be used
+        if let Err(err) = file_shares::Entity::delete_many()
+            .filter(file_shares::Column::Expires.lte(Utc::now()))
+            .exec(&state.database)
+            .await
+        {
+            log::error!("Error deleting expired share links: {}", err);
+        }
+
+        if let Err(err) = prune_views(&state.database, analytics_retention).await {
+            log::error!("Error pruning old views: {}", err);
+        }
+
+        let expired_files = match files::Entity::find()
+            .filter(expired())
+            .all(&state.database)
+            .await
+        {
+            Ok(v) => v,
+            Err(err) => {
+                log::error!("Error finding expired files: {}", err);
+                continue;
+            }
+        };
+
+        for file in expired_files {
+            let name = file.name.to_owned();
+            match purge_file(&state, file).await {
+                Ok(_) => log::info!("Purged expired file {}", name),
+                Err(err) => log::error!("Error purging {}: {}", name, err),
+            }
+        }
+
+        // Files deleted along with their uploader leave blobs without references
+        match collect_blobs(&state).await {
+            Ok(0) => {}
+            Ok(count) => log::info!("Deleted {} unreferenced objects", count),
+            Err(err) => log::error!("Error deleting unreferenced objects: {}", err),
+        }
+
+        match expire_upload_sessions(&state).await {
+            Ok(0) => {}
+            Ok(count) => log::info!("Expired {} abandoned upload sessions", count),
+            Err(err) => log::error!("Error expiring abandoned upload sessions: {}", err),
+        }
+
+        match recover_pending_uploads(&state).await {
+            Ok(0) => {}
+            Ok(count) => log::info!("Recovered {} interrupted uploads", count),
+            Err(err) => log::error!("Error recovering interrupted uploads: {}", err),
+        }
+
+        match resync_replicas(&state).await {
+            Ok(0) => {}
+            Ok(count) => log::info!("Resynced {} missed replica operations", count),
+            Err(err) => log::error!("Error resyncing replicas: {}", err),
+        }
+    }
+}
+
+/// Periodically move files between the hot and cold storage tiers
+async fn tier_files(state: Data<State>, config: TieringConfig) {
+    let mut interval = tokio::time::interval(Duration::from_secs(config.interval));
+
+    loop {
+        interval.tick().await;
+
+        match tier_objects(&state, &config).await {
+            Ok(0) => {}
+            Ok(count) => log::info!("Moved {} objects between storage tiers", count),
+            Err(err) => log::error!("Error moving objects between storage tiers: {}", err),
+        }
+    }
+}
+
 async fn generate_thumbnails(state: &Data<State>) -> anyhow::Result<()> {
-    log::info!("Regenerating image thumbnails");
+    log::info!("Regenerating thumbnails");
 
     let files = files::Entity::find().all(&state.database).await?;
 
-    let image_files: Vec<files::Model> = files
+    let thumbnail_files: Vec<files::Model> = files
         .iter()
         .filter(|file| {
-            let extension = Path::new(&file.name)
-                .extension()
-                .and_then(OsStr::to_str)
-                .unwrap_or("");
-
-            IMAGE_EXTS
-                .into_iter()
-                .any(|ext| ext.eq(&extension.to_uppercase()))
+            supports_thumbnail(
+                &file
+                    .mime_type
+                    .clone()
+                    .unwrap_or_else(|| mime_from_name(&file.name)),
+            )
         })
         .map(|v| v.clone())
         .collect();
 
     log::info!(
         "{} files to generate",
-        image_files.len().to_string().yellow()
+        thumbnail_files.len().to_string().yellow()
     );
 
-    let progress = ProgressBar::new(image_files.len().try_into().unwrap());
+    let progress = progress_bar(thumbnail_files.len());
+
+    for file in thumbnail_files {
+        progress.set_message(file.name.clone());
+        progress.inc(1);
+
+        if !regenerate_thumbnails(state, &file).await? {
+            log::error!("Unable to create thumbnails for {}", file.name);
+        }
+    }
+
+    progress.finish_with_message("Finished generating thumbnails");
+
+    Ok(())
+}
+
+/// Progress bar of a CLI task going through `len` items
+fn progress_bar(len: usize) -> ProgressBar {
+    let progress = ProgressBar::new(len.try_into().unwrap());
     progress.set_style(
         ProgressStyle::default_bar()
             .template(&format!(
@@ -260,38 +553,212 @@ async fn generate_thumbnails(state: &Data<State>) -> anyhow::Result<()> {
             .progress_chars("##-"),
     );
 
-    for file in image_files {
-        let extension = Path::new(&file.name)
-            .extension()
-            .and_then(OsStr::to_str)
-            .unwrap_or("");
+    progress
+}
+
+/// Fill in the metadata of files uploaded before it was detected from their contents.
+/// Files encrypted by the client are skipped, their contents can't be read.
+async fn detect_file_metadata(state: &Data<State>) -> anyhow::Result<()> {
+    log::info!("Detecting file metadata");
+
+    let files = files::Entity::find()
+        .filter(files::Column::ClientEncrypted.eq(false))
+        .all(&state.database)
+        .await?;
 
-        if IMAGE_EXTS
-            .into_iter()
-            .any(|ext| ext.eq(&extension.to_uppercase()))
+    log::info!("{} files to detect", files.len().to_string().yellow());
+
+    let progress = progress_bar(files.len());
+
+    for file in files {
+        progress.set_message(file.name.clone());
+        progress.inc(1);
+
+        let metadata = detect_metadata(state.storage.as_ref(), &file.object, &file.name).await;
+
+        // Anything which could not be detected keeps its previous value
+        if let Err(err) = files::Entity::update_many()
+            .col_expr(files::Column::MimeType, Expr::value(metadata.mime_type))
+            .col_expr(
+                files::Column::Width,
+                Expr::value(metadata.width.or(file.width)),
+            )
+            .col_expr(
+                files::Column::Height,
+                Expr::value(metadata.height.or(file.height)),
+            )
+            .col_expr(
+                files::Column::Duration,
+                Expr::value(metadata.duration.or(file.duration)),
+            )
+            .filter(files::Column::Id.eq(file.id))
+            .exec(&state.database)
+            .await
         {
-            progress.set_message(file.name.clone());
-            progress.inc(1);
-
-            match state.storage.get_object(&file.name).await {
-                Ok(buf) => {
-                    if let Err(err) = state
-                        .storage
-                        .put_object(
-                            &format!("thumb/{}", file.name),
-                            &util::file::get_thumbnail_image(&buf)?,
-                        )
-                        .await
-                    {
-                        log::error!("Error putting {}: {}", file.name, err)
-                    }
-                }
-                Err(err) => log::error!("Error getting {}: {}", file.name, err),
+            log::error!("Unable to save the metadata of {}: {}", file.name, err);
+        }
+    }
+
+    progress.finish_with_message("Finished detecting metadata");
+
+    Ok(())
+}
+
+/// Encrypt the objects of files, thumbnails and transformations stored before encryption at rest was enabled.
+/// Each object is replaced by an encrypted copy under the same name.
+async fn encrypt_objects(state: &Data<State>) -> anyhow::Result<()> {
+    log::info!("Encrypting objects");
+
+    let encrypted: HashSet<String> = object_keys::Entity::find()
+        .all(&state.database)
+        .await?
+        .into_iter()
+        .map(|key| key.name)
+        .collect();
+
+    let objects: Vec<MigratedObject> = referenced_objects(&state.database)
+        .await?
+        .into_iter()
+        .filter(|object| !encrypted.contains(&object.name))
+        .collect();
+
+    log::info!("{} objects to encrypt", objects.len().to_string().yellow());
+
+    let progress = progress_bar(objects.len());
+
+    for object in objects {
+        progress.set_message(object.name.clone());
+        progress.inc(1);
+
+        match state.storage.encrypt_object(&object.name).await {
+            Ok(true) => {}
+            Ok(false) => anyhow::bail!("The storage provider does not encrypt objects"),
+            // Thumbnails from before there were variants may have been deleted already
+            Err(_) if object.optional => {}
+            Err(err) => log::error!("Unable to encrypt {}: {}", object.name, err),
+        }
+    }
+
+    progress.finish_with_message("Finished encrypting objects");
+
+    Ok(())
+}
+
+/// Copy every referenced object to another storage provider, verifying each copy.
+/// Objects are copied as stored, encrypted objects stay encrypted with the same keys.
+/// Cold objects are copied as well, every file is hot on the destination.
+async fn migrate_storage(
+    state: &Data<State>,
+    source: &StorageConfig,
+    cold: Option<&StorageConfig>,
+    destination: &StorageConfig,
+    encryption_key: Option<&[u8]>,
+) -> anyhow::Result<()> {
+    log::info!("Migrating storage");
+
+    let source = create_tiered_storage(source, cold, &state.database).await;
+    let verify: Box<dyn StorageProvider> = match encryption_key {
+        Some(key) => Box::new(EncryptedProvider::new(
+            create_storage(destination, &state.database).await,
+            key,
+            Box::new(DatabaseKeys::new(state.database.clone())),
+        )),
+        None => create_storage(destination, &state.database).await,
+    };
+    let destination = create_storage(destination, &state.database).await;
+
+    let objects = referenced_objects(&state.database).await?;
+
+    log::info!("{} objects to copy", objects.len().to_string().yellow());
+
+    let progress = progress_bar(objects.len());
+
+    let mut copied = 0;
+    let mut skipped = 0;
+    let mut missing = Vec::new();
+    let mut failed = Vec::new();
+
+    for object in &objects {
+        progress.set_message(object.name.clone());
+        progress.inc(1);
+
+        match copy_object(
+            source.as_ref(),
+            destination.as_ref(),
+            verify.as_ref(),
+            object,
+        )
+        .await
+        {
+            Ok(CopyOutcome::Copied) => copied += 1,
+            Ok(CopyOutcome::Skipped) => skipped += 1,
+            Ok(CopyOutcome::Missing) if object.optional => {}
+            Ok(CopyOutcome::Missing) => missing.push(object.name.to_owned()),
+            Err(err) => {
+                progress.println(format!("Unable to copy {}: {}", object.name, err));
+                failed.push(object.name.to_owned());
             }
         }
     }
 
-    progress.finish_with_message("Finished generating thumbnails");
+    progress.finish_with_message("Finished migrating storage");
+
+    log::info!(
+        "Copied {} objects, {} were already copied",
+        copied.to_string().yellow(),
+        skipped.to_string().yellow()
+    );
+
+    for name in &missing {
+        log::warn!("Missing from the source provider: {}", name);
+    }
+
+    for name in orphaned_blobs(&state.database).await? {
+        log::warn!("Orphaned object, not referenced by any file: {}", name);
+    }
+
+    // Running again retries only the objects which failed
+    if !failed.is_empty() {
+        anyhow::bail!("{} objects could not be copied", failed.len());
+    }
+
+    // The tiering worker moves idle files to cold storage again
+    files::Entity::update_many()
+        .col_expr(files::Column::Tier, Expr::cust("'hot'::storage_tier"))
+        .exec(&state.database)
+        .await?;
+
+    Ok(())
+}
+
+/// Check the storage provider against the database and log everything which was found
+async fn fsck(state: &Data<State>, params: &FsckParams) -> anyhow::Result<()> {
+    log::info!("Checking storage, this lists every object");
+
+    let report = check_storage(state, params).await?;
+
+    log::info!(
+        "Checked {} objects of {} files",
+        report.objects.to_string().yellow(),
+        report.files.to_string().yellow()
+    );
+
+    for (kind, names) in [
+        ("Missing object", &report.missing_objects),
+        ("Corrupted object", &report.corrupted_objects),
+        ("Broken file", &report.broken_files),
+        ("Orphaned object", &report.orphaned_objects),
+        ("Orphaned thumbnail", &report.orphaned_thumbnails),
+        ("Missing thumbnails of file", &report.missing_thumbnails),
+    ] {
+        for name in names {
+            log::warn!("{}: {}", kind, name);
+        }
+    }
+
+    if report.repaired {
+        log::info!("Repaired inconsistencies");
+    }
 
     Ok(())
 }
@@ -0,0 +1,40 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct FsckParams {
+    // Read every object to compare it with the stored hash, this is slow
+    #[serde(default)]
+    pub verify_hashes: bool,
+
+    // Delete orphans, regenerate missing thumbnails and flag broken files
+    #[serde(default)]
+    pub repair: bool,
+}
+
+#[derive(Serialize, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct FsckReport {
+    pub objects: usize,
+    pub files: usize,
+
+    // Objects of files which don't exist on the storage provider
+    pub missing_objects: Vec<String>,
+
+    // Objects which don't match the hash of their files
+    pub corrupted_objects: Vec<String>,
+
+    // IDs of files whose object is missing or corrupted
+    pub broken_files: Vec<String>,
+
+    // Objects which no file, blob or upload refers to
+    pub orphaned_objects: Vec<String>,
+
+    // Thumbnails of files or variants which no longer exist
+    pub orphaned_thumbnails: Vec<String>,
+
+    // IDs of files with recorded thumbnails which don't exist on the storage provider
+    pub missing_thumbnails: Vec<String>,
+
+    pub repaired: bool,
+}
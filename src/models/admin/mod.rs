@@ -1 +1,4 @@
+pub mod fsck;
+pub mod quota;
 pub mod registration_key;
+pub mod replica;
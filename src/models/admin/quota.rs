@@ -0,0 +1,7 @@
+use serde::Deserialize;
+
+#[derive(Deserialize)]
+pub struct QuotaForm {
+    // Quota in bytes, null removes the quota
+    pub quota: Option<i64>,
+}
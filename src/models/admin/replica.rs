@@ -0,0 +1,35 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+use crate::database::entity::{object_replicas, sea_orm_active_enums::ReplicaOperation};
+
+/// Operation a replica missed on an object, with the files whose contents it is
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ReplicaStatusData {
+    pub object: String,
+    pub replica: String,
+    pub operation: ReplicaOperation,
+    pub error: String,
+
+    // Failed resyncs since the operation was missed
+    pub attempts: i32,
+    pub updated: DateTime<Utc>,
+
+    // IDs of files pointing at the object, derived objects and uploads have none
+    pub files: Vec<String>,
+}
+
+impl ReplicaStatusData {
+    pub fn new(status: object_replicas::Model, files: Vec<String>) -> Self {
+        ReplicaStatusData {
+            object: status.object,
+            replica: status.replica,
+            operation: status.operation,
+            error: status.error,
+            attempts: status.attempts,
+            updated: status.updated.into(),
+            files,
+        }
+    }
+}
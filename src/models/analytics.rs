@@ -0,0 +1,89 @@
+use actix_web::http::StatusCode;
+use chrono::NaiveDate;
+use serde::{Deserialize, Serialize};
+
+use crate::models::MessageResponse;
+
+/// Longest time series which can be requested
+const MAX_ANALYTICS_DAYS: i32 = 365;
+
+/// Kind of client a file was served to
+#[derive(Serialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum UserAgentClass {
+    Desktop,
+    Mobile,
+    // Crawlers and link preview fetchers
+    Bot,
+    // Command line tools and HTTP libraries
+    Cli,
+    Other,
+}
+
+impl UserAgentClass {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            UserAgentClass::Desktop => "desktop",
+            UserAgentClass::Mobile => "mobile",
+            UserAgentClass::Bot => "bot",
+            UserAgentClass::Cli => "cli",
+            UserAgentClass::Other => "other",
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct AnalyticsQuery {
+    // Length of the time series ending today, defaults to 30
+    pub days: Option<i32>,
+}
+
+impl AnalyticsQuery {
+    pub fn validate(&self) -> Result<(), MessageResponse> {
+        if self
+            .days
+            .map_or(false, |days| days < 1 || days > MAX_ANALYTICS_DAYS)
+        {
+            return Err(MessageResponse::new(
+                StatusCode::BAD_REQUEST,
+                &format!("Days must be between 1 and {}", MAX_ANALYTICS_DAYS),
+            ));
+        }
+
+        Ok(())
+    }
+
+    pub fn days(&self) -> i32 {
+        self.days.unwrap_or(30)
+    }
+}
+
+/// Views on a single day
+#[derive(Serialize)]
+pub struct DailyViews {
+    pub date: NaiveDate,
+    pub views: i64,
+    pub bytes: i64,
+}
+
+/// Views grouped by a value such as the referrer
+#[derive(Serialize)]
+pub struct ViewCount {
+    pub value: String,
+    pub views: i64,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AnalyticsData {
+    // All time totals
+    pub total_views: i64,
+    pub total_bytes: i64,
+
+    // One entry for every day in the requested period, including days without views
+    pub series: Vec<DailyViews>,
+
+    // Breakdowns of the requested period, limited by how long single views are kept
+    pub referrers: Vec<ViewCount>,
+    pub user_agents: Vec<ViewCount>,
+}
@@ -1,15 +1,17 @@
-use std::{
-    ffi::OsStr,
-    path::{Path, PathBuf},
-};
+use std::{collections::BTreeMap, path::PathBuf};
+
+use serde::{Deserialize, Serialize};
 
-use serde::Serialize;
+use chrono::{DateTime, Duration, Utc};
 
-use chrono::{DateTime, Utc};
+use actix_web::http::StatusCode;
 
-use crate::util::file::IMAGE_EXTS;
+use crate::models::MessageResponse;
 
-use crate::database::entity::files;
+use crate::database::entity::{
+    files,
+    sea_orm_active_enums::{StorageTier, Visibility},
+};
 
 #[derive(Serialize)]
 #[serde(rename_all = "camelCase")]
@@ -19,15 +21,63 @@ pub struct FileData {
     pub name: String,
     pub original_name: String,
 
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub url: Option<String>,
+    // Storage key of the contents, shared by files with the same hash
+    #[serde(skip_serializing)]
+    pub object: String,
 
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub thumbnail_url: Option<String>,
+    pub url: Option<String>,
 
     pub hash: String,
     pub uploaded: DateTime<Utc>,
     pub size: i64,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expires: Option<DateTime<Utc>>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_downloads: Option<i32>,
+    pub downloads: i32,
+
+    pub visibility: FileVisibility,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub folder: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mime_type: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub width: Option<i32>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub height: Option<i32>,
+
+    // Seconds
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub duration: Option<f64>,
+
+    pub tags: Vec<String>,
+
+    // Thumbnail URLs by size and then format
+    pub thumbnails: BTreeMap<u32, BTreeMap<String, String>>,
+
+    // Only present when uploading with metadata removal enabled
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metadata_removed: Option<bool>,
+
+    // Contents were encrypted by the client, the server can't read them
+    pub encrypted: bool,
+
+    // Opaque metadata the client sent with encrypted contents
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub encrypted_metadata: Option<String>,
+
+    // Contents are missing or corrupted, found by a storage consistency check
+    pub broken: bool,
+
+    // Cold files were not read for a while and may be slower to load
+    pub tier: StorageTier,
 }
 
 impl From<files::Model> for FileData {
@@ -37,43 +87,301 @@ impl From<files::Model> for FileData {
             uploader: file.uploader,
             name: file.name,
             original_name: file.original_name,
+            object: file.object,
             hash: file.hash,
             uploaded: file.uploaded.into(),
             size: file.size,
+            expires: file.expires.map(|expires| expires.into()),
+            max_downloads: file.max_downloads,
+            downloads: file.downloads,
+            visibility: FileVisibility::from(file.visibility),
+            folder: file.folder,
+            mime_type: file.mime_type,
+            width: file.width,
+            height: file.height,
+            duration: file.duration,
+            encrypted: file.client_encrypted,
+            encrypted_metadata: file.client_metadata,
+            broken: file.broken,
+            tier: file.tier,
             // These fields are not stored in database
             // They are filled in by the route returning it
             url: None,
-            thumbnail_url: None,
+            tags: Vec::new(),
+            thumbnails: BTreeMap::new(),
+            metadata_removed: None,
         }
     }
 }
 
 impl FileData {
-    /// Computes and sets the URL based on a root storage path
-    pub fn set_url(&mut self, mut root_path: PathBuf) {
-        root_path.push(&self.name);
-        self.url = Some(root_path.as_path().display().to_string().replace("\\", "/"))
+    /// Was the object uploaded as this file, only then is it readable from the storage URL of the file
+    pub fn owns_object(&self) -> bool {
+        self.object == self.name
     }
+}
 
-    /// Computes and sets the URL based on root storage path
-    /// This will only set if a valid image or extension was sent
-    pub fn set_thumbnail_url(&mut self, mut root_path: PathBuf) {
-        let extension = Path::new(&self.name)
-            .extension()
-            .and_then(OsStr::to_str)
-            .unwrap_or("");
-
-        if IMAGE_EXTS
-            .into_iter()
-            .any(|ext| ext.eq(&extension.to_uppercase()))
-        {
-            root_path.push(format!("thumb/{}", &self.name));
-            self.thumbnail_url = Some(root_path.as_path().display().to_string().replace("\\", "/"));
+/// Who can read a file
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum FileVisibility {
+    // Readable by anyone and may be shown publicly
+    Public,
+    // Readable by anyone with the link
+    Unlisted,
+    // Only readable by the uploader
+    Private,
+    // Readable by anyone with the password
+    Password,
+}
+
+impl FileVisibility {
+    /// Can the file be read directly from the storage URL
+    pub fn is_direct(&self) -> bool {
+        matches!(self, FileVisibility::Public | FileVisibility::Unlisted)
+    }
+}
+
+impl From<Visibility> for FileVisibility {
+    fn from(visibility: Visibility) -> Self {
+        match visibility {
+            Visibility::Public => FileVisibility::Public,
+            Visibility::Unlisted => FileVisibility::Unlisted,
+            Visibility::Private => FileVisibility::Private,
+            Visibility::Password => FileVisibility::Password,
         }
     }
 }
 
+impl From<FileVisibility> for Visibility {
+    fn from(visibility: FileVisibility) -> Self {
+        match visibility {
+            FileVisibility::Public => Visibility::Public,
+            FileVisibility::Unlisted => Visibility::Unlisted,
+            FileVisibility::Private => Visibility::Private,
+            FileVisibility::Password => Visibility::Password,
+        }
+    }
+}
+
+/// Filters and sorting for listing files, every filter is optional
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FileSearchQuery {
+    // Full text search over the original name and tags
+    pub query: Option<String>,
+
+    // Comma separated, files must have every tag
+    pub tags: Option<String>,
+
+    // Exact MIME type or a prefix ending with a slash such as `image/`
+    pub mime: Option<String>,
+
+    pub min_size: Option<i64>,
+    pub max_size: Option<i64>,
+    pub after: Option<DateTime<Utc>>,
+    pub before: Option<DateTime<Utc>>,
+
+    #[serde(default)]
+    pub sort: FileSort,
+    #[serde(default)]
+    pub order: SortOrder,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FileSort {
+    Date,
+    Name,
+    Size,
+}
+
+impl Default for FileSort {
+    fn default() -> Self {
+        FileSort::Date
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+    Asc,
+    Desc,
+}
+
+impl Default for SortOrder {
+    fn default() -> Self {
+        SortOrder::Desc
+    }
+}
+
+#[derive(Deserialize)]
+pub struct FileTagsForm {
+    pub tags: Vec<String>,
+}
+
+#[derive(Serialize)]
+pub struct TagData {
+    pub tag: String,
+    pub count: i64,
+}
+
+#[derive(Deserialize)]
+pub struct FileMoveForm {
+    pub files: Vec<String>,
+
+    // Moved out of any folder if not provided
+    pub folder: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct FileDownloadQuery {
+    // Ask the browser to save the file instead of displaying it
+    #[serde(default)]
+    pub attachment: bool,
+
+    // Signed token given to the uploader in place of a storage URL
+    pub token: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct FileVisibilityForm {
+    pub visibility: FileVisibility,
+
+    // Required when visibility is password
+    pub password: Option<String>,
+}
+
+impl FileData {
+    /// Computes and sets the URL based on a root storage path
+    pub fn set_url(&mut self, mut root_path: PathBuf) {
+        root_path.push(&self.object);
+        self.url = Some(root_path.as_path().display().to_string().replace("\\", "/"))
+    }
+}
+
 #[derive(Serialize)]
 pub struct FileStats {
     pub usage: i64,
+
+    // Not present if the user has no quota
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub quota: Option<i64>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub remaining: Option<i64>,
+}
+
+impl FileStats {
+    pub fn new(usage: i64, quota: Option<i64>) -> Self {
+        Self {
+            usage,
+            quota,
+            remaining: quota.map(|quota| (quota - usage).max(0)),
+        }
+    }
+}
+
+/// Longest metadata which can be stored with an encrypted upload
+pub const MAX_ENCRYPTED_METADATA: usize = 4096;
+
+/// Options which can be sent as query parameters alongside an upload
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct UploadOptions {
+    // Time at which the file expires
+    pub expires: Option<DateTime<Utc>>,
+
+    // Seconds until the file expires, used if expires is not set
+    pub ttl: Option<i64>,
+
+    // Delete the file after it was downloaded this many times
+    pub max_downloads: Option<i32>,
+
+    // Defaults to public, a password can only be set after uploading
+    pub visibility: Option<FileVisibility>,
+
+    // Remove EXIF, XMP and ICC metadata from images, defaults to the instance setting
+    pub strip_metadata: Option<bool>,
+
+    // Contents are ciphertext encrypted by the client
+    #[serde(default)]
+    pub encrypted: bool,
+
+    // Opaque base64 metadata stored with encrypted contents
+    pub metadata: Option<String>,
+}
+
+impl UploadOptions {
+    pub fn validate(&self) -> Result<(), MessageResponse> {
+        if self.expires.map_or(false, |expires| expires <= Utc::now()) {
+            return Err(MessageResponse::new(
+                StatusCode::BAD_REQUEST,
+                "Expiry time must be in the future",
+            ));
+        }
+
+        // Limit to 10 years so the timestamp can not overflow
+        if self
+            .ttl
+            .map_or(false, |ttl| ttl < 1 || ttl > 10 * 365 * 24 * 60 * 60)
+        {
+            return Err(MessageResponse::new(
+                StatusCode::BAD_REQUEST,
+                "TTL must be between 1 second and 10 years",
+            ));
+        }
+
+        if self.visibility == Some(FileVisibility::Password) {
+            return Err(MessageResponse::new(
+                StatusCode::BAD_REQUEST,
+                "Password protection must be set after uploading",
+            ));
+        }
+
+        if self.max_downloads.map_or(false, |max| max < 1) {
+            return Err(MessageResponse::new(
+                StatusCode::BAD_REQUEST,
+                "Maximum downloads must be at least 1",
+            ));
+        }
+
+        if let Some(metadata) = &self.metadata {
+            if !self.encrypted {
+                return Err(MessageResponse::new(
+                    StatusCode::BAD_REQUEST,
+                    "Metadata can only be sent with encrypted uploads",
+                ));
+            }
+
+            // Limited to base64 so it can be embedded in the share page as it is
+            if metadata.len() > MAX_ENCRYPTED_METADATA
+                || !metadata
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || matches!(c, '+' | '/' | '-' | '_' | '='))
+            {
+                return Err(MessageResponse::new(
+                    StatusCode::BAD_REQUEST,
+                    &format!(
+                        "Metadata must be base64 and at most {} characters",
+                        MAX_ENCRYPTED_METADATA
+                    ),
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Absolute expiry time, a TTL is counted from now
+    pub fn expiry(&self) -> Option<DateTime<Utc>> {
+        self.expires
+            .or_else(|| self.ttl.map(|ttl| Utc::now() + Duration::seconds(ttl)))
+    }
+
+    /// Should image metadata be removed, `default` is the instance setting.
+    /// Encrypted contents are never changed.
+    pub fn strip_metadata(&self, default: bool) -> bool {
+        !self.encrypted && self.strip_metadata.unwrap_or(default)
+    }
 }
@@ -0,0 +1,73 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    database::entity::folders,
+    models::{FileData, Page},
+};
+
+#[derive(Deserialize)]
+pub struct FolderCreateForm {
+    pub name: String,
+
+    // Created at the top level if not provided
+    pub parent: Option<String>,
+
+    #[serde(default)]
+    pub public: bool,
+}
+
+#[derive(Deserialize)]
+pub struct FolderUpdateForm {
+    pub name: Option<String>,
+    pub public: Option<bool>,
+}
+
+#[derive(Deserialize)]
+pub struct FolderMoveForm {
+    // Moved to the top level if not provided
+    pub parent: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct FolderData {
+    pub id: String,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parent: Option<String>,
+
+    pub name: String,
+    pub public: bool,
+    pub created: DateTime<Utc>,
+}
+
+impl From<folders::Model> for FolderData {
+    fn from(folder: folders::Model) -> Self {
+        Self {
+            id: folder.id,
+            parent: folder.parent,
+            name: folder.name,
+            public: folder.public,
+            created: folder.created.into(),
+        }
+    }
+}
+
+/// Folder along with the folders directly inside of it
+#[derive(Serialize)]
+pub struct FolderInfo {
+    #[serde(flatten)]
+    pub folder: FolderData,
+    pub folders: Vec<FolderData>,
+}
+
+/// Public view of a folder shared as an album
+#[derive(Serialize)]
+pub struct AlbumData {
+    pub name: String,
+    pub owner: String,
+    pub created: DateTime<Utc>,
+
+    #[serde(flatten)]
+    pub files: Page<FileData>,
+}
@@ -0,0 +1,83 @@
+use actix_web::http::StatusCode;
+use serde::Deserialize;
+
+use crate::{models::MessageResponse, util::thumbnail::ThumbnailFormat};
+
+/// Largest width or height a transformed image can have
+pub const MAX_TRANSFORM_DIMENSION: u32 = 4096;
+
+/// How an image is fit into the requested width and height
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum ImageFit {
+    // Scaled to fit within the size, keeping the aspect ratio
+    Contain,
+    // Scaled to fill the size, cropping the overflow
+    Cover,
+    // Stretched to exactly the size
+    Fill,
+}
+
+impl Default for ImageFit {
+    fn default() -> Self {
+        ImageFit::Contain
+    }
+}
+
+/// Transformation applied to an image before it is served, every parameter is optional
+#[derive(Deserialize)]
+pub struct ImageTransformQuery {
+    pub w: Option<u32>,
+    pub h: Option<u32>,
+
+    #[serde(default)]
+    pub fit: ImageFit,
+
+    // Degrees clockwise, a multiple of 90
+    #[serde(default)]
+    pub rotate: u16,
+
+    // Defaults to the format of the original image
+    pub format: Option<ThumbnailFormat>,
+
+    // Between 1 and 100, ignored by lossless formats
+    pub quality: Option<u8>,
+}
+
+impl ImageTransformQuery {
+    pub fn validate(&self) -> Result<(), MessageResponse> {
+        if self
+            .w
+            .into_iter()
+            .chain(self.h)
+            .any(|size| size < 1 || size > MAX_TRANSFORM_DIMENSION)
+        {
+            return Err(MessageResponse::new(
+                StatusCode::BAD_REQUEST,
+                &format!(
+                    "Width and height must be between 1 and {} pixels",
+                    MAX_TRANSFORM_DIMENSION
+                ),
+            ));
+        }
+
+        if !matches!(self.rotate, 0 | 90 | 180 | 270) {
+            return Err(MessageResponse::new(
+                StatusCode::BAD_REQUEST,
+                "Rotation must be 0, 90, 180 or 270 degrees",
+            ));
+        }
+
+        if self
+            .quality
+            .map_or(false, |quality| quality < 1 || quality > 100)
+        {
+            return Err(MessageResponse::new(
+                StatusCode::BAD_REQUEST,
+                "Quality must be between 1 and 100",
+            ));
+        }
+
+        Ok(())
+    }
+}
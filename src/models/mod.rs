@@ -1,7 +1,12 @@
 pub mod admin;
+pub mod analytics;
 pub mod application;
 pub mod auth;
 pub mod file;
+pub mod folder;
+pub mod image;
+pub mod share;
+pub mod upload;
 pub mod user;
 
 use crate::{database::entity::settings, util::GIT_VERSION};
@@ -13,7 +18,10 @@ use sea_orm::ActiveEnum;
 use serde::Serialize;
 use std::fmt::Display;
 
-pub use self::{application::*, auth::*, file::*, user::*};
+pub use self::{
+    analytics::*, application::*, auth::*, file::*, folder::*, image::*, share::*, upload::*,
+    user::*,
+};
 
 #[derive(Debug, Display)]
 pub struct Error(anyhow::Error);
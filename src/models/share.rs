@@ -0,0 +1,87 @@
+use actix_web::http::StatusCode;
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::{database::entity::file_shares, models::MessageResponse};
+
+/// Longest time a share link can be valid for
+const MAX_SHARE_LIFETIME: i64 = 30 * 24 * 60 * 60;
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ShareCreateForm {
+    // Seconds until the link expires, defaults to a day
+    pub expires_in: Option<i64>,
+
+    // Amount of times the link can be used, unlimited if not provided
+    pub max_downloads: Option<i32>,
+}
+
+impl ShareCreateForm {
+    pub fn validate(&self) -> Result<(), MessageResponse> {
+        if self.expires_in.map_or(false, |expires_in| {
+            expires_in < 1 || expires_in > MAX_SHARE_LIFETIME
+        }) {
+            return Err(MessageResponse::new(
+                StatusCode::BAD_REQUEST,
+                "Share links must expire between 1 second and 30 days",
+            ));
+        }
+
+        if self.max_downloads.map_or(false, |max| max < 1) {
+            return Err(MessageResponse::new(
+                StatusCode::BAD_REQUEST,
+                "Maximum downloads must be at least 1",
+            ));
+        }
+
+        Ok(())
+    }
+
+    pub fn expiry(&self) -> DateTime<Utc> {
+        Utc::now() + Duration::seconds(self.expires_in.unwrap_or(24 * 60 * 60))
+    }
+}
+
+/// Signed parameters of a share link, checked against the stored share
+#[derive(Deserialize)]
+pub struct ShareQuery {
+    pub file: String,
+    pub expires: i64,
+    pub downloads: Option<i32>,
+    pub signature: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ShareData {
+    pub id: String,
+    pub file_id: String,
+    pub url: String,
+
+    // Page decrypting files encrypted by the client, the key is added as the URL fragment
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub page_url: Option<String>,
+
+    pub expires: DateTime<Utc>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_downloads: Option<i32>,
+    pub downloads: i32,
+    pub created: DateTime<Utc>,
+}
+
+impl ShareData {
+    pub fn new(share: file_shares::Model, url: String) -> Self {
+        Self {
+            id: share.id,
+            file_id: share.file_id,
+            url,
+            page_url: None,
+            expires: share.expires.into(),
+            max_downloads: share.max_downloads,
+            downloads: share.downloads,
+            created: share.created.into(),
+        }
+    }
+}
@@ -0,0 +1,77 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::database::entity::{upload_chunks, upload_sessions};
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UploadSessionCreateForm {
+    // Original name of the file being uploaded
+    pub name: String,
+    pub size: u64,
+
+    // SHA-256 hash the assembled file must match
+    pub hash: Option<String>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UploadSessionData {
+    pub id: String,
+    pub original_name: String,
+    pub size: i64,
+    pub chunk_size: i64,
+
+    // Total amount of chunks expected, numbered from 1
+    pub chunks: i64,
+    pub received_chunks: Vec<i32>,
+
+    // Byte ranges which were received, merged where contiguous
+    pub received_ranges: Vec<UploadRange>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hash: Option<String>,
+    pub created: DateTime<Utc>,
+}
+
+/// Inclusive range of bytes
+#[derive(Serialize)]
+pub struct UploadRange {
+    pub start: i64,
+    pub end: i64,
+}
+
+impl UploadSessionData {
+    pub fn new(session: upload_sessions::Model, chunks: &[upload_chunks::Model]) -> Self {
+        let mut received_chunks: Vec<i32> = chunks.iter().map(|chunk| chunk.number).collect();
+        received_chunks.sort();
+
+        let mut received_ranges: Vec<UploadRange> = Vec::new();
+        for number in &received_chunks {
+            let start = (*number as i64 - 1) * session.chunk_size;
+            let end = (start + session.chunk_size).min(session.size) - 1;
+
+            match received_ranges.last_mut() {
+                Some(last) if last.end + 1 == start => last.end = end,
+                _ => received_ranges.push(UploadRange { start, end }),
+            }
+        }
+
+        Self {
+            id: session.id,
+            original_name: session.original_name,
+            size: session.size,
+            chunk_size: session.chunk_size,
+            chunks: chunk_count(session.size, session.chunk_size),
+            received_chunks,
+            received_ranges,
+            hash: session.hash,
+            created: session.created.into(),
+        }
+    }
+}
+
+/// Amount of chunks needed for a file
+pub fn chunk_count(size: i64, chunk_size: i64) -> i64 {
+    (size + chunk_size - 1) / chunk_size
+}
@@ -0,0 +1,24 @@
+use actix_web::{post, web, HttpResponse, Responder, Scope};
+
+use crate::{
+    models::{admin::fsck::FsckParams, Response},
+    state::State,
+    util::{
+        auth::{auth_role, Auth},
+        fsck::check_storage,
+    },
+};
+
+pub fn get_routes() -> Scope {
+    web::scope("/fsck").service(run)
+}
+
+/// Check the storage provider against the database, optionally repairing what was found
+#[post("")]
+async fn run(
+    state: web::Data<State>,
+    _auth: Auth<auth_role::Admin>,
+    params: web::Json<FsckParams>,
+) -> Response<impl Responder> {
+    Ok(HttpResponse::Ok().json(check_storage(&state, &params).await?))
+}
@@ -1,9 +1,15 @@
 use actix_web::{web, Scope};
 
+pub mod fsck;
+pub mod quota;
 pub mod registration_key;
+pub mod replica;
 
 pub fn get_routes(invite_only: bool) -> Scope {
-    let scope = web::scope("/admin");
+    let scope = web::scope("/admin")
+        .service(quota::get_routes())
+        .service(fsck::get_routes())
+        .service(replica::get_routes());
 
     if invite_only {
         scope.service(registration_key::get_routes())
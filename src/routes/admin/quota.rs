@@ -0,0 +1,96 @@
+use actix_http::StatusCode;
+use actix_web::{get, put, web, HttpResponse, Responder, Scope};
+use sea_orm::{ActiveModelTrait, EntityTrait, Set};
+
+use crate::{
+    database::entity::{settings, users},
+    models::{admin::quota::QuotaForm, FileStats, MessageResponse, Response},
+    state::State,
+    util::{
+        auth::{auth_role, Auth},
+        quota::{get_quota, get_usage},
+    },
+};
+
+pub fn get_routes() -> Scope {
+    web::scope("/quota")
+        .service(set_default)
+        .service(get_user)
+        .service(set_user)
+}
+
+/// Set the default quota for users without their own quota
+#[put("")]
+async fn set_default(
+    state: web::Data<State>,
+    _auth: Auth<auth_role::Admin>,
+    form: web::Json<QuotaForm>,
+) -> Response<impl Responder> {
+    if form.quota.map_or(false, |quota| quota < 0) {
+        return MessageResponse::ok(StatusCode::BAD_REQUEST, "Quota can not be negative");
+    }
+
+    settings::ActiveModel {
+        one_row_enforce: Set(true),
+        default_quota: Set(form.quota),
+        ..Default::default()
+    }
+    .update(&state.database)
+    .await?;
+
+    MessageResponse::ok(StatusCode::OK, "Default quota was updated")
+}
+
+#[get("/{user_id}")]
+async fn get_user(
+    state: web::Data<State>,
+    user_id: web::Path<String>,
+    _auth: Auth<auth_role::Admin>,
+) -> Response<impl Responder> {
+    if users::Entity::find_by_id(user_id.to_string())
+        .one(&state.database)
+        .await?
+        .is_none()
+    {
+        return MessageResponse::ok(StatusCode::NOT_FOUND, "User not found");
+    }
+
+    Ok(HttpResponse::Ok().json(FileStats::new(
+        get_usage(&state.database, &user_id).await?,
+        get_quota(&state.database, &user_id).await?,
+    )))
+}
+
+/// Set the quota of a single user, null falls back to the default quota
+#[put("/{user_id}")]
+async fn set_user(
+    state: web::Data<State>,
+    user_id: web::Path<String>,
+    _auth: Auth<auth_role::Admin>,
+    form: web::Json<QuotaForm>,
+) -> Response<impl Responder> {
+    if form.quota.map_or(false, |quota| quota < 0) {
+        return MessageResponse::ok(StatusCode::BAD_REQUEST, "Quota can not be negative");
+    }
+
+    if users::Entity::find_by_id(user_id.to_string())
+        .one(&state.database)
+        .await?
+        .is_none()
+    {
+        return MessageResponse::ok(StatusCode::NOT_FOUND, "User not found");
+    }
+
+    users::ActiveModel {
+        id: Set(user_id.to_string()),
+        quota: Set(form.quota),
+        ..Default::default()
+    }
+    .update(&state.database)
+    .await?;
+
+    MessageResponse::ok(
+        StatusCode::OK,
+        &format!("Quota of user ({}) was updated", user_id),
+    )
+}
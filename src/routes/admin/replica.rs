@@ -0,0 +1,35 @@
+use actix_http::StatusCode;
+use actix_web::{get, post, web, HttpResponse, Responder, Scope};
+
+use crate::{
+    models::{MessageResponse, Response},
+    state::State,
+    util::{
+        auth::{auth_role, Auth},
+        replicas::{missed_operations, resync_replicas},
+    },
+};
+
+pub fn get_routes() -> Scope {
+    web::scope("/replicas").service(list).service(resync)
+}
+
+/// Operations replicas of a mirrored storage provider missed and which were not resynced yet
+#[get("")]
+async fn list(state: web::Data<State>, _auth: Auth<auth_role::Admin>) -> Response<impl Responder> {
+    Ok(HttpResponse::Ok().json(missed_operations(&state.database).await?))
+}
+
+/// Resync replicas now instead of waiting for the purge worker
+#[post("/resync")]
+async fn resync(
+    state: web::Data<State>,
+    _auth: Auth<auth_role::Admin>,
+) -> Response<impl Responder> {
+    let resynced = resync_replicas(&state).await?;
+
+    MessageResponse::ok(
+        StatusCode::OK,
+        &format!("Resynced {} missed operations", resynced),
+    )
+}
@@ -1,26 +1,58 @@
-use std::{
-    collections::HashMap,
-    ffi::OsStr,
-    path::{Path, PathBuf},
-};
-
 use actix_multipart::Multipart;
-use actix_web::{delete, get, http::StatusCode, post, web, HttpResponse, Responder, Scope};
-use nanoid::nanoid;
+use actix_web::{
+    delete, get,
+    http::{
+        header::{CacheControl, CacheDirective, DispositionType},
+        StatusCode,
+    },
+    post, put, web, HttpRequest, HttpResponse, Responder, Scope,
+};
+use chrono::{TimeZone, Utc};
 use sea_orm::{
-    sea_query::SimpleExpr, ActiveModelTrait, ColumnTrait, ConnectionTrait, DbBackend, EntityTrait,
-    ModelTrait, PaginatorTrait, QueryFilter, QueryOrder, Set, Statement,
+    sea_query::Expr, ActiveModelTrait, ColumnTrait, ConnectionTrait, DbBackend, DbErr, EntityTrait,
+    ModelTrait, NotSet, Order, PaginatorTrait, QueryFilter, QueryOrder, Set, Statement,
+    TransactionTrait, Value,
 };
 use serde_json::json;
-use sha2::{Digest, Sha256};
 
 use crate::{
-    database::entity::files,
-    models::{Error, FileData, FileStats, MessageResponse, Page, Response},
+    database::entity::{file_shares, file_tags, files},
+    models::{
+        AnalyticsQuery, Error, FileData, FileDownloadQuery, FileMoveForm, FileSearchQuery,
+        FileSort, FileStats, FileTagsForm, FileVisibility, FileVisibilityForm, ImageTransformQuery,
+        MessageResponse, Page, Response, ShareCreateForm, ShareData, SortOrder, TagData,
+        UploadOptions,
+    },
+    routes::folder::find_owned_folder,
     state::State,
+    storage::pending_name,
     util::{
+        access::{
+            check_access, owner_file_data, request_password, set_public_url, share_page_url,
+            share_url,
+        },
+        analytics::{get_analytics, record_view, AnalyticsScope},
         auth::{auth_role, Auth},
-        file::{get_file_from_payload, get_thumbnail_image, MultipartError, IMAGE_EXTS},
+        blob::{create_blob, find_blob},
+        expiry::{count_download, is_expired, not_expired, purge_file, record_access},
+        file::{
+            get_file_from_payload, new_file_name, peek_stream, store_stream, MultipartError,
+            StoredObject,
+        },
+        metadata::{detect_metadata, is_thumbnailable, mime_from_name, FileMetadata},
+        quota::{get_quota, get_usage, quota_exceeded, remaining_quota},
+        serve::{check_intact, counts_as_download, serve_derived, serve_file},
+        signature::verify_object_token,
+        strip::{store_stripped_stream, supports_stripping, SNIFF_SIZE},
+        tags::{load_tags, normalize_tags, search_query},
+        thumbnail::{
+            create_thumbnails, load_thumbnails, put_thumbnails, record_thumbnails, thumbnail_name,
+            thumbnail_names, ThumbnailFormat,
+        },
+        transform::{
+            cache_transform, image_dimensions, transform_names, ImageTransform, MAX_SOURCE_PIXELS,
+        },
+        user::new_password,
         validate_paginate,
     },
 };
@@ -28,9 +60,21 @@ use crate::{
 pub fn get_routes() -> Scope {
     web::scope("/file")
         .service(stats)
+        .service(user_analytics)
+        .service(tags)
+        .service(move_files)
         .service(list)
         .service(info)
+        .service(file_analytics)
         .service(upload)
+        .service(download)
+        .service(thumbnail)
+        .service(transform_image)
+        .service(visibility)
+        .service(set_tags)
+        .service(create_share)
+        .service(list_shares)
+        .service(revoke_share)
         .service(delete_file)
 }
 
@@ -38,100 +82,355 @@ pub fn get_routes() -> Scope {
 async fn upload(
     state: web::Data<State>,
     auth: Auth<auth_role::User, false, true>,
+    options: web::Query<UploadOptions>,
     mut payload: Multipart,
 ) -> Response<impl Responder> {
-    match get_file_from_payload(&mut payload, state.file_size_limit, "uploadFile").await {
-        Ok(file) => {
-            let extension = Path::new(&file.filename)
-                .extension()
-                .and_then(OsStr::to_str)
-                .unwrap_or("");
+    if let Err(err) = options.validate() {
+        return Ok(err.http_response());
+    }
 
-            // New filename, collision not likely with NanoID
-            let filename = nanoid!(10) + "." + extension;
+    let file = match get_file_from_payload(&mut payload, "uploadFile").await {
+        Ok(v) => v,
+        Err(_) => return Ok(MessageResponse::bad_request().http_response()),
+    };
 
-            let hash = &format!("{:x}", Sha256::digest(&file.bytes));
+    let filename = new_file_name(&file.filename);
+    let pending = pending_name(&filename);
 
-            let file_exists = files::Entity::find()
-                .filter(files::Column::Hash.eq(hash.to_owned()))
-                .one(&state.database)
-                .await?;
-
-            if let Some(file) = file_exists {
-                // Push the existing file name for the matching hash
-                let mut file_url = PathBuf::from(&state.storage_url);
-                file_url.push(file.name);
-
-                let mut object = serde_json::Map::new();
-                object.insert(
-                    "url".to_string(),
-                    json!(&file_url.as_path().display().to_string().replace("\\", "/")),
-                );
-
-                return MessageResponse::ok_with_data(
-                    StatusCode::CONFLICT,
-                    "You have already uploaded this file",
-                    serde_json::Value::Object(object),
-                );
-            }
+    // The quota is enforced while receiving so the object is never fully written
+    let remaining = remaining_quota(&state.database, &auth.user.id).await?;
+    let size_limit = match remaining {
+        Some(remaining) => state.file_size_limit.min(remaining as usize),
+        None => state.file_size_limit,
+    };
 
-            let file_model = files::ActiveModel {
-                uploader: Set(auth.user.id.to_owned()),
-                name: Set(filename.to_owned()),
-                original_name: Set(file.filename.to_owned()),
-                hash: Set(hash.to_owned()),
-                size: Set(file.size as i64),
-                ..Default::default()
-            }
-            .insert(&state.database)
-            .await?;
+    // Images which have their metadata removed are held in memory so the original is never written
+    let (header, field) = peek_stream(file.field, SNIFF_SIZE).await;
+    let stored = if options.strip_metadata(state.strip_metadata) && supports_stripping(&header) {
+        store_stripped_stream(field, state.storage.as_ref(), &pending, size_limit).await
+    } else {
+        // Upload file to storage provider while it is being received
+        store_stream(field, state.storage.as_ref(), &pending, size_limit).await
+    };
 
-            // Upload file to storage provider
-            // If this fails attempt to delete the file from database
-            if let Err(_) = state.storage.put_object(&filename, &file.bytes).await {
-                let _ = file_model.delete(&state.database).await;
-                return MessageResponse::ok(
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                    "Unable to upload file",
-                );
+    let stored = match stored {
+        Ok(v) => v,
+        Err(err) => {
+            return match err {
+                MultipartError::FieldNotFound(_) => {
+                    Ok(MessageResponse::bad_request().http_response())
+                }
+                MultipartError::PayloadTooLarge(_) if size_limit < state.file_size_limit => {
+                    Ok(quota_exceeded().http_response())
+                }
+                MultipartError::PayloadTooLarge(_) => MessageResponse::ok(
+                    StatusCode::PAYLOAD_TOO_LARGE,
+                    &format!(
+                        "File was larger than the size limit of {}mb",
+                        state.file_size_limit / 1000 / 1000
+                    ),
+                ),
+                MultipartError::WriteError(err) => Err(Error::from(err)),
+                MultipartError::StorageError(_) => {
+                    MessageResponse::ok(StatusCode::INTERNAL_SERVER_ERROR, "Unable to upload file")
+                }
             }
+        }
+    };
 
-            let root_path = PathBuf::from(&state.storage_url);
+    Ok(
+        match create_file(
+            &state,
+            &auth.user.id,
+            &filename,
+            &file.filename,
+            &stored,
+            &options,
+        )
+        .await?
+        {
+            Ok(file_data) => HttpResponse::Ok().json(file_data),
+            Err(err) => err.http_response(),
+        },
+    )
+}
 
-            let mut file_api = FileData::from(file_model);
+/// Create the file entry for an upload which was written to the pending name of `filename`.
+/// This deduplicates by hash and creates the thumbnail, neither is done for contents encrypted by the client.
+/// Contents already stored for any user are shared, the upload is deleted in favour of the existing blob.
+///
+/// The blob, file and thumbnails are inserted in one transaction and the upload is promoted to its final name
+/// before it commits, so a file never points at an object which does not exist.
+/// The upload is deleted if the file entry could not be created, interrupted uploads are cleaned up by the purge worker.
+pub async fn create_file(
+    state: &State,
+    uploader: &str,
+    filename: &str,
+    original_name: &str,
+    stored: &StoredObject,
+    options: &UploadOptions,
+) -> Response<Result<FileData, MessageResponse>> {
+    let pending = pending_name(filename);
 
-            // Create thumbnail
-            if IMAGE_EXTS
-                .into_iter()
-                .any(|ext| ext.eq(&extension.to_uppercase()))
-            {
-                // We don't care if this fails. Thumbnail can fail for whatever reason due to image encoding
-                // User/API caller should not expect thumbnail to ALWAYS exist
-                if let Ok(image) = &get_thumbnail_image(&file.bytes) {
-                    let _ = state
-                        .storage
-                        .put_object(&format!("./thumb/{}", &filename), image)
-                        .await;
+    // Encrypted uploads are never deduplicated, the ciphertext differs every time anyway
+    let file_exists = if options.encrypted {
+        None
+    } else {
+        files::Entity::find()
+            .filter(files::Column::Uploader.eq(uploader.to_owned()))
+            .filter(files::Column::Hash.eq(stored.hash.to_owned()))
+            .filter(files::Column::ClientEncrypted.eq(false))
+            .one(&state.database)
+            .await?
+    };
 
-                    file_api.set_thumbnail_url(root_path.clone());
-                }
+    // An expired file which was not purged yet should not block uploading it again
+    let file_exists = match file_exists {
+        Some(file) if is_expired(&file) => {
+            purge_file(state, file).await?;
+            None
+        }
+        v => v,
+    };
+
+    if let Some(file) = file_exists {
+        // The hash is only known after the object was written
+        let _ = state.storage.delete_object(&pending).await;
+
+        // Don't reveal where a file is if it can't be read directly
+        if !FileVisibility::from(file.visibility.clone()).is_direct() {
+            return Ok(Err(MessageResponse::new(
+                StatusCode::CONFLICT,
+                "This file has already been uploaded",
+            )));
+        }
+
+        // Push the URL of the existing file for the matching hash
+        let mut file_data = FileData::from(file);
+        set_public_url(state, &mut file_data);
+
+        let mut object = serde_json::Map::new();
+        object.insert("url".to_string(), json!(&file_data.url));
+
+        return Ok(Err(MessageResponse::new_with_data(
+            StatusCode::CONFLICT,
+            "You have already uploaded this file",
+            serde_json::Value::Object(object),
+        )));
+    }
+
+    // Other uploads may have finished since this one started
+    if let Some(remaining) = remaining_quota(&state.database, uploader).await? {
+        if stored.size as i64 > remaining {
+            let _ = state.storage.delete_object(&pending).await;
+            return Ok(Err(quota_exceeded()));
+        }
+    }
+
+    let existing_blob = if options.encrypted {
+        None
+    } else {
+        find_blob(&state.database, &stored.hash).await?
+    };
+
+    let source = existing_blob
+        .as_ref()
+        .map_or(pending.as_str(), |blob| blob.name.as_str());
+
+    // Nothing can be detected from ciphertext
+    let (metadata, thumbnails) = if options.encrypted {
+        (
+            FileMetadata {
+                mime_type: "application/octet-stream".to_string(),
+                width: None,
+                height: None,
+                duration: None,
+            },
+            Vec::new(),
+        )
+    } else {
+        let metadata = detect_metadata(state.storage.as_ref(), source, filename).await;
+
+        // We don't care if this fails. Thumbnail can fail for whatever reason due to encoding or missing tools
+        // User/API caller should not expect thumbnails to ALWAYS exist
+        let thumbnails = create_thumbnails(
+            state.storage.as_ref(),
+            source,
+            &metadata.mime_type,
+            metadata.duration,
+            &state.thumbnail_sizes,
+            state.thumbnail_format,
+        )
+        .await;
+
+        (
+            metadata,
+            put_thumbnails(state.storage.as_ref(), filename, thumbnails).await,
+        )
+    };
+
+    // Point at the contents another upload already stored, or keep this upload as a new blob
+    let blob = match existing_blob {
+        Some(blob) => {
+            let _ = state.storage.delete_object(&pending).await;
+
+            // Files sharing an object share its tier
+            files::Entity::find()
+                .filter(files::Column::Object.eq(blob.name.to_owned()))
+                .one(&state.database)
+                .await
+                .map(|file| (blob.name, file.map(|file| file.tier), None))
+        }
+        // The upload becomes a new blob under the name it is pending for
+        None => Ok((filename.to_owned(), None, Some(pending.to_owned()))),
+    };
+
+    let (object, tier, promoted) = match blob {
+        Ok(v) => v,
+        Err(err) => {
+            delete_thumbnails(state, filename, &thumbnails).await;
+            return Err(Error::from(err));
+        }
+    };
+
+    let file = files::ActiveModel {
+        uploader: Set(uploader.to_owned()),
+        name: Set(filename.to_owned()),
+        original_name: Set(original_name.to_owned()),
+        hash: Set(stored.hash.to_owned()),
+        size: Set(stored.size as i64),
+        expires: Set(options.expiry().map(|expires| expires.into())),
+        max_downloads: Set(options.max_downloads),
+        visibility: Set(options.visibility.unwrap_or(FileVisibility::Public).into()),
+        mime_type: Set(Some(metadata.mime_type.to_owned())),
+        width: Set(metadata.width),
+        height: Set(metadata.height),
+        duration: Set(metadata.duration),
+        object: Set(object.to_owned()),
+        client_encrypted: Set(options.encrypted),
+        client_metadata: Set(options.metadata.to_owned()),
+        tier: tier.map_or(NotSet, Set),
+        ..Default::default()
+    };
+
+    let file_model = match commit_file(
+        state,
+        file,
+        stored,
+        &object,
+        promoted.as_deref(),
+        &thumbnails,
+    )
+    .await
+    {
+        Ok(v) => v,
+        Err(err) => {
+            // Nothing was committed, a blob which was found is left to the files still pointing at it
+            if let Some(promoted) = &promoted {
+                let _ = state.storage.delete_object(promoted).await;
+                let _ = state.storage.delete_object(&object).await;
             }
+            delete_thumbnails(state, filename, &thumbnails).await;
+            return Err(Error::from(err));
+        }
+    };
 
-            file_api.set_url(root_path.clone());
-            Ok(HttpResponse::Ok().json(file_api))
+    // Objects are written private, the file is removed if it can't be made readable.
+    // A shared blob keeps the access of the file it was uploaded as.
+    if FileVisibility::from(file_model.visibility.clone()).is_direct() {
+        if let Err(err) = set_objects_public(state, &file_model, true).await {
+            purge_file(state, file_model).await?;
+            return Err(Error::from(err));
         }
-        Err(err) => match err {
-            MultipartError::FieldNotFound(_) => Ok(MessageResponse::bad_request().http_response()),
-            MultipartError::PayloadTooLarge(_) => MessageResponse::ok(
-                StatusCode::PAYLOAD_TOO_LARGE,
-                &format!(
-                    "File was larger than the size limit of {}mb",
-                    state.file_size_limit / 1000 / 1000
-                ),
-            ),
-            MultipartError::WriteError(err) => Err(Error::from(err)),
-        },
     }
+
+    let mut file_data = [owner_file_data(state, file_model)];
+    load_thumbnails(state, &mut file_data).await?;
+
+    let [mut file_data] = file_data;
+    if options.strip_metadata(state.strip_metadata) {
+        file_data.metadata_removed = Some(stored.metadata_removed);
+    }
+
+    Ok(Ok(file_data))
+}
+
+/// Insert the file with its blob and thumbnails in one transaction.
+/// The pending object is promoted to the blob name right before committing,
+/// so it only exists under that name if the rows can be committed.
+async fn commit_file(
+    state: &State,
+    file: files::ActiveModel,
+    stored: &StoredObject,
+    object: &str,
+    promoted: Option<&str>,
+    thumbnails: &[(u32, ThumbnailFormat)],
+) -> Result<files::Model, anyhow::Error> {
+    let txn = state.database.begin().await?;
+
+    if promoted.is_some() {
+        create_blob(&txn, object, &stored.hash, stored.size as i64).await?;
+    }
+
+    let file = file.insert(&txn).await?;
+    record_thumbnails(&txn, &file.id, thumbnails).await?;
+
+    if let Some(promoted) = promoted {
+        state.storage.rename_object(promoted, object).await?;
+    }
+
+    if let Err(err) = txn.commit().await {
+        // The commit may have gone through even though its result was lost
+        return match files::Entity::find_by_id(file.id.to_owned())
+            .one(&state.database)
+            .await
+        {
+            Ok(Some(_)) => Ok(file),
+            _ => Err(err.into()),
+        };
+    }
+
+    Ok(file)
+}
+
+/// Delete thumbnails which were written for a file that could not be created
+async fn delete_thumbnails(state: &State, filename: &str, thumbnails: &[(u32, ThumbnailFormat)]) {
+    for (size, format) in thumbnails {
+        let _ = state
+            .storage
+            .delete_object(&thumbnail_name(filename, *size, format.extension()))
+            .await;
+    }
+}
+
+/// Change whether a file and the objects derived from it can be read directly from the storage provider.
+/// Shared contents are only changed by the file they were uploaded as.
+async fn set_objects_public(
+    state: &State,
+    file: &files::Model,
+    public: bool,
+) -> Result<(), anyhow::Error> {
+    if file.object == file.name {
+        state
+            .storage
+            .set_object_public(&file.object, public)
+            .await?;
+    }
+
+    let mut derived = thumbnail_names(&state.database, &file.id, &file.name).await?;
+    derived.extend(transform_names(&state.database, &file.id).await?);
+
+    for name in derived {
+        if let Err(err) = state.storage.set_object_public(&name, public).await {
+            // A derived object left public would stay readable without access to the file
+            if !public {
+                return Err(err);
+            }
+            log::warn!("Unable to make derived object {} public: {}", name, err);
+        }
+    }
+
+    Ok(())
 }
 
 #[get("/stats")]
@@ -139,22 +438,56 @@ async fn stats(
     state: web::Data<State>,
     auth: Auth<auth_role::User, false, true>,
 ) -> Response<impl Responder> {
-    // Im not using an ORM for this query
-    let usage = state
-        .database
-        .query_one(Statement::from_sql_and_values(
-            DbBackend::Postgres,
-            r#"SELECT COALESCE(CAST(SUM(size) AS BIGINT), 0) FROM files WHERE uploader = $1"#,
-            vec![auth.user.id.into()],
-        ))
-        .await?;
+    Ok(HttpResponse::Ok().json(FileStats::new(
+        get_usage(&state.database, &auth.user.id).await?,
+        get_quota(&state.database, &auth.user.id).await?,
+    )))
+}
 
-    Ok(HttpResponse::Ok().json(FileStats {
-        usage: match usage {
-            Some(v) => v.try_get("", "coalesce")?,
-            None => 0,
-        },
-    }))
+#[get("/analytics")]
+async fn user_analytics(
+    state: web::Data<State>,
+    auth: Auth<auth_role::User, false, true>,
+    query: web::Query<AnalyticsQuery>,
+) -> Response<impl Responder> {
+    if let Err(err) = query.validate() {
+        return Ok(err.http_response());
+    }
+
+    Ok(HttpResponse::Ok().json(
+        get_analytics(
+            &state.database,
+            AnalyticsScope::User(&auth.user.id),
+            query.days(),
+        )
+        .await?,
+    ))
+}
+
+#[get("/{file_id}/analytics")]
+async fn file_analytics(
+    state: web::Data<State>,
+    file_id: web::Path<String>,
+    auth: Auth<auth_role::User, false, true>,
+    query: web::Query<AnalyticsQuery>,
+) -> Response<impl Responder> {
+    if let Err(err) = query.validate() {
+        return Ok(err.http_response());
+    }
+
+    let file = match find_owned_file(&state, &auth.user.id, &file_id).await? {
+        Ok(v) => v,
+        Err(err) => return Ok(err.http_response()),
+    };
+
+    Ok(HttpResponse::Ok().json(
+        get_analytics(
+            &state.database,
+            AnalyticsScope::File(&file.id),
+            query.days(),
+        )
+        .await?,
+    ))
 }
 
 #[get("/list/{page_number}")]
@@ -162,17 +495,83 @@ async fn list(
     state: web::Data<State>,
     page_number: web::Path<usize>,
     auth: Auth<auth_role::User, false, true>,
-    query_params: web::Query<HashMap<String, String>>,
+    search: web::Query<FileSearchQuery>,
 ) -> Response<impl Responder> {
-    let query = match query_params.get("query") {
-        Some(str) => files::Column::Name.like(&format!("%{}%", str)),
-        None => SimpleExpr::Custom("true".to_string()),
+    let mut select = files::Entity::find()
+        .filter(files::Column::Uploader.eq(auth.user.id.to_owned()))
+        .filter(not_expired());
+
+    if let Some(query) = search.query.as_deref().and_then(search_query) {
+        select = select.filter(Expr::cust_with_values(
+            "search_vector @@ to_tsquery('simple', ?)",
+            vec![query],
+        ));
+    }
+
+    if let Some(tags) = &search.tags {
+        let tags: Vec<String> = tags
+            .split(',')
+            .map(|tag| tag.trim().to_lowercase())
+            .filter(|tag| !tag.is_empty())
+            .collect();
+
+        if !tags.is_empty() {
+            // Files must have every tag, not just one of them
+            let placeholders = vec!["?"; tags.len()].join(", ");
+            let tag_count = tags.len() as i64;
+
+            let mut values: Vec<Value> = tags.into_iter().map(Value::from).collect();
+            values.push(tag_count.into());
+
+            select = select.filter(Expr::cust_with_values(
+                &format!(
+                    "id IN (SELECT file_id FROM file_tags WHERE tag IN ({}) GROUP BY file_id HAVING COUNT(*) = ?)",
+                    placeholders
+                ),
+                values,
+            ));
+        }
+    }
+
+    if let Some(mime) = &search.mime {
+        select = select.filter(if mime.ends_with('/') {
+            files::Column::MimeType.starts_with(mime)
+        } else {
+            files::Column::MimeType.eq(mime.to_owned())
+        });
+    }
+
+    if let Some(min_size) = search.min_size {
+        select = select.filter(files::Column::Size.gte(min_size));
+    }
+
+    if let Some(max_size) = search.max_size {
+        select = select.filter(files::Column::Size.lte(max_size));
+    }
+
+    if let Some(after) = search.after {
+        select = select.filter(files::Column::Uploaded.gte(after));
+    }
+
+    if let Some(before) = search.before {
+        select = select.filter(files::Column::Uploaded.lte(before));
+    }
+
+    let sort_column = match search.sort {
+        FileSort::Date => files::Column::Uploaded,
+        FileSort::Name => files::Column::OriginalName,
+        FileSort::Size => files::Column::Size,
     };
 
-    let paginator = files::Entity::find()
-        .filter(files::Column::Uploader.eq(auth.user.id.to_owned()))
-        .filter(query)
-        .order_by_desc(files::Column::Uploaded)
+    let order = match search.order {
+        SortOrder::Asc => Order::Asc,
+        SortOrder::Desc => Order::Desc,
+    };
+
+    // Sorted by ID last so pages stay stable when values are equal
+    let paginator = select
+        .order_by(sort_column, order.clone())
+        .order_by(files::Column::Id, order)
         .paginate(&state.database, 25);
 
     let pages = paginator.num_pages().await?;
@@ -180,25 +579,91 @@ async fn list(
         return Ok(err.http_response());
     }
 
-    let storage_url = PathBuf::from(state.storage_url.clone());
+    let mut list: Vec<FileData> = paginator
+        .fetch_page(*page_number - 1)
+        .await?
+        .into_iter()
+        .map(|model| owner_file_data(&state, model))
+        .collect();
+
+    load_tags(&state.database, &mut list).await?;
+    load_thumbnails(&state, &mut list).await?;
 
     Ok(HttpResponse::Ok().json(Page {
         page: *page_number,
         pages,
-        list: paginator
-            .fetch_page(*page_number - 1)
-            .await?
-            .iter()
-            .map(|model| {
-                let mut file_data = FileData::from(model.to_owned());
-                file_data.set_url(storage_url.clone());
-                file_data.set_thumbnail_url(storage_url.clone());
-                file_data
-            })
-            .collect(),
+        list,
     }))
 }
 
+#[get("/tags")]
+async fn tags(
+    state: web::Data<State>,
+    auth: Auth<auth_role::User, false, true>,
+) -> Response<impl Responder> {
+    // Im not using an ORM for this query
+    let tags: Vec<TagData> = state
+        .database
+        .query_all(Statement::from_sql_and_values(
+            DbBackend::Postgres,
+            r#"SELECT tag, COUNT(*) AS count FROM file_tags
+               INNER JOIN files ON files.id = file_tags.file_id
+               WHERE files.uploader = $1
+               GROUP BY tag ORDER BY tag"#,
+            vec![auth.user.id.to_owned().into()],
+        ))
+        .await?
+        .iter()
+        .map(|row| {
+            Ok(TagData {
+                tag: row.try_get("", "tag")?,
+                count: row.try_get("", "count")?,
+            })
+        })
+        .collect::<Result<_, DbErr>>()?;
+
+    Ok(HttpResponse::Ok().json(tags))
+}
+
+#[put("/{file_id}/tags")]
+async fn set_tags(
+    state: web::Data<State>,
+    file_id: web::Path<String>,
+    auth: Auth<auth_role::User, true, true>,
+    form: web::Json<FileTagsForm>,
+) -> Response<impl Responder> {
+    let tags = match normalize_tags(&form.tags) {
+        Ok(v) => v,
+        Err(err) => return Ok(err.http_response()),
+    };
+
+    let file = match find_owned_file(&state, &auth.user.id, &file_id).await? {
+        Ok(v) => v,
+        Err(err) => return Ok(err.http_response()),
+    };
+
+    // Setting tags replaces all of them
+    file_tags::Entity::delete_many()
+        .filter(file_tags::Column::FileId.eq(file.id.to_owned()))
+        .exec(&state.database)
+        .await?;
+
+    if !tags.is_empty() {
+        file_tags::Entity::insert_many(tags.iter().map(|tag| file_tags::ActiveModel {
+            file_id: Set(file.id.to_owned()),
+            tag: Set(tag.to_owned()),
+        }))
+        .exec(&state.database)
+        .await?;
+    }
+
+    let mut file_data = [owner_file_data(&state, file)];
+    file_data[0].tags = tags;
+    load_thumbnails(&state, &mut file_data).await?;
+
+    Ok(HttpResponse::Ok().json(&file_data[0]))
+}
+
 #[get("/{file_id}")]
 async fn info(
     state: web::Data<State>,
@@ -217,14 +682,14 @@ async fn info(
                         "You are not allowed to access this file",
                     )
                     .http_response()
+                } else if is_expired(&v) {
+                    MessageResponse::new(StatusCode::GONE, "That file has expired").http_response()
                 } else {
-                    let storage_url = PathBuf::from(&state.storage_url);
-
-                    let mut file = FileData::from(v);
-                    file.set_url(storage_url.clone());
-                    file.set_thumbnail_url(storage_url);
+                    let mut file_data = [owner_file_data(&state, v)];
+                    load_tags(&state.database, &mut file_data).await?;
+                    load_thumbnails(&state, &mut file_data).await?;
 
-                    HttpResponse::Ok().json(file)
+                    HttpResponse::Ok().json(&file_data[0])
                 }
             }
             None => MessageResponse::new(StatusCode::NOT_FOUND, "That file was not found")
@@ -233,6 +698,441 @@ async fn info(
     )
 }
 
+#[get("/{file_id}/download")]
+async fn download(
+    req: HttpRequest,
+    state: web::Data<State>,
+    file_id: web::Path<String>,
+    auth: Option<Auth<auth_role::User, true, true>>,
+    query: web::Query<FileDownloadQuery>,
+) -> Response<impl Responder> {
+    let file = match files::Entity::find_by_id(file_id.to_string())
+        .one(&state.database)
+        .await?
+    {
+        Some(v) => v,
+        None => return MessageResponse::ok(StatusCode::NOT_FOUND, "That file was not found"),
+    };
+
+    if is_expired(&file) {
+        return MessageResponse::ok(StatusCode::GONE, "That file has expired");
+    }
+
+    if let Err(err) = check_intact(&file) {
+        return Ok(err.http_response());
+    }
+
+    // Uploaders are given signed URLs for files which can't be read from the storage URL
+    let signed = query.token.as_deref().map_or(false, |token| {
+        verify_object_token(&state.jwt_key, &file.id, token)
+    });
+
+    if !signed {
+        if let Err(err) = check_access(
+            &file,
+            auth.as_ref().map(|auth| auth.user.id.as_str()),
+            request_password(&req),
+        ) {
+            return Ok(err.http_response());
+        }
+    }
+
+    if counts_as_download(&req, &file) {
+        count_download(&state.database, &file.id).await?;
+    }
+
+    let disposition = if query.attachment {
+        DispositionType::Attachment
+    } else {
+        DispositionType::Inline
+    };
+
+    serve_file(&req, &state, file, disposition).await
+}
+
+/// Response for images with too many pixels to be decoded
+fn image_too_large() -> Response<HttpResponse> {
+    MessageResponse::ok(
+        StatusCode::BAD_REQUEST,
+        "That image is too large to be transformed",
+    )
+}
+
+/// Thumbnails of objects encrypted at rest can't be read from the storage URL
+#[get("/{file_id}/thumbnail/{size}/{format}")]
+async fn thumbnail(
+    req: HttpRequest,
+    state: web::Data<State>,
+    path: web::Path<(String, u32, ThumbnailFormat)>,
+    auth: Option<Auth<auth_role::User, true, true>>,
+    query: web::Query<FileDownloadQuery>,
+) -> Response<impl Responder> {
+    let (file_id, size, format) = path.into_inner();
+
+    let file = match files::Entity::find_by_id(file_id)
+        .one(&state.database)
+        .await?
+    {
+        Some(v) => v,
+        None => return MessageResponse::ok(StatusCode::NOT_FOUND, "That file was not found"),
+    };
+
+    if is_expired(&file) {
+        return MessageResponse::ok(StatusCode::GONE, "That file has expired");
+    }
+
+    let name = thumbnail_name(&file.name, size, format.extension());
+    let signed = query.token.as_deref().map_or(false, |token| {
+        verify_object_token(&state.jwt_key, &name, token)
+    });
+
+    if !signed {
+        if let Err(err) = check_access(
+            &file,
+            auth.as_ref().map(|auth| auth.user.id.as_str()),
+            request_password(&req),
+        ) {
+            return Ok(err.http_response());
+        }
+    }
+
+    let public = FileVisibility::from(file.visibility).is_direct();
+    Ok(serve_derived(&state, &name, format.content_type(), public).await)
+}
+
+#[get("/{file_id}/image")]
+async fn transform_image(
+    req: HttpRequest,
+    state: web::Data<State>,
+    file_id: web::Path<String>,
+    auth: Option<Auth<auth_role::User, true, true>>,
+    query: web::Query<ImageTransformQuery>,
+) -> Response<impl Responder> {
+    if let Err(err) = query.validate() {
+        return Ok(err.http_response());
+    }
+
+    let file = match files::Entity::find_by_id(file_id.to_string())
+        .one(&state.database)
+        .await?
+    {
+        Some(v) => v,
+        None => return MessageResponse::ok(StatusCode::NOT_FOUND, "That file was not found"),
+    };
+
+    if is_expired(&file) {
+        return MessageResponse::ok(StatusCode::GONE, "That file has expired");
+    }
+
+    if let Err(err) = check_intact(&file) {
+        return Ok(err.http_response());
+    }
+
+    if let Err(err) = check_access(
+        &file,
+        auth.as_ref().map(|auth| auth.user.id.as_str()),
+        request_password(&req),
+    ) {
+        return Ok(err.http_response());
+    }
+
+    let mime_type = file
+        .mime_type
+        .clone()
+        .unwrap_or_else(|| mime_from_name(&file.name));
+
+    if !is_thumbnailable(&mime_type) {
+        return MessageResponse::ok(StatusCode::BAD_REQUEST, "That file is not an image");
+    }
+
+    // Decoding allocates for every pixel regardless of the output size
+    if file
+        .width
+        .zip(file.height)
+        .map_or(false, |(width, height)| {
+            width as i64 * height as i64 > MAX_SOURCE_PIXELS
+        })
+    {
+        return image_too_large();
+    }
+
+    let transform = ImageTransform::new(&query, &mime_type);
+    let content_type = transform.format.content_type();
+    let name = transform.name(&file.name);
+
+    // Transformations are views of the file, they don't use up its downloads
+    record_access(&state.database, &file.id).await?;
+
+    let bytes = match state.storage.get_object_bytes(&name).await {
+        Ok(v) => v,
+        Err(_) => {
+            let source = state.storage.get_object_bytes(&file.object).await?;
+
+            // Files whose dimensions are unknown are checked before they are decoded
+            if file.width.is_none() || file.height.is_none() {
+                match image_dimensions(&source) {
+                    Ok((width, height)) if width as i64 * height as i64 <= MAX_SOURCE_PIXELS => {}
+                    Ok(_) => return image_too_large(),
+                    Err(_) => {
+                        return MessageResponse::ok(
+                            StatusCode::UNPROCESSABLE_ENTITY,
+                            "That image could not be transformed",
+                        )
+                    }
+                }
+            }
+
+            // Encoding is slow, keep it off the async workers
+            let bytes = match tokio::task::spawn_blocking(move || transform.apply(&source)).await? {
+                Ok(v) => v,
+                Err(_) => {
+                    return MessageResponse::ok(
+                        StatusCode::UNPROCESSABLE_ENTITY,
+                        "That image could not be transformed",
+                    )
+                }
+            };
+
+            if let Err(err) = cache_transform(&state, &file, &name, bytes.clone()).await {
+                log::warn!("Unable to cache {}: {}", name, err);
+            }
+
+            bytes
+        }
+    };
+
+    record_view(&state.database, &file.id, &req, bytes.len() as u64).await;
+
+    // The same parameters always produce the same image
+    let cache_control = if FileVisibility::from(file.visibility).is_direct() {
+        CacheDirective::Public
+    } else {
+        CacheDirective::Private
+    };
+
+    Ok(HttpResponse::Ok()
+        .content_type(content_type)
+        .insert_header(CacheControl(vec![
+            cache_control,
+            CacheDirective::MaxAge(24 * 60 * 60),
+        ]))
+        .body(bytes))
+}
+
+/// Find a file owned by the user
+async fn find_owned_file(
+    state: &State,
+    user_id: &str,
+    file_id: &str,
+) -> Response<Result<files::Model, MessageResponse>> {
+    Ok(
+        match files::Entity::find_by_id(file_id.to_string())
+            .one(&state.database)
+            .await?
+        {
+            Some(v) => {
+                if v.uploader != user_id {
+                    Err(MessageResponse::new(
+                        StatusCode::FORBIDDEN,
+                        "You are not allowed to access this file",
+                    ))
+                } else {
+                    Ok(v)
+                }
+            }
+            None => Err(MessageResponse::new(
+                StatusCode::NOT_FOUND,
+                "That file was not found",
+            )),
+        },
+    )
+}
+
+#[post("/{file_id}/shares")]
+async fn create_share(
+    state: web::Data<State>,
+    file_id: web::Path<String>,
+    auth: Auth<auth_role::User, true, true>,
+    form: web::Json<ShareCreateForm>,
+) -> Response<impl Responder> {
+    if let Err(err) = form.validate() {
+        return Ok(err.http_response());
+    }
+
+    let file = match find_owned_file(&state, &auth.user.id, &file_id).await? {
+        Ok(v) => v,
+        Err(err) => return Ok(err.http_response()),
+    };
+
+    if is_expired(&file) {
+        return MessageResponse::ok(StatusCode::GONE, "That file has expired");
+    }
+
+    // Stored without sub-second precision so the signed timestamp matches exactly
+    let expires = Utc.timestamp(form.expiry().timestamp(), 0);
+
+    let share = file_shares::ActiveModel {
+        file_id: Set(file.id),
+        expires: Set(expires.into()),
+        max_downloads: Set(form.max_downloads),
+        ..Default::default()
+    }
+    .insert(&state.database)
+    .await?;
+
+    let url = share_url(&state, &share);
+    let page_url = Some(share_page_url(&state, &share)).filter(|_| file.client_encrypted);
+
+    Ok(HttpResponse::Ok().json(ShareData {
+        page_url,
+        ..ShareData::new(share, url)
+    }))
+}
+
+#[get("/{file_id}/shares")]
+async fn list_shares(
+    state: web::Data<State>,
+    file_id: web::Path<String>,
+    auth: Auth<auth_role::User, true, true>,
+) -> Response<impl Responder> {
+    let file = match find_owned_file(&state, &auth.user.id, &file_id).await? {
+        Ok(v) => v,
+        Err(err) => return Ok(err.http_response()),
+    };
+
+    let shares: Vec<ShareData> = file
+        .find_related(file_shares::Entity)
+        .filter(file_shares::Column::Expires.gt(Utc::now()))
+        .order_by_desc(file_shares::Column::Created)
+        .all(&state.database)
+        .await?
+        .into_iter()
+        .map(|share| {
+            let url = share_url(&state, &share);
+            let page_url = Some(share_page_url(&state, &share)).filter(|_| file.client_encrypted);
+
+            ShareData {
+                page_url,
+                ..ShareData::new(share, url)
+            }
+        })
+        .collect();
+
+    Ok(HttpResponse::Ok().json(shares))
+}
+
+#[delete("/{file_id}/shares/{share_id}")]
+async fn revoke_share(
+    state: web::Data<State>,
+    path: web::Path<(String, String)>,
+    auth: Auth<auth_role::User, true, true>,
+) -> Response<impl Responder> {
+    let (file_id, share_id) = path.into_inner();
+
+    let file = match find_owned_file(&state, &auth.user.id, &file_id).await? {
+        Ok(v) => v,
+        Err(err) => return Ok(err.http_response()),
+    };
+
+    let result = file_shares::Entity::delete_many()
+        .filter(file_shares::Column::Id.eq(share_id))
+        .filter(file_shares::Column::FileId.eq(file.id))
+        .exec(&state.database)
+        .await?;
+
+    if result.rows_affected < 1 {
+        return MessageResponse::ok(StatusCode::NOT_FOUND, "That share was not found");
+    }
+
+    MessageResponse::ok(StatusCode::OK, "Share link was revoked")
+}
+
+#[put("/move")]
+async fn move_files(
+    state: web::Data<State>,
+    auth: Auth<auth_role::User, true, true>,
+    form: web::Json<FileMoveForm>,
+) -> Response<impl Responder> {
+    if let Some(folder) = &form.folder {
+        if let Err(err) = find_owned_folder(&state, &auth.user.id, folder).await? {
+            return Ok(err.http_response());
+        }
+    }
+
+    // Files which belong to other users are ignored
+    let result = files::Entity::update_many()
+        .col_expr(files::Column::Folder, Expr::value(form.folder.to_owned()))
+        .filter(files::Column::Id.is_in(form.files.to_owned()))
+        .filter(files::Column::Uploader.eq(auth.user.id.to_owned()))
+        .exec(&state.database)
+        .await?;
+
+    MessageResponse::ok(
+        StatusCode::OK,
+        &format!("{} files were moved", result.rows_affected),
+    )
+}
+
+#[put("/{file_id}/visibility")]
+async fn visibility(
+    state: web::Data<State>,
+    file_id: web::Path<String>,
+    auth: Auth<auth_role::User, true, true>,
+    form: web::Json<FileVisibilityForm>,
+) -> Response<impl Responder> {
+    let file = match files::Entity::find_by_id(file_id.to_string())
+        .one(&state.database)
+        .await?
+    {
+        Some(v) => v,
+        None => return MessageResponse::ok(StatusCode::NOT_FOUND, "That file was not found"),
+    };
+
+    if file.uploader != auth.user.id {
+        return MessageResponse::ok(
+            StatusCode::FORBIDDEN,
+            "You are not allowed to access this file",
+        );
+    }
+
+    let password = match (form.visibility, &form.password) {
+        (FileVisibility::Password, Some(password)) => match new_password(password)? {
+            Ok(v) => Some(v),
+            Err(err) => return Ok(err.http_response()),
+        },
+        (FileVisibility::Password, None) => {
+            return MessageResponse::ok(StatusCode::BAD_REQUEST, "A password is required")
+        }
+        _ => None,
+    };
+
+    // Objects are restricted before the file is and opened after it is,
+    // so they are never readable directly while the file requires access
+    let public = form.visibility.is_direct();
+    if !public {
+        set_objects_public(&state, &file, false).await?;
+    }
+
+    let file = files::ActiveModel {
+        id: Set(file.id),
+        visibility: Set(form.visibility.into()),
+        password: Set(password),
+        ..Default::default()
+    }
+    .update(&state.database)
+    .await?;
+
+    if public {
+        set_objects_public(&state, &file, true).await?;
+    }
+
+    let mut file_data = [owner_file_data(&state, file)];
+    load_tags(&state.database, &mut file_data).await?;
+    load_thumbnails(&state, &mut file_data).await?;
+
+    Ok(HttpResponse::Ok().json(&file_data[0]))
+}
+
 #[delete("/{file_id}")]
 async fn delete_file(
     state: web::Data<State>,
@@ -251,16 +1151,10 @@ async fn delete_file(
                         "You are not allowed to access this file",
                     )
                 } else {
-                    v.clone().delete(&state.database).await?;
-
-                    // We dont care about the result of this because of discrepancies
-                    let _ = state.storage.delete_object(&v.name).await;
-                    let _ = state
-                        .storage
-                        .delete_object(&format!("thumb/{}", &v.name))
-                        .await;
+                    let name = v.name.to_owned();
+                    purge_file(&state, v).await?;
 
-                    MessageResponse::new(StatusCode::OK, &format!("File {} was deleted", v.name))
+                    MessageResponse::new(StatusCode::OK, &format!("File {} was deleted", name))
                 }
             }
             None => MessageResponse::new(StatusCode::NOT_FOUND, "That file was not found"),
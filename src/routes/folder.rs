@@ -0,0 +1,407 @@
+use actix_web::{delete, get, http::StatusCode, post, put, web, HttpResponse, Responder, Scope};
+use sea_orm::{
+    ActiveModelTrait, ColumnTrait, EntityTrait, ModelTrait, PaginatorTrait, QueryFilter,
+    QueryOrder, Set,
+};
+
+use crate::{
+    database::entity::{files, folders, sea_orm_active_enums::Visibility, users},
+    models::{
+        AlbumData, FileData, FolderCreateForm, FolderData, FolderInfo, FolderMoveForm,
+        FolderUpdateForm, MessageResponse, Page, Response,
+    },
+    state::State,
+    util::{
+        access::{owner_file_data, set_public_url},
+        auth::{auth_role, Auth},
+        expiry::not_expired,
+        tags::load_tags,
+        thumbnail::load_thumbnails,
+        validate_paginate,
+    },
+};
+
+pub fn get_routes() -> Scope {
+    web::scope("/folder")
+        .service(list)
+        .service(create)
+        .service(info)
+        .service(list_files)
+        .service(album)
+        .service(update)
+        .service(move_folder)
+        .service(delete)
+}
+
+/// Find a folder owned by the user
+pub async fn find_owned_folder(
+    state: &State,
+    user_id: &str,
+    folder_id: &str,
+) -> Response<Result<folders::Model, MessageResponse>> {
+    Ok(
+        match folders::Entity::find_by_id(folder_id.to_string())
+            .one(&state.database)
+            .await?
+        {
+            Some(v) => {
+                if v.owner != user_id {
+                    Err(MessageResponse::new(
+                        StatusCode::FORBIDDEN,
+                        "You are not allowed to access this folder",
+                    ))
+                } else {
+                    Ok(v)
+                }
+            }
+            None => Err(MessageResponse::new(
+                StatusCode::NOT_FOUND,
+                "That folder was not found",
+            )),
+        },
+    )
+}
+
+fn validate_name(name: &str) -> Result<(), MessageResponse> {
+    if name.trim().is_empty() {
+        Err(MessageResponse::new(
+            StatusCode::BAD_REQUEST,
+            "Folder name can not be empty",
+        ))
+    } else if name.len() > 64 {
+        Err(MessageResponse::new(
+            StatusCode::BAD_REQUEST,
+            "Folder name too long (maximum 64 characters)",
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+/// Check if a folder with the name already exists in the parent folder
+async fn name_taken(
+    state: &State,
+    owner: &str,
+    parent: Option<&str>,
+    name: &str,
+) -> Response<bool> {
+    let parent_filter = match parent {
+        Some(parent) => folders::Column::Parent.eq(parent),
+        None => folders::Column::Parent.is_null(),
+    };
+
+    Ok(folders::Entity::find()
+        .filter(folders::Column::Owner.eq(owner))
+        .filter(parent_filter)
+        .filter(folders::Column::Name.eq(name))
+        .one(&state.database)
+        .await?
+        .is_some())
+}
+
+/// Check if a folder is the same as or nested anywhere inside of another folder
+async fn is_inside(state: &State, folder_id: &str, ancestor_id: &str) -> Response<bool> {
+    let mut current = Some(folder_id.to_string());
+
+    while let Some(id) = current {
+        if id == ancestor_id {
+            return Ok(true);
+        }
+
+        current = folders::Entity::find_by_id(id)
+            .one(&state.database)
+            .await?
+            .and_then(|folder| folder.parent);
+    }
+
+    Ok(false)
+}
+
+/// List top level folders
+#[get("")]
+async fn list(
+    state: web::Data<State>,
+    auth: Auth<auth_role::User, false, true>,
+) -> Response<impl Responder> {
+    let folders: Vec<FolderData> = auth
+        .user
+        .find_related(folders::Entity)
+        .filter(folders::Column::Parent.is_null())
+        .order_by_asc(folders::Column::Name)
+        .all(&state.database)
+        .await?
+        .into_iter()
+        .map(FolderData::from)
+        .collect();
+
+    Ok(HttpResponse::Ok().json(folders))
+}
+
+#[post("")]
+async fn create(
+    state: web::Data<State>,
+    auth: Auth<auth_role::User, false, true>,
+    form: web::Json<FolderCreateForm>,
+) -> Response<impl Responder> {
+    if let Err(err) = validate_name(&form.name) {
+        return Ok(err.http_response());
+    }
+
+    if let Some(parent) = &form.parent {
+        if let Err(err) = find_owned_folder(&state, &auth.user.id, parent).await? {
+            return Ok(err.http_response());
+        }
+    }
+
+    if name_taken(&state, &auth.user.id, form.parent.as_deref(), &form.name).await? {
+        return MessageResponse::ok(
+            StatusCode::CONFLICT,
+            "A folder with that name already exists",
+        );
+    }
+
+    let folder = folders::ActiveModel {
+        owner: Set(auth.user.id.to_owned()),
+        parent: Set(form.parent.to_owned()),
+        name: Set(form.name.to_owned()),
+        public: Set(form.public),
+        ..Default::default()
+    }
+    .insert(&state.database)
+    .await?;
+
+    Ok(HttpResponse::Ok().json(FolderData::from(folder)))
+}
+
+#[get("/{folder_id}")]
+async fn info(
+    state: web::Data<State>,
+    folder_id: web::Path<String>,
+    auth: Auth<auth_role::User, false, true>,
+) -> Response<impl Responder> {
+    let folder = match find_owned_folder(&state, &auth.user.id, &folder_id).await? {
+        Ok(v) => v,
+        Err(err) => return Ok(err.http_response()),
+    };
+
+    let folders: Vec<FolderData> = folders::Entity::find()
+        .filter(folders::Column::Parent.eq(folder.id.to_owned()))
+        .order_by_asc(folders::Column::Name)
+        .all(&state.database)
+        .await?
+        .into_iter()
+        .map(FolderData::from)
+        .collect();
+
+    Ok(HttpResponse::Ok().json(FolderInfo {
+        folder: FolderData::from(folder),
+        folders,
+    }))
+}
+
+/// List files directly inside of a folder
+#[get("/{folder_id}/list/{page_number}")]
+async fn list_files(
+    state: web::Data<State>,
+    path: web::Path<(String, usize)>,
+    auth: Auth<auth_role::User, false, true>,
+) -> Response<impl Responder> {
+    let (folder_id, page_number) = path.into_inner();
+
+    let folder = match find_owned_folder(&state, &auth.user.id, &folder_id).await? {
+        Ok(v) => v,
+        Err(err) => return Ok(err.http_response()),
+    };
+
+    let paginator = folder
+        .find_related(files::Entity)
+        .filter(not_expired())
+        .order_by_desc(files::Column::Uploaded)
+        .paginate(&state.database, 25);
+
+    let pages = paginator.num_pages().await?;
+    if let Some(err) = validate_paginate(page_number, pages) {
+        return Ok(err.http_response());
+    }
+
+    let mut list: Vec<FileData> = paginator
+        .fetch_page(page_number - 1)
+        .await?
+        .into_iter()
+        .map(|model| owner_file_data(&state, model))
+        .collect();
+
+    load_tags(&state.database, &mut list).await?;
+    load_thumbnails(&state, &mut list).await?;
+
+    Ok(HttpResponse::Ok().json(Page {
+        page: page_number,
+        pages,
+        list,
+    }))
+}
+
+/// Public view of a folder, only files which are directly readable are shown
+#[get("/{folder_id}/album/{page_number}")]
+async fn album(
+    state: web::Data<State>,
+    path: web::Path<(String, usize)>,
+) -> Response<impl Responder> {
+    let (folder_id, page_number) = path.into_inner();
+
+    // Private folders should not be known to exist
+    let folder = match folders::Entity::find_by_id(folder_id)
+        .one(&state.database)
+        .await?
+    {
+        Some(v) if v.public => v,
+        _ => return MessageResponse::ok(StatusCode::NOT_FOUND, "That album was not found"),
+    };
+
+    let owner = match folder
+        .find_related(users::Entity)
+        .one(&state.database)
+        .await?
+    {
+        Some(v) => v,
+        None => return MessageResponse::ok(StatusCode::NOT_FOUND, "That album was not found"),
+    };
+
+    let paginator = folder
+        .find_related(files::Entity)
+        .filter(files::Column::Visibility.is_in(vec![Visibility::Public, Visibility::Unlisted]))
+        .filter(not_expired())
+        .order_by_desc(files::Column::Uploaded)
+        .paginate(&state.database, 25);
+
+    let pages = paginator.num_pages().await?;
+    if let Some(err) = validate_paginate(page_number, pages) {
+        return Ok(err.http_response());
+    }
+
+    let mut list: Vec<FileData> = paginator
+        .fetch_page(page_number - 1)
+        .await?
+        .into_iter()
+        .map(|model| {
+            let mut file_data = FileData::from(model);
+            set_public_url(&state, &mut file_data);
+            file_data
+        })
+        .collect();
+
+    load_tags(&state.database, &mut list).await?;
+    load_thumbnails(&state, &mut list).await?;
+
+    Ok(HttpResponse::Ok().json(AlbumData {
+        name: folder.name,
+        owner: owner.username,
+        created: folder.created.into(),
+        files: Page {
+            page: page_number,
+            pages,
+            list,
+        },
+    }))
+}
+
+#[put("/{folder_id}")]
+async fn update(
+    state: web::Data<State>,
+    folder_id: web::Path<String>,
+    auth: Auth<auth_role::User, false, true>,
+    form: web::Json<FolderUpdateForm>,
+) -> Response<impl Responder> {
+    let folder = match find_owned_folder(&state, &auth.user.id, &folder_id).await? {
+        Ok(v) => v,
+        Err(err) => return Ok(err.http_response()),
+    };
+
+    let mut active_folder: folders::ActiveModel = folder.clone().into();
+
+    if let Some(name) = &form.name {
+        if let Err(err) = validate_name(name) {
+            return Ok(err.http_response());
+        }
+
+        if name != &folder.name
+            && name_taken(&state, &auth.user.id, folder.parent.as_deref(), name).await?
+        {
+            return MessageResponse::ok(
+                StatusCode::CONFLICT,
+                "A folder with that name already exists",
+            );
+        }
+
+        active_folder.name = Set(name.to_owned());
+    }
+
+    if let Some(public) = form.public {
+        active_folder.public = Set(public);
+    }
+
+    let folder = active_folder.update(&state.database).await?;
+    Ok(HttpResponse::Ok().json(FolderData::from(folder)))
+}
+
+#[put("/{folder_id}/move")]
+async fn move_folder(
+    state: web::Data<State>,
+    folder_id: web::Path<String>,
+    auth: Auth<auth_role::User, false, true>,
+    form: web::Json<FolderMoveForm>,
+) -> Response<impl Responder> {
+    let folder = match find_owned_folder(&state, &auth.user.id, &folder_id).await? {
+        Ok(v) => v,
+        Err(err) => return Ok(err.http_response()),
+    };
+
+    if let Some(parent) = &form.parent {
+        if let Err(err) = find_owned_folder(&state, &auth.user.id, parent).await? {
+            return Ok(err.http_response());
+        }
+
+        if is_inside(&state, parent, &folder.id).await? {
+            return MessageResponse::ok(
+                StatusCode::BAD_REQUEST,
+                "A folder can not be moved inside of itself",
+            );
+        }
+    }
+
+    if name_taken(&state, &auth.user.id, form.parent.as_deref(), &folder.name).await? {
+        return MessageResponse::ok(
+            StatusCode::CONFLICT,
+            "A folder with that name already exists",
+        );
+    }
+
+    let folder = folders::ActiveModel {
+        id: Set(folder.id),
+        parent: Set(form.parent.to_owned()),
+        ..Default::default()
+    }
+    .update(&state.database)
+    .await?;
+
+    Ok(HttpResponse::Ok().json(FolderData::from(folder)))
+}
+
+/// Delete a folder and every folder inside of it.
+/// Files inside are not deleted, they are moved out of any folder.
+#[delete("/{folder_id}")]
+async fn delete(
+    state: web::Data<State>,
+    folder_id: web::Path<String>,
+    auth: Auth<auth_role::User, false, true>,
+) -> Response<impl Responder> {
+    let folder = match find_owned_folder(&state, &auth.user.id, &folder_id).await? {
+        Ok(v) => v,
+        Err(err) => return Ok(err.http_response()),
+    };
+
+    let name = folder.name.to_owned();
+    folder.delete(&state.database).await?;
+
+    MessageResponse::ok(StatusCode::OK, &format!("Folder {} was deleted", name))
+}
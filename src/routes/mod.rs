@@ -10,6 +10,10 @@ pub mod admin;
 pub mod application;
 pub mod auth;
 pub mod file;
+pub mod folder;
+pub mod share;
+pub mod tus;
+pub mod upload;
 pub mod user;
 
 pub fn get_routes() -> Scope {
@@ -29,6 +33,7 @@ async fn info(state: web::Data<State>) -> Response<impl Responder> {
             app_name: settings.app_name,
             app_description: settings.app_description,
             color: settings.color,
+            default_quota: settings.default_quota,
         },
         state.invite_only,
         state.smtp_client.is_some(),
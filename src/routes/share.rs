@@ -0,0 +1,169 @@
+use actix_web::{
+    get,
+    http::{
+        header::{self, CacheControl, CacheDirective, DispositionType},
+        StatusCode,
+    },
+    web, HttpRequest, HttpResponse, Responder, Scope,
+};
+use sea_orm::{ConnectionTrait, DbBackend, DbErr, EntityTrait, Statement};
+
+use crate::{
+    database::entity::{file_shares, files},
+    models::{MessageResponse, Response, ShareQuery},
+    state::State,
+    util::{
+        expiry::{count_download, is_expired},
+        serve::{check_intact, is_not_modified, serve_file},
+        signature::verify_share,
+    },
+};
+
+/// Page decrypting files encrypted by the client, the metadata is filled in when served
+const ENCRYPTED_SHARE_PAGE: &str = include_str!("../resources/encrypted_share.html");
+
+pub fn get_routes() -> Scope {
+    web::scope("/share").service(page).service(download)
+}
+
+/// Use up one download of a share, false if it has no downloads left
+async fn claim_download(state: &State, share_id: &str) -> Result<bool, DbErr> {
+    // Checked and incremented at once so concurrent downloads can't go over the limit
+    let result = state
+        .database
+        .execute(Statement::from_sql_and_values(
+            DbBackend::Postgres,
+            r#"UPDATE file_shares SET downloads = downloads + 1
+               WHERE id = $1 AND (max_downloads IS NULL OR downloads < max_downloads)"#,
+            vec![share_id.into()],
+        ))
+        .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+/// Find the share and file of a share link, the link must be valid and not revoked
+async fn find_share(
+    state: &State,
+    share_id: &str,
+    query: &ShareQuery,
+) -> Response<Result<(file_shares::Model, files::Model), MessageResponse>> {
+    if !verify_share(
+        &state.jwt_key,
+        share_id,
+        &query.file,
+        query.expires,
+        query.downloads,
+        &query.signature,
+    ) {
+        return Ok(Err(MessageResponse::new(
+            StatusCode::FORBIDDEN,
+            "This share link is invalid or has expired",
+        )));
+    }
+
+    // Links stop working once revoked even though the signature is still valid
+    let share = match file_shares::Entity::find_by_id(share_id.to_string())
+        .one(&state.database)
+        .await?
+    {
+        Some(v) => v,
+        None => {
+            return Ok(Err(MessageResponse::new(
+                StatusCode::NOT_FOUND,
+                "This share link was revoked",
+            )))
+        }
+    };
+
+    if share.file_id != query.file
+        || share.expires.timestamp() != query.expires
+        || share.max_downloads != query.downloads
+    {
+        return Ok(Err(MessageResponse::new(
+            StatusCode::FORBIDDEN,
+            "This share link is invalid or has expired",
+        )));
+    }
+
+    let file = match files::Entity::find_by_id(share.file_id.to_owned())
+        .one(&state.database)
+        .await?
+    {
+        Some(v) => v,
+        None => {
+            return Ok(Err(MessageResponse::new(
+                StatusCode::NOT_FOUND,
+                "That file was not found",
+            )))
+        }
+    };
+
+    if is_expired(&file) {
+        return Ok(Err(MessageResponse::new(
+            StatusCode::GONE,
+            "That file has expired",
+        )));
+    }
+
+    if let Err(err) = check_intact(&file) {
+        return Ok(Err(err));
+    }
+
+    Ok(Ok((share, file)))
+}
+
+#[get("/{share_id}")]
+async fn download(
+    req: HttpRequest,
+    state: web::Data<State>,
+    share_id: web::Path<String>,
+    query: web::Query<ShareQuery>,
+) -> Response<impl Responder> {
+    let (share, file) = match find_share(&state, &share_id, &query).await? {
+        Ok(v) => v,
+        Err(err) => return Ok(err.http_response()),
+    };
+
+    // Anything but a revalidation uses up a download so ranges can't get around the limit
+    if !is_not_modified(&req, &file) {
+        if !claim_download(&state, &share.id).await? {
+            return MessageResponse::ok(StatusCode::GONE, "This share link has no downloads left");
+        }
+
+        count_download(&state.database, &file.id).await?;
+    }
+
+    serve_file(&req, &state, file, DispositionType::Inline).await
+}
+
+/// Page decrypting a file which was encrypted by the client.
+/// The key is only ever in the URL fragment, the page downloads the ciphertext through the share link.
+#[get("/{share_id}/page")]
+async fn page(
+    state: web::Data<State>,
+    share_id: web::Path<String>,
+    query: web::Query<ShareQuery>,
+) -> Response<impl Responder> {
+    let (_, file) = match find_share(&state, &share_id, &query).await? {
+        Ok(v) => v,
+        Err(err) => return Ok(err.http_response()),
+    };
+
+    if !file.client_encrypted {
+        return MessageResponse::ok(StatusCode::BAD_REQUEST, "That file is not encrypted");
+    }
+
+    // Metadata is validated as base64 when uploading, so it can't break out of the string
+    let metadata = serde_json::to_string(&file.client_metadata)?;
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .insert_header(CacheControl(vec![CacheDirective::NoStore]))
+        .insert_header((header::REFERRER_POLICY, "no-referrer"))
+        .insert_header((
+            header::CONTENT_SECURITY_POLICY,
+            "default-src 'none'; script-src 'unsafe-inline'; style-src 'unsafe-inline'; connect-src 'self'",
+        ))
+        .body(ENCRYPTED_SHARE_PAGE.replace("{{metadata}}", &metadata)))
+}
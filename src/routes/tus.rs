@@ -0,0 +1,526 @@
+//! Upload endpoint implementing the [tus](https://tus.io/protocols/resumable-upload.html) 1.0.0 protocol.
+//! Supports the creation, termination and checksum extensions.
+
+use std::str::FromStr;
+
+use actix_web::{
+    delete, head, http::StatusCode, options, patch, post, web, HttpRequest, HttpResponse,
+    HttpResponseBuilder, Responder, ResponseError, Scope,
+};
+use futures::StreamExt;
+use nanoid::nanoid;
+use sea_orm::{
+    sea_query::Expr, ActiveModelTrait, ColumnTrait, EntityTrait, ModelTrait, QueryFilter, Set,
+};
+use sha1::{Digest, Sha1};
+
+use crate::{
+    database::entity::tus_uploads,
+    models::{Error, FileData, MessageResponse, Response, UploadOptions},
+    routes::file::create_file,
+    state::State,
+    storage::{chunk_object_name, concat_objects, pending_name},
+    util::{
+        auth::{auth_role, Auth},
+        file::{hash_object, new_file_name, store_stream, MultipartError},
+        quota::{quota_exceeded, remaining_quota},
+        strip::strip_stored_object,
+    },
+};
+
+const TUS_VERSION: &str = "1.0.0";
+const TUS_EXTENSIONS: &str = "creation,termination,checksum";
+const TUS_CHECKSUM_ALGORITHMS: &str = "sha1,sha256";
+
+/// Status code for a failed `Upload-Checksum`, defined by the checksum extension
+const CHECKSUM_MISMATCH: u16 = 460;
+
+pub fn get_routes() -> Scope {
+    web::scope("/tus")
+        .service(capabilities)
+        .service(create)
+        .service(offset)
+        .service(append)
+        .service(terminate)
+}
+
+enum Checksum {
+    Sha1(Vec<u8>),
+    Sha256(Vec<u8>),
+}
+
+/// Response builder with the headers required on every tus response
+fn tus_response(status: StatusCode) -> HttpResponseBuilder {
+    let mut builder = HttpResponse::build(status);
+    builder.insert_header(("Tus-Resumable", TUS_VERSION));
+    builder
+}
+
+fn tus_error(status: StatusCode, message: &str) -> HttpResponse {
+    tus_response(status).json(MessageResponse::new(status, message))
+}
+
+fn get_header<T: FromStr>(req: &HttpRequest, name: &str) -> Option<T> {
+    req.headers()
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<T>().ok())
+}
+
+/// Make sure the client is using a supported protocol version
+fn check_version(req: &HttpRequest) -> Option<HttpResponse> {
+    match get_header::<String>(req, "Tus-Resumable") {
+        Some(version) if version == TUS_VERSION => None,
+        _ => Some(
+            tus_response(StatusCode::PRECONDITION_FAILED)
+                .insert_header(("Tus-Version", TUS_VERSION))
+                .finish(),
+        ),
+    }
+}
+
+/// Get the original file name from the `Upload-Metadata` header
+fn metadata_filename(req: &HttpRequest) -> Option<String> {
+    let metadata: String = get_header(req, "Upload-Metadata")?;
+
+    metadata.split(',').find_map(|pair| {
+        let mut parts = pair.trim().splitn(2, ' ');
+        match (parts.next(), parts.next()) {
+            (Some("filename"), Some(value)) | (Some("name"), Some(value)) => base64::decode(value)
+                .ok()
+                .and_then(|value| String::from_utf8(value).ok()),
+            _ => None,
+        }
+    })
+}
+
+/// Parse the `Upload-Checksum` header, `Err` if the algorithm is not supported
+fn parse_checksum(req: &HttpRequest) -> Result<Option<Checksum>, ()> {
+    let header: String = match get_header(req, "Upload-Checksum") {
+        Some(v) => v,
+        None => return Ok(None),
+    };
+
+    let mut parts = header.splitn(2, ' ');
+    let (algorithm, value) = match (parts.next(), parts.next()) {
+        (Some(algorithm), Some(value)) => (algorithm, base64::decode(value).map_err(|_| ())?),
+        _ => return Err(()),
+    };
+
+    match algorithm {
+        "sha1" => Ok(Some(Checksum::Sha1(value))),
+        "sha256" => Ok(Some(Checksum::Sha256(value))),
+        _ => Err(()),
+    }
+}
+
+/// Find a tus upload owned by the user
+async fn find_upload(
+    state: &State,
+    user_id: &str,
+    upload_id: &str,
+) -> Response<Result<tus_uploads::Model, HttpResponse>> {
+    Ok(
+        match tus_uploads::Entity::find_by_id(upload_id.to_string())
+            .one(&state.database)
+            .await?
+        {
+            Some(v) => {
+                if v.uploader != user_id {
+                    Err(tus_error(
+                        StatusCode::FORBIDDEN,
+                        "You are not allowed to access this upload",
+                    ))
+                } else {
+                    Ok(v)
+                }
+            }
+            None => Err(tus_error(
+                StatusCode::NOT_FOUND,
+                "That upload was not found",
+            )),
+        },
+    )
+}
+
+/// Assemble the parts of a finished upload and create the file.
+/// The upload and its parts are kept until the file was created, so completing it can be retried.
+async fn complete_upload(
+    state: &State,
+    upload: tus_uploads::Model,
+) -> Response<Result<FileData, HttpResponse>> {
+    let parts: Vec<String> = (1..=upload.parts)
+        .map(|part| chunk_object_name(&upload.id, part))
+        .collect();
+
+    let pending = pending_name(&upload.name);
+    if let Err(err) = concat_objects(state.storage.as_ref(), &pending, &parts).await {
+        log::error!("Error assembling {}: {}", upload.name, err);
+        return Ok(Err(tus_error(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Unable to assemble file",
+        )));
+    }
+
+    let stored = match hash_object(state.storage.as_ref(), &pending).await {
+        Ok(v) => v,
+        Err(err) => {
+            let _ = state.storage.delete_object(&pending).await;
+            return Err(Error::from(err));
+        }
+    };
+
+    if stored.size as i64 != upload.length {
+        let _ = state.storage.delete_object(&pending).await;
+        return Ok(Err(tus_error(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Assembled file did not match Upload-Length",
+        )));
+    }
+
+    // Parts are written as they arrive, so metadata can only be removed once assembled
+    let stored = if state.strip_metadata {
+        match strip_stored_object(state.storage.as_ref(), &pending, stored).await {
+            Ok(v) => v,
+            Err(err) => {
+                let _ = state.storage.delete_object(&pending).await;
+                return Err(Error::from(err));
+            }
+        }
+    } else {
+        stored
+    };
+
+    let file_data = match create_file(
+        state,
+        &upload.uploader,
+        &upload.name,
+        &upload.original_name,
+        &stored,
+        &UploadOptions::default(),
+    )
+    .await?
+    {
+        Ok(v) => v,
+        Err(err) => return Ok(Err(err.http_response())),
+    };
+
+    upload.delete(&state.database).await?;
+
+    for part in &parts {
+        if let Err(err) = state.storage.delete_object(part).await {
+            log::warn!("Unable to delete upload part {}: {}", part, err);
+        }
+    }
+
+    Ok(Ok(file_data))
+}
+
+/// Response to the request which completed an upload, file information is passed in headers
+fn completed_response(status: StatusCode, offset: i64, file_data: FileData) -> HttpResponse {
+    tus_response(status)
+        .insert_header(("Upload-Offset", offset.to_string()))
+        .insert_header(("Backpack-File-Id", file_data.id.to_owned()))
+        .insert_header(("Backpack-File-Url", file_data.url.unwrap_or_default()))
+        .finish()
+}
+
+#[options("")]
+async fn capabilities(state: web::Data<State>) -> impl Responder {
+    tus_response(StatusCode::NO_CONTENT)
+        .insert_header(("Tus-Version", TUS_VERSION))
+        .insert_header(("Tus-Extension", TUS_EXTENSIONS))
+        .insert_header(("Tus-Max-Size", state.file_size_limit.to_string()))
+        .insert_header(("Tus-Checksum-Algorithm", TUS_CHECKSUM_ALGORITHMS))
+        .finish()
+}
+
+#[post("")]
+async fn create(
+    state: web::Data<State>,
+    req: HttpRequest,
+    auth: Auth<auth_role::User, false, true>,
+) -> Response<impl Responder> {
+    if let Some(err) = check_version(&req) {
+        return Ok(err);
+    }
+
+    let length = match get_header::<u64>(&req, "Upload-Length") {
+        Some(v) => v,
+        None => {
+            return Ok(tus_error(
+                StatusCode::BAD_REQUEST,
+                "Upload-Length header is required",
+            ))
+        }
+    };
+
+    if length > state.file_size_limit as u64 {
+        return Ok(tus_error(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            &format!(
+                "File was larger than the size limit of {}mb",
+                state.file_size_limit / 1000 / 1000
+            ),
+        ));
+    }
+
+    if let Some(remaining) = remaining_quota(&state.database, &auth.user.id).await? {
+        if length > remaining as u64 {
+            let err = quota_exceeded();
+            return Ok(tus_response(err.status_code()).json(err));
+        }
+    }
+
+    let original_name = metadata_filename(&req).unwrap_or("file".to_string());
+    if original_name.len() > 256 {
+        return Ok(tus_error(
+            StatusCode::BAD_REQUEST,
+            "File name too long (maximum 256 characters)",
+        ));
+    }
+
+    let upload = tus_uploads::ActiveModel {
+        uploader: Set(auth.user.id.to_owned()),
+        name: Set(new_file_name(&original_name)),
+        original_name: Set(original_name),
+        length: Set(length as i64),
+        ..Default::default()
+    }
+    .insert(&state.database)
+    .await?;
+
+    // Nothing will be sent for empty files, they are complete once created
+    if length == 0 {
+        return Ok(match complete_upload(&state, upload).await? {
+            Ok(file_data) => completed_response(StatusCode::CREATED, 0, file_data),
+            Err(err) => err,
+        });
+    }
+
+    Ok(tus_response(StatusCode::CREATED)
+        .insert_header((
+            "Location",
+            format!("{}api/tus/{}", state.base_url, upload.id),
+        ))
+        .finish())
+}
+
+#[head("/{upload_id}")]
+async fn offset(
+    state: web::Data<State>,
+    req: HttpRequest,
+    upload_id: web::Path<String>,
+    auth: Auth<auth_role::User, false, true>,
+) -> Response<impl Responder> {
+    if let Some(err) = check_version(&req) {
+        return Ok(err);
+    }
+
+    let upload = match find_upload(&state, &auth.user.id, &upload_id).await? {
+        Ok(v) => v,
+        Err(err) => return Ok(err),
+    };
+
+    Ok(tus_response(StatusCode::OK)
+        .insert_header(("Upload-Offset", upload.upload_offset.to_string()))
+        .insert_header(("Upload-Length", upload.length.to_string()))
+        .insert_header(("Cache-Control", "no-store"))
+        .finish())
+}
+
+#[patch("/{upload_id}")]
+async fn append(
+    state: web::Data<State>,
+    req: HttpRequest,
+    upload_id: web::Path<String>,
+    auth: Auth<auth_role::User, false, true>,
+    payload: web::Payload,
+) -> Response<impl Responder> {
+    if let Some(err) = check_version(&req) {
+        return Ok(err);
+    }
+
+    if get_header::<String>(&req, "Content-Type").as_deref()
+        != Some("application/offset+octet-stream")
+    {
+        return Ok(tus_error(
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "Content-Type must be application/offset+octet-stream",
+        ));
+    }
+
+    let upload = match find_upload(&state, &auth.user.id, &upload_id).await? {
+        Ok(v) => v,
+        Err(err) => return Ok(err),
+    };
+
+    if get_header::<i64>(&req, "Upload-Offset") != Some(upload.upload_offset) {
+        return Ok(tus_error(
+            StatusCode::CONFLICT,
+            "Upload-Offset does not match the current offset",
+        ));
+    }
+
+    let checksum = match parse_checksum(&req) {
+        Ok(v) => v,
+        Err(_) => {
+            return Ok(tus_error(
+                StatusCode::BAD_REQUEST,
+                "Unsupported checksum algorithm",
+            ))
+        }
+    };
+
+    // Written under a name of its own, another request may be appending at the same offset
+    let part = upload.parts + 1;
+    let part_name = chunk_object_name(&upload.id, part);
+    let received_name = format!("{}.{}", part_name, nanoid!(10));
+
+    // SHA-256 is computed while storing, SHA-1 is only needed for the checksum
+    let mut sha1 = Sha1::new();
+    let stream = payload.inspect(|chunk| {
+        if let Ok(chunk) = chunk {
+            sha1.update(chunk);
+        }
+    });
+
+    let stored = match store_stream(
+        stream,
+        state.storage.as_ref(),
+        &received_name,
+        (upload.length - upload.upload_offset) as usize,
+    )
+    .await
+    {
+        Ok(v) => v,
+        Err(err) => {
+            return match err {
+                MultipartError::PayloadTooLarge(_) => Ok(tus_error(
+                    StatusCode::PAYLOAD_TOO_LARGE,
+                    "Upload was larger than Upload-Length",
+                )),
+                MultipartError::WriteError(err) => Err(Error::from(err)),
+                _ => Ok(tus_error(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "Unable to upload file",
+                )),
+            }
+        }
+    };
+
+    let checksum_matches = match checksum {
+        Some(Checksum::Sha1(expected)) => expected == sha1.finalize().as_slice(),
+        Some(Checksum::Sha256(expected)) => {
+            expected
+                .iter()
+                .map(|byte| format!("{:02x}", byte))
+                .collect::<String>()
+                == stored.hash
+        }
+        None => true,
+    };
+
+    // Discard the part, the client can resend it from the same offset
+    if !checksum_matches || stored.size == 0 {
+        if let Err(err) = state.storage.delete_object(&received_name).await {
+            log::warn!("Unable to delete upload part {}: {}", received_name, err);
+        }
+
+        if !checksum_matches {
+            return Ok(tus_error(
+                StatusCode::from_u16(CHECKSUM_MISMATCH).unwrap(),
+                "Checksum Mismatch",
+            ));
+        }
+    } else {
+        // Only one request can move the upload past an offset
+        let offset = upload.upload_offset + stored.size as i64;
+        let updated = tus_uploads::Entity::update_many()
+            .col_expr(tus_uploads::Column::UploadOffset, Expr::value(offset))
+            .col_expr(tus_uploads::Column::Parts, Expr::value(part))
+            .filter(tus_uploads::Column::Id.eq(upload.id.to_owned()))
+            .filter(tus_uploads::Column::UploadOffset.eq(upload.upload_offset))
+            .exec(&state.database)
+            .await?;
+
+        if updated.rows_affected == 0 {
+            if let Err(err) = state.storage.delete_object(&received_name).await {
+                log::warn!("Unable to delete upload part {}: {}", received_name, err);
+            }
+
+            return Ok(tus_error(
+                StatusCode::CONFLICT,
+                "Upload-Offset does not match the current offset",
+            ));
+        }
+
+        if let Err(err) = state
+            .storage
+            .rename_object(&received_name, &part_name)
+            .await
+        {
+            // The offset is moved back so the client can send the part again
+            tus_uploads::Entity::update_many()
+                .col_expr(
+                    tus_uploads::Column::UploadOffset,
+                    Expr::value(upload.upload_offset),
+                )
+                .col_expr(tus_uploads::Column::Parts, Expr::value(upload.parts))
+                .filter(tus_uploads::Column::Id.eq(upload.id.to_owned()))
+                .filter(tus_uploads::Column::UploadOffset.eq(offset))
+                .exec(&state.database)
+                .await?;
+
+            return Err(Error::from(err));
+        }
+    }
+
+    let offset = upload.upload_offset + stored.size as i64;
+
+    // An empty request completes an upload again if creating its file failed before
+    if offset == upload.length {
+        let upload = tus_uploads::Model {
+            upload_offset: offset,
+            parts: if stored.size == 0 { upload.parts } else { part },
+            ..upload
+        };
+
+        return Ok(match complete_upload(&state, upload).await? {
+            Ok(file_data) => completed_response(StatusCode::NO_CONTENT, offset, file_data),
+            Err(err) => err,
+        });
+    }
+
+    Ok(tus_response(StatusCode::NO_CONTENT)
+        .insert_header(("Upload-Offset", offset.to_string()))
+        .finish())
+}
+
+#[delete("/{upload_id}")]
+async fn terminate(
+    state: web::Data<State>,
+    req: HttpRequest,
+    upload_id: web::Path<String>,
+    auth: Auth<auth_role::User, false, true>,
+) -> Response<impl Responder> {
+    if let Some(err) = check_version(&req) {
+        return Ok(err);
+    }
+
+    let upload = match find_upload(&state, &auth.user.id, &upload_id).await? {
+        Ok(v) => v,
+        Err(err) => return Ok(err),
+    };
+
+    upload.clone().delete(&state.database).await?;
+
+    for part in 1..=upload.parts {
+        let part = chunk_object_name(&upload.id, part);
+        if let Err(err) = state.storage.delete_object(&part).await {
+            log::warn!("Unable to delete upload part {}: {}", part, err);
+        }
+    }
+
+    Ok(tus_response(StatusCode::NO_CONTENT).finish())
+}
@@ -0,0 +1,366 @@
+use actix_web::{delete, get, http::StatusCode, post, put, web, HttpResponse, Responder, Scope};
+use sea_orm::{
+    ActiveModelTrait, ColumnTrait, EntityTrait, ModelTrait, QueryFilter, QueryOrder, Set,
+};
+
+use crate::{
+    database::entity::{upload_chunks, upload_sessions},
+    models::{
+        chunk_count, Error, MessageResponse, Response, UploadOptions, UploadSessionCreateForm,
+        UploadSessionData,
+    },
+    routes::file::create_file,
+    state::State,
+    storage::{pending_name, UploadedChunk},
+    util::{
+        auth::{auth_role, Auth},
+        file::{hash_object, new_file_name, pipe_stream, MultipartError},
+        quota::{quota_exceeded, remaining_quota},
+        strip::strip_stored_object,
+    },
+};
+
+/// Size of every chunk except the last one.
+/// S3 requires every part except the last to be at least 5MB.
+const CHUNK_SIZE: i64 = 8 * 1024 * 1024;
+
+pub fn get_routes() -> Scope {
+    web::scope("/upload")
+        .service(create)
+        .service(info)
+        .service(put_chunk)
+        .service(finalize)
+        .service(delete)
+}
+
+/// Find an upload session owned by the user
+async fn find_session(
+    state: &State,
+    user_id: &str,
+    session_id: &str,
+) -> Response<Result<upload_sessions::Model, MessageResponse>> {
+    Ok(
+        match upload_sessions::Entity::find_by_id(session_id.to_string())
+            .one(&state.database)
+            .await?
+        {
+            Some(v) => {
+                if v.uploader != user_id {
+                    Err(MessageResponse::new(
+                        StatusCode::FORBIDDEN,
+                        "You are not allowed to access this upload",
+                    ))
+                } else {
+                    Ok(v)
+                }
+            }
+            None => Err(MessageResponse::new(
+                StatusCode::NOT_FOUND,
+                "That upload was not found",
+            )),
+        },
+    )
+}
+
+/// Get all chunks received for an upload session in order
+async fn find_chunks(
+    state: &State,
+    session: &upload_sessions::Model,
+) -> Response<Vec<upload_chunks::Model>> {
+    Ok(session
+        .find_related(upload_chunks::Entity)
+        .order_by_asc(upload_chunks::Column::Number)
+        .all(&state.database)
+        .await?)
+}
+
+#[post("")]
+async fn create(
+    state: web::Data<State>,
+    auth: Auth<auth_role::User, false, true>,
+    form: web::Json<UploadSessionCreateForm>,
+) -> Response<impl Responder> {
+    if form.size < 1 {
+        return MessageResponse::ok(StatusCode::BAD_REQUEST, "File can not be empty");
+    }
+
+    if form.size > state.file_size_limit as u64 {
+        return MessageResponse::ok(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            &format!(
+                "File was larger than the size limit of {}mb",
+                state.file_size_limit / 1000 / 1000
+            ),
+        );
+    }
+
+    if let Some(remaining) = remaining_quota(&state.database, &auth.user.id).await? {
+        if form.size > remaining as u64 {
+            return Ok(quota_exceeded().http_response());
+        }
+    }
+
+    if form.name.len() > 256 {
+        return MessageResponse::ok(
+            StatusCode::BAD_REQUEST,
+            "File name too long (maximum 256 characters)",
+        );
+    }
+
+    if let Some(hash) = &form.hash {
+        if hash.len() != 64 || !hash.chars().all(|c| c.is_ascii_hexdigit()) {
+            return MessageResponse::ok(StatusCode::BAD_REQUEST, "Invalid SHA-256 hash");
+        }
+    }
+
+    let filename = new_file_name(&form.name);
+    let storage_upload_id = state
+        .storage
+        .create_chunked_upload(&pending_name(&filename))
+        .await?;
+
+    let session = upload_sessions::ActiveModel {
+        uploader: Set(auth.user.id.to_owned()),
+        name: Set(filename),
+        original_name: Set(form.name.to_owned()),
+        size: Set(form.size as i64),
+        chunk_size: Set(CHUNK_SIZE),
+        hash: Set(form.hash.as_ref().map(|hash| hash.to_lowercase())),
+        storage_upload_id: Set(storage_upload_id),
+        ..Default::default()
+    }
+    .insert(&state.database)
+    .await?;
+
+    Ok(HttpResponse::Ok().json(UploadSessionData::new(session, &[])))
+}
+
+#[get("/{session_id}")]
+async fn info(
+    state: web::Data<State>,
+    session_id: web::Path<String>,
+    auth: Auth<auth_role::User, false, true>,
+) -> Response<impl Responder> {
+    let session = match find_session(&state, &auth.user.id, &session_id).await? {
+        Ok(v) => v,
+        Err(err) => return Ok(err.http_response()),
+    };
+
+    let chunks = find_chunks(&state, &session).await?;
+    Ok(HttpResponse::Ok().json(UploadSessionData::new(session, &chunks)))
+}
+
+#[put("/{session_id}/{chunk_number}")]
+async fn put_chunk(
+    state: web::Data<State>,
+    path: web::Path<(String, i32)>,
+    auth: Auth<auth_role::User, false, true>,
+    payload: web::Payload,
+) -> Response<impl Responder> {
+    let (session_id, number) = path.into_inner();
+
+    let session = match find_session(&state, &auth.user.id, &session_id).await? {
+        Ok(v) => v,
+        Err(err) => return Ok(err.http_response()),
+    };
+
+    let chunks = chunk_count(session.size, session.chunk_size);
+    if number < 1 || number as i64 > chunks {
+        return MessageResponse::ok(
+            StatusCode::BAD_REQUEST,
+            &format!("Chunk number must be between 1 and {}", chunks),
+        );
+    }
+
+    // Only the last chunk may be smaller than the chunk size
+    let expected_size = if number as i64 == chunks {
+        session.size - (chunks - 1) * session.chunk_size
+    } else {
+        session.chunk_size
+    };
+
+    let pending = pending_name(&session.name);
+    let (stored, tag) = match pipe_stream(payload, expected_size as usize, |data| {
+        state.storage.put_chunk(
+            &pending,
+            &session.storage_upload_id,
+            number,
+            data,
+            expected_size as u64,
+        )
+    })
+    .await
+    {
+        Ok(v) => v,
+        Err(err) => {
+            return match err {
+                MultipartError::PayloadTooLarge(_) => MessageResponse::ok(
+                    StatusCode::PAYLOAD_TOO_LARGE,
+                    &format!("Chunk {} must be {} bytes", number, expected_size),
+                ),
+                MultipartError::WriteError(err) => Err(Error::from(err)),
+                _ => {
+                    MessageResponse::ok(StatusCode::INTERNAL_SERVER_ERROR, "Unable to upload chunk")
+                }
+            }
+        }
+    };
+
+    if stored.size as i64 != expected_size {
+        return MessageResponse::ok(
+            StatusCode::BAD_REQUEST,
+            &format!("Chunk {} must be {} bytes", number, expected_size),
+        );
+    }
+
+    // Uploading a chunk again replaces it
+    upload_chunks::Entity::delete_many()
+        .filter(upload_chunks::Column::SessionId.eq(session.id.to_owned()))
+        .filter(upload_chunks::Column::Number.eq(number))
+        .exec(&state.database)
+        .await?;
+
+    upload_chunks::ActiveModel {
+        session_id: Set(session.id.to_owned()),
+        number: Set(number),
+        size: Set(stored.size as i64),
+        tag: Set(tag),
+    }
+    .insert(&state.database)
+    .await?;
+
+    let chunks = find_chunks(&state, &session).await?;
+    Ok(HttpResponse::Ok().json(UploadSessionData::new(session, &chunks)))
+}
+
+#[post("/{session_id}/finalize")]
+async fn finalize(
+    state: web::Data<State>,
+    session_id: web::Path<String>,
+    auth: Auth<auth_role::User, false, true>,
+    options: web::Query<UploadOptions>,
+) -> Response<impl Responder> {
+    if let Err(err) = options.validate() {
+        return Ok(err.http_response());
+    }
+
+    let session = match find_session(&state, &auth.user.id, &session_id).await? {
+        Ok(v) => v,
+        Err(err) => return Ok(err.http_response()),
+    };
+
+    let chunks = find_chunks(&state, &session).await?;
+    if chunks.len() as i64 != chunk_count(session.size, session.chunk_size) {
+        return MessageResponse::ok(StatusCode::BAD_REQUEST, "Not all chunks have been uploaded");
+    }
+
+    let uploaded_chunks: Vec<UploadedChunk> = chunks
+        .iter()
+        .map(|chunk| UploadedChunk {
+            number: chunk.number,
+            tag: chunk.tag.to_owned(),
+        })
+        .collect();
+
+    // The session is kept so assembling can be retried
+    let pending = pending_name(&session.name);
+    if let Err(err) = state
+        .storage
+        .complete_chunked_upload(&pending, &session.storage_upload_id, &uploaded_chunks)
+        .await
+    {
+        log::error!("Error assembling {}: {}", session.name, err);
+        return MessageResponse::ok(StatusCode::INTERNAL_SERVER_ERROR, "Unable to assemble file");
+    }
+
+    // Validate the assembled object rather than trusting the chunks
+    let stored = match hash_object(state.storage.as_ref(), &pending).await {
+        Ok(v) => v,
+        Err(err) => {
+            let _ = state.storage.delete_object(&pending).await;
+            return Err(Error::from(err));
+        }
+    };
+
+    if stored.size as i64 != session.size
+        || session
+            .hash
+            .as_ref()
+            .map_or(false, |hash| hash != &stored.hash)
+    {
+        let _ = state.storage.delete_object(&pending).await;
+        return MessageResponse::ok(
+            StatusCode::BAD_REQUEST,
+            "Assembled file did not match the expected hash",
+        );
+    }
+
+    // Chunks are written as they arrive, so metadata can only be removed once assembled
+    let stored = if options.strip_metadata(state.strip_metadata) {
+        match strip_stored_object(state.storage.as_ref(), &pending, stored).await {
+            Ok(v) => v,
+            Err(err) => {
+                let _ = state.storage.delete_object(&pending).await;
+                return Err(Error::from(err));
+            }
+        }
+    } else {
+        stored
+    };
+
+    let file_data = match create_file(
+        &state,
+        &auth.user.id,
+        &session.name,
+        &session.original_name,
+        &stored,
+        &options,
+    )
+    .await?
+    {
+        Ok(v) => v,
+        Err(err) => return Ok(err.http_response()),
+    };
+
+    // Sessions which failed are left for the purge worker to expire
+    session.delete(&state.database).await?;
+
+    Ok(HttpResponse::Ok().json(file_data))
+}
+
+#[delete("/{session_id}")]
+async fn delete(
+    state: web::Data<State>,
+    session_id: web::Path<String>,
+    auth: Auth<auth_role::User, false, true>,
+) -> Response<impl Responder> {
+    let session = match find_session(&state, &auth.user.id, &session_id).await? {
+        Ok(v) => v,
+        Err(err) => return Ok(err.http_response()),
+    };
+
+    let uploaded_chunks: Vec<UploadedChunk> = find_chunks(&state, &session)
+        .await?
+        .iter()
+        .map(|chunk| UploadedChunk {
+            number: chunk.number,
+            tag: chunk.tag.to_owned(),
+        })
+        .collect();
+
+    session.clone().delete(&state.database).await?;
+
+    if let Err(err) = state
+        .storage
+        .abort_chunked_upload(
+            &pending_name(&session.name),
+            &session.storage_upload_id,
+            &uploaded_chunks,
+        )
+        .await
+    {
+        log::warn!("Unable to abort upload session {}: {}", session.id, err);
+    }
+
+    MessageResponse::ok(StatusCode::OK, "Upload was cancelled")
+}
@@ -2,7 +2,7 @@ use actix_http::Uri;
 use lettre::{AsyncSmtpTransport, Tokio1Executor};
 use sea_orm::DatabaseConnection;
 
-use crate::storage::StorageProvider;
+use crate::{storage::StorageProvider, util::thumbnail::ThumbnailFormat};
 
 pub struct State {
     pub database: DatabaseConnection,
@@ -13,4 +13,10 @@ pub struct State {
     pub smtp_client: Option<(AsyncSmtpTransport<Tokio1Executor>, String)>,
     pub file_size_limit: usize,
     pub invite_only: bool,
+    pub thumbnail_sizes: Vec<u32>,
+    pub thumbnail_format: ThumbnailFormat,
+    pub strip_metadata: bool,
+
+    // Every object is encrypted before it reaches the storage provider, none can be read from the storage URL
+    pub encrypted_at_rest: bool,
 }
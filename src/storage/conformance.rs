@@ -0,0 +1,519 @@
+use bytes::Bytes;
+use futures::{future, stream, TryStreamExt};
+use nanoid::nanoid;
+
+use super::{ObjectRange, ObjectStream, StorageProvider, UploadedChunk};
+
+/// Chunks of chunked uploads except the last must be at least this big on S3
+const MIN_CHUNK_SIZE: usize = 5 * 1024 * 1024;
+
+/// Check that a storage provider behaves the way the rest of the application expects.
+/// Every object is written below a random prefix and deleted again afterwards.
+pub async fn check_provider(storage: &dyn StorageProvider) -> Result<(), anyhow::Error> {
+    let prefix = format!("conformance/{}", nanoid!());
+    let mut written = Vec::new();
+
+    let result = run_checks(storage, &prefix, &mut written).await;
+
+    // Objects which were deleted by a check already fail here, that's fine
+    for name in written {
+        let _ = storage.delete_object(&name).await;
+    }
+
+    result
+}
+
+async fn run_checks(
+    storage: &dyn StorageProvider,
+    prefix: &str,
+    written: &mut Vec<String>,
+) -> Result<(), anyhow::Error> {
+    let contents = pattern(256 * 1024);
+
+    // Objects read back exactly as they were written
+    let name = format!("{}/object", prefix);
+    written.push(name.clone());
+    storage.put_object_bytes(&name, contents.clone()).await?;
+    expect_contents(storage, &name, None, &contents, "roundtrip").await?;
+    if !storage.object_exists(&name).await? {
+        anyhow::bail!("exists: written object is missing");
+    }
+
+    // Empty objects
+    let empty = format!("{}/empty", prefix);
+    written.push(empty.clone());
+    storage.put_object_bytes(&empty, Vec::new()).await?;
+    expect_contents(storage, &empty, None, &[], "empty object").await?;
+
+    // Streams of many chunks are written as one object
+    let streamed = format!("{}/streamed", prefix);
+    written.push(streamed.clone());
+    storage
+        .put_object(&streamed, chunked_stream(&contents, 1000))
+        .await?;
+    expect_contents(storage, &streamed, None, &contents, "streamed write").await?;
+
+    // Byte ranges are inclusive and may be open ended
+    let ranges = [
+        (
+            ObjectRange {
+                start: 10,
+                end: Some(99),
+            },
+            &contents[10..100],
+        ),
+        (
+            ObjectRange {
+                start: 200_000,
+                end: None,
+            },
+            &contents[200_000..],
+        ),
+        (
+            ObjectRange {
+                start: 0,
+                end: Some(0),
+            },
+            &contents[0..1],
+        ),
+        (
+            ObjectRange {
+                start: 70_000,
+                end: Some(70_000),
+            },
+            &contents[70_000..70_001],
+        ),
+    ];
+    for (range, expected) in ranges {
+        expect_contents(storage, &name, Some(range), expected, "range").await?;
+    }
+
+    // Writing an existing object replaces it
+    let replaced = pattern(1000).into_iter().rev().collect::<Vec<u8>>();
+    storage.put_object_bytes(&name, replaced.clone()).await?;
+    expect_contents(storage, &name, None, &replaced, "overwrite").await?;
+
+    // Names with slashes are nested objects
+    let nested = format!("{}/nested/deeper/object.txt", prefix);
+    written.push(nested.clone());
+    storage
+        .put_object_bytes(&nested, b"nested".to_vec())
+        .await?;
+    expect_contents(storage, &nested, None, b"nested", "nested name").await?;
+
+    // A failing stream must not leave a partial object behind
+    let failed = format!("{}/failed", prefix);
+    written.push(failed.clone());
+    let failing: ObjectStream = Box::pin(stream::iter(vec![
+        Ok(Bytes::from(contents[..1000].to_vec())),
+        Err(anyhow::anyhow!("Stream failed")),
+    ]));
+    if storage.put_object(&failed, failing).await.is_ok() {
+        anyhow::bail!("failed stream: write succeeded");
+    }
+    if storage.get_object_bytes(&failed).await.is_ok() {
+        anyhow::bail!("failed stream: partial object was left behind");
+    }
+
+    // Deleted and missing objects can't be read
+    storage.delete_object(&empty).await?;
+    if storage.get_object_bytes(&empty).await.is_ok() {
+        anyhow::bail!("delete: object can still be read");
+    }
+    if storage.object_exists(&empty).await? {
+        anyhow::bail!("exists: deleted object still exists");
+    }
+    if storage
+        .get_object_bytes(&format!("{}/missing", prefix))
+        .await
+        .is_ok()
+    {
+        anyhow::bail!("missing object: read succeeded");
+    }
+
+    // Renamed objects keep their contents and are gone under the old name
+    let renamed = format!("{}/renamed/object", prefix);
+    written.push(renamed.clone());
+    storage.rename_object(&nested, &renamed).await?;
+    expect_contents(storage, &renamed, None, b"nested", "rename").await?;
+    if storage.get_object_bytes(&nested).await.is_ok() {
+        anyhow::bail!("rename: object can still be read under the old name");
+    }
+
+    // Access changes don't fail, providers without ACLs ignore them
+    storage.set_object_public(&name, false).await?;
+    storage.set_object_public(&name, true).await?;
+
+    // Chunks are assembled in order, numbers start at 1 like S3 part numbers
+    let chunked = format!("{}/chunked", prefix);
+    written.push(chunked.clone());
+    let first = pattern(MIN_CHUNK_SIZE + 7);
+    let second = pattern(1234);
+    let upload_id = storage.create_chunked_upload(&chunked).await?;
+    let mut chunks = Vec::new();
+    for (number, data) in [(1, &first), (2, &second)] {
+        let tag = storage
+            .put_chunk(
+                &chunked,
+                &upload_id,
+                number,
+                chunked_stream(data, 64 * 1024),
+                data.len() as u64,
+            )
+            .await?;
+        chunks.push(UploadedChunk { number, tag });
+    }
+    storage
+        .complete_chunked_upload(&chunked, &upload_id, &chunks)
+        .await?;
+    expect_contents(
+        storage,
+        &chunked,
+        None,
+        &[first, second].concat(),
+        "chunked upload",
+    )
+    .await?;
+
+    // Aborted uploads never become an object
+    let aborted = format!("{}/aborted", prefix);
+    written.push(aborted.clone());
+    let upload_id = storage.create_chunked_upload(&aborted).await?;
+    let tag = storage
+        .put_chunk(
+            &aborted,
+            &upload_id,
+            1,
+            chunked_stream(&contents, 64 * 1024),
+            contents.len() as u64,
+        )
+        .await?;
+    storage
+        .abort_chunked_upload(&aborted, &upload_id, &[UploadedChunk { number: 1, tag }])
+        .await?;
+    if storage.get_object_bytes(&aborted).await.is_ok() {
+        anyhow::bail!("aborted chunked upload: object exists");
+    }
+
+    Ok(())
+}
+
+/// Bytes which differ at most offsets so misplaced ranges are noticed
+fn pattern(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i % 251) as u8).collect()
+}
+
+fn chunked_stream(data: &[u8], chunk_size: usize) -> ObjectStream {
+    let chunks: Vec<Result<Bytes, anyhow::Error>> = data
+        .chunks(chunk_size)
+        .map(|chunk| Ok(Bytes::from(chunk.to_vec())))
+        .collect();
+
+    Box::pin(stream::iter(chunks))
+}
+
+async fn expect_contents(
+    storage: &dyn StorageProvider,
+    name: &str,
+    range: Option<ObjectRange>,
+    expected: &[u8],
+    check: &str,
+) -> Result<(), anyhow::Error> {
+    let contents = storage
+        .get_object(name, range)
+        .await?
+        .try_fold(Vec::new(), |mut buf, chunk| {
+            buf.extend_from_slice(&chunk);
+            future::ok(buf)
+        })
+        .await?;
+
+    if contents != expected {
+        anyhow::bail!(
+            "{}: read {} bytes of {}, expected {} bytes",
+            check,
+            contents.len(),
+            name,
+            expected.len()
+        );
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        path::PathBuf,
+        sync::{Arc, Mutex},
+    };
+
+    use async_trait::async_trait;
+    use nanoid::nanoid;
+
+    use super::check_provider;
+    use crate::storage::{
+        encrypted::{EncryptedProvider, ObjectKey, ObjectKeys, KEY_SIZE},
+        local::LocalProvider,
+        memory::MemoryProvider,
+        mirrored::{MirroredProvider, MissedOperation, Replica, ReplicaLog},
+        sharded::ShardedLocalProvider,
+        tiered::TieredProvider,
+        webdav::WebDavProvider,
+        ListedObject, ObjectRange, ObjectStream, StorageProvider,
+    };
+
+    /// Directory below the system temporary directory, removed when dropped
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new() -> Self {
+            let mut path = std::env::temp_dir();
+            path.push(format!("backpack-conformance-{}", nanoid!()));
+            std::fs::create_dir_all(&path).unwrap();
+            TempDir(path)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    /// Keys kept in memory instead of the database
+    #[derive(Default)]
+    struct MemoryKeys(Mutex<HashMap<String, ObjectKey>>);
+
+    #[async_trait]
+    impl ObjectKeys for MemoryKeys {
+        async fn get_key(&self, name: &str) -> Result<Option<ObjectKey>, anyhow::Error> {
+            Ok(self.0.lock().unwrap().get(name).cloned())
+        }
+
+        async fn set_key(&self, name: &str, key: &ObjectKey) -> Result<(), anyhow::Error> {
+            self.0.lock().unwrap().insert(name.to_owned(), key.clone());
+            Ok(())
+        }
+
+        async fn delete_key(&self, name: &str) -> Result<(), anyhow::Error> {
+            self.0.lock().unwrap().remove(name);
+            Ok(())
+        }
+    }
+
+    /// Missed operations kept in memory instead of the database
+    #[derive(Clone, Default)]
+    struct MemoryReplicaLog(Arc<Mutex<Vec<(String, String, MissedOperation)>>>);
+
+    #[async_trait]
+    impl ReplicaLog for MemoryReplicaLog {
+        async fn missed(
+            &self,
+            name: &str,
+            replica: &str,
+            operation: MissedOperation,
+            _error: &str,
+        ) -> Result<(), anyhow::Error> {
+            let mut missed = self.0.lock().unwrap();
+            missed.retain(|(missed_name, missed_replica, _)| {
+                missed_name != name || missed_replica != replica
+            });
+            missed.push((name.to_owned(), replica.to_owned(), operation));
+            Ok(())
+        }
+
+        async fn forget(&self, name: &str) -> Result<(), anyhow::Error> {
+            self.0
+                .lock()
+                .unwrap()
+                .retain(|(missed_name, _, _)| missed_name != name);
+            Ok(())
+        }
+    }
+
+    /// Storage provider which is unavailable
+    struct FailingProvider;
+
+    #[async_trait]
+    impl StorageProvider for FailingProvider {
+        async fn put_object(&self, _name: &str, _data: ObjectStream) -> Result<(), anyhow::Error> {
+            anyhow::bail!("Unavailable")
+        }
+
+        async fn delete_object(&self, _name: &str) -> Result<(), anyhow::Error> {
+            anyhow::bail!("Unavailable")
+        }
+
+        async fn get_object(
+            &self,
+            _path: &str,
+            _range: Option<ObjectRange>,
+        ) -> Result<ObjectStream, anyhow::Error> {
+            anyhow::bail!("Unavailable")
+        }
+
+        async fn list_objects(&self, _prefix: &str) -> Result<Vec<ListedObject>, anyhow::Error> {
+            anyhow::bail!("Unavailable")
+        }
+    }
+
+    fn replica(name: &str, provider: Box<dyn StorageProvider>) -> Replica {
+        Replica {
+            name: name.to_owned(),
+            provider,
+        }
+    }
+
+    #[tokio::test]
+    async fn memory_provider() {
+        check_provider(&MemoryProvider::new()).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn local_provider() {
+        let dir = TempDir::new();
+        check_provider(&LocalProvider::new(dir.0.clone()))
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn sharded_local_provider() {
+        let dir = TempDir::new();
+        check_provider(&ShardedLocalProvider::new(dir.0.clone()))
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn encrypted_provider() {
+        let storage = EncryptedProvider::new(
+            Box::new(MemoryProvider::new()),
+            &[7; KEY_SIZE],
+            Box::new(MemoryKeys::default()),
+        );
+
+        check_provider(&storage).await.unwrap();
+    }
+
+    /// Needs a WebDAV server, skipped unless WEBDAV_TEST_URL is set
+    #[tokio::test]
+    async fn webdav_provider() {
+        let url = match std::env::var("WEBDAV_TEST_URL") {
+            Ok(v) => v,
+            Err(_) => return,
+        };
+
+        let storage = WebDavProvider::new(
+            &url,
+            std::env::var("WEBDAV_TEST_USERNAME").ok(),
+            std::env::var("WEBDAV_TEST_PASSWORD").ok(),
+        );
+
+        check_provider(&storage).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn mirrored_provider() {
+        let storage = MirroredProvider::new(
+            vec![
+                replica("first", Box::new(MemoryProvider::new())),
+                replica("second", Box::new(MemoryProvider::new())),
+            ],
+            2,
+            Box::new(MemoryReplicaLog::default()),
+        );
+
+        check_provider(&storage).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn tiered_provider() {
+        let storage = TieredProvider::new(
+            Box::new(MemoryProvider::new()),
+            Box::new(MemoryProvider::new()),
+        );
+
+        check_provider(&storage).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn mirrored_quorum_failure() {
+        let healthy = MemoryProvider::new();
+        healthy
+            .put_object_bytes("object", b"existing".to_vec())
+            .await
+            .unwrap();
+
+        let log = MemoryReplicaLog::default();
+        let storage = MirroredProvider::new(
+            vec![
+                replica("healthy", Box::new(healthy)),
+                replica("failing", Box::new(FailingProvider)),
+            ],
+            2,
+            Box::new(log.clone()),
+        );
+
+        // The write fails without a quorum but the copy which was written is kept
+        assert!(storage
+            .put_object_bytes("object", b"replaced".to_vec())
+            .await
+            .is_err());
+        assert_eq!(
+            storage.get_object_bytes("object").await.unwrap(),
+            b"replaced".to_vec()
+        );
+        assert_eq!(
+            *log.0.lock().unwrap(),
+            vec![(
+                "object".to_owned(),
+                "failing".to_owned(),
+                MissedOperation::Write
+            )]
+        );
+
+        // Renames without a quorum leave the object under its old name
+        assert!(storage.rename_object("object", "renamed").await.is_err());
+        assert_eq!(
+            storage.get_object_bytes("object").await.unwrap(),
+            b"replaced".to_vec()
+        );
+        assert!(storage.get_object_bytes("renamed").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn mirrored_read_failover() {
+        let log = MemoryReplicaLog::default();
+        let storage = MirroredProvider::new(
+            vec![
+                replica("failing", Box::new(FailingProvider)),
+                replica("healthy", Box::new(MemoryProvider::new())),
+            ],
+            1,
+            Box::new(log.clone()),
+        );
+
+        storage
+            .put_object_bytes("object", b"contents".to_vec())
+            .await
+            .unwrap();
+        assert_eq!(
+            *log.0.lock().unwrap(),
+            vec![(
+                "object".to_owned(),
+                "failing".to_owned(),
+                MissedOperation::Write
+            )]
+        );
+
+        // Reads fall back to the replica which has the object
+        assert_eq!(
+            storage.get_object_bytes("object").await.unwrap(),
+            b"contents".to_vec()
+        );
+        assert!(storage.object_exists("object").await.unwrap());
+    }
+}
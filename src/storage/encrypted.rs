@@ -0,0 +1,463 @@
+use std::{convert::TryFrom, time::Duration};
+
+use anyhow::anyhow;
+use async_trait::async_trait;
+use bytes::{Bytes, BytesMut};
+use chacha20poly1305::{
+    aead::{Aead, KeyInit},
+    Key, XChaCha20Poly1305, XNonce,
+};
+use futures::{stream, StreamExt};
+use rand::RngCore;
+
+use super::{
+    mirrored::MissedOperation, tiered::Tier, ListedObject, ObjectRange, ObjectStream,
+    StorageProvider,
+};
+
+/// Plaintext bytes encrypted as one segment.
+/// Segments are authenticated on their own so a range can be read without the whole object.
+const SEGMENT_SIZE: usize = 64 * 1024;
+
+/// Poly1305 tag appended to every segment
+const TAG_SIZE: usize = 16;
+
+const ENCRYPTED_SEGMENT_SIZE: usize = SEGMENT_SIZE + TAG_SIZE;
+
+/// Random part of a segment nonce, followed by the segment number and a flag marking the last segment
+pub const NONCE_PREFIX_SIZE: usize = 19;
+
+/// Size of XChaCha20-Poly1305 keys and nonces
+pub const KEY_SIZE: usize = 32;
+const NONCE_SIZE: usize = 24;
+
+/// Data key of an object wrapped by the master key, kept in a key store under the name of the object
+#[derive(Clone, Debug)]
+pub struct ObjectKey {
+    // Nonce and ciphertext of the data key
+    pub wrapped_key: Vec<u8>,
+
+    // Prefix of the nonce of every segment
+    pub nonce: Vec<u8>,
+}
+
+#[async_trait]
+/// Store of the keys encrypted objects were written with
+pub trait ObjectKeys: Sync + Send {
+    /// Key of an object, `None` if the object is stored in plaintext
+    async fn get_key(&self, name: &str) -> Result<Option<ObjectKey>, anyhow::Error>;
+
+    /// Remember the key an object was written with, replacing its previous key
+    async fn set_key(&self, name: &str, key: &ObjectKey) -> Result<(), anyhow::Error>;
+
+    /// Forget the key of an object which was deleted
+    async fn delete_key(&self, name: &str) -> Result<(), anyhow::Error>;
+}
+
+/// Storage provider encrypting objects at rest with XChaCha20-Poly1305 before they reach another provider.
+///
+/// Every object is encrypted with its own data key while it is written, so plaintext never reaches the other provider.
+/// Reads are decrypted for every object which has a key, objects written before encryption was enabled have none.
+/// Chunked uploads are stored as separate encrypted objects and encrypted again when they are assembled.
+pub struct EncryptedProvider {
+    inner: Box<dyn StorageProvider>,
+    master_key: XChaCha20Poly1305,
+    keys: Box<dyn ObjectKeys>,
+}
+
+impl EncryptedProvider {
+    pub fn new(
+        inner: Box<dyn StorageProvider>,
+        master_key: &[u8],
+        keys: Box<dyn ObjectKeys>,
+    ) -> Self {
+        EncryptedProvider {
+            inner,
+            master_key: XChaCha20Poly1305::new(Key::from_slice(master_key)),
+            keys,
+        }
+    }
+
+    /// Encrypt a data key with the master key, the random nonce is stored in front of it
+    fn wrap_key(&self, data_key: &[u8]) -> Result<Vec<u8>, anyhow::Error> {
+        let mut nonce = [0u8; NONCE_SIZE];
+        rand::thread_rng().fill_bytes(&mut nonce);
+
+        let wrapped = self
+            .master_key
+            .encrypt(XNonce::from_slice(&nonce), data_key)
+            .map_err(|_| anyhow!("Unable to wrap data key"))?;
+
+        Ok([&nonce[..], &wrapped].concat())
+    }
+
+    /// Cipher of the data key an object was encrypted with
+    fn unwrap_key(&self, key: &ObjectKey) -> Result<XChaCha20Poly1305, anyhow::Error> {
+        if key.wrapped_key.len() <= NONCE_SIZE || key.nonce.len() != NONCE_PREFIX_SIZE {
+            return Err(anyhow!("Invalid object key"));
+        }
+
+        let (nonce, wrapped) = key.wrapped_key.split_at(NONCE_SIZE);
+        let data_key = self
+            .master_key
+            .decrypt(XNonce::from_slice(nonce), wrapped)
+            .map_err(|_| anyhow!("Unable to unwrap data key, the master key may have changed"))?;
+
+        if data_key.len() != KEY_SIZE {
+            return Err(anyhow!("Invalid object key"));
+        }
+
+        Ok(XChaCha20Poly1305::new(Key::from_slice(&data_key)))
+    }
+}
+
+/// Object the encrypted copy of a plaintext object is written to before it replaces the plaintext
+fn encrypted_name(name: &str) -> String {
+    format!("{}.enc", name)
+}
+
+/// Nonce of a segment, a segment can't be moved or marked as the last one without failing to decrypt
+fn segment_nonce(prefix: &[u8], number: u64, last: bool) -> Result<XNonce, anyhow::Error> {
+    let number = u32::try_from(number).map_err(|_| anyhow!("Object has too many segments"))?;
+
+    let mut nonce = [0u8; NONCE_SIZE];
+    nonce[..NONCE_PREFIX_SIZE].copy_from_slice(prefix);
+    nonce[NONCE_PREFIX_SIZE..NONCE_SIZE - 1].copy_from_slice(&number.to_be_bytes());
+    nonce[NONCE_SIZE - 1] = last as u8;
+
+    Ok(*XNonce::from_slice(&nonce))
+}
+
+struct EncryptState {
+    data: ObjectStream,
+    cipher: XChaCha20Poly1305,
+    prefix: Vec<u8>,
+    buffer: BytesMut,
+    number: u64,
+    done: bool,
+}
+
+/// Encrypt a plaintext stream into segments.
+/// A segment is only written once more data follows it, so the last one can always be marked.
+fn encrypt_stream(cipher: XChaCha20Poly1305, prefix: Vec<u8>, data: ObjectStream) -> ObjectStream {
+    let state = EncryptState {
+        data,
+        cipher,
+        prefix,
+        buffer: BytesMut::new(),
+        number: 0,
+        done: false,
+    };
+
+    Box::pin(stream::try_unfold(state, |mut state| async move {
+        if state.done {
+            return Ok(None);
+        }
+
+        loop {
+            let last = if state.buffer.len() > SEGMENT_SIZE {
+                false
+            } else {
+                match state.data.next().await {
+                    Some(chunk) => {
+                        state.buffer.extend_from_slice(&chunk?);
+                        continue;
+                    }
+                    None => true,
+                }
+            };
+
+            let segment = if last {
+                state.done = true;
+                state.buffer.split()
+            } else {
+                state.buffer.split_to(SEGMENT_SIZE)
+            };
+
+            let encrypted = state
+                .cipher
+                .encrypt(
+                    &segment_nonce(&state.prefix, state.number, last)?,
+                    &segment[..],
+                )
+                .map_err(|_| anyhow!("Unable to encrypt segment {}", state.number))?;
+
+            state.number += 1;
+            return Ok(Some((Bytes::from(encrypted), state)));
+        }
+    }))
+}
+
+struct DecryptState {
+    data: ObjectStream,
+    cipher: XChaCha20Poly1305,
+    prefix: Vec<u8>,
+    buffer: BytesMut,
+    number: u64,
+
+    // The last segment was read, nothing may follow it
+    finished: bool,
+
+    // Plaintext before the requested range in the first segment
+    skip: usize,
+
+    // Plaintext left in the requested range, `None` if it reaches the end of the object
+    remaining: Option<u64>,
+}
+
+impl DecryptState {
+    /// Decrypt the next segment, a full segment may be the last one if the object ends on a segment boundary
+    fn open_segment(
+        &mut self,
+        segment: &[u8],
+        must_be_last: bool,
+    ) -> Result<Vec<u8>, anyhow::Error> {
+        if self.finished {
+            return Err(anyhow!("Encrypted object has data after its last segment"));
+        }
+
+        if !must_be_last {
+            if let Ok(plaintext) = self
+                .cipher
+                .decrypt(&segment_nonce(&self.prefix, self.number, false)?, segment)
+            {
+                self.number += 1;
+                return Ok(plaintext);
+            }
+        }
+
+        let plaintext = self
+            .cipher
+            .decrypt(&segment_nonce(&self.prefix, self.number, true)?, segment)
+            .map_err(|_| anyhow!("Unable to decrypt segment {}", self.number))?;
+
+        self.number += 1;
+        self.finished = true;
+        Ok(plaintext)
+    }
+
+    /// Cut a decrypted segment down to the requested range
+    fn trim(&mut self, plaintext: Vec<u8>) -> Bytes {
+        let mut plaintext = Bytes::from(plaintext);
+
+        let skip = self.skip.min(plaintext.len());
+        self.skip -= skip;
+        let _ = plaintext.split_to(skip);
+
+        if let Some(remaining) = self.remaining.as_mut() {
+            plaintext.truncate((*remaining).min(plaintext.len() as u64) as usize);
+            *remaining -= plaintext.len() as u64;
+        }
+
+        plaintext
+    }
+}
+
+/// Decrypt a stream of segments starting at segment `first`, limited to a plaintext range
+fn decrypt_stream(
+    cipher: XChaCha20Poly1305,
+    prefix: Vec<u8>,
+    data: ObjectStream,
+    first: u64,
+    skip: usize,
+    remaining: Option<u64>,
+) -> ObjectStream {
+    let state = DecryptState {
+        data,
+        cipher,
+        prefix,
+        buffer: BytesMut::new(),
+        number: first,
+        finished: false,
+        skip,
+        remaining,
+    };
+
+    Box::pin(stream::try_unfold(state, |mut state| async move {
+        loop {
+            if state.remaining == Some(0) {
+                return Ok(None);
+            }
+
+            let plaintext = if state.buffer.len() >= ENCRYPTED_SEGMENT_SIZE {
+                let segment = state.buffer.split_to(ENCRYPTED_SEGMENT_SIZE);
+                state.open_segment(&segment, false)?
+            } else {
+                match state.data.next().await {
+                    Some(chunk) => {
+                        state.buffer.extend_from_slice(&chunk?);
+                        continue;
+                    }
+                    None if !state.buffer.is_empty() => {
+                        let segment = state.buffer.split();
+                        state.open_segment(&segment, true)?
+                    }
+                    // Segments can be removed from the end without any of the others failing
+                    None if !state.finished && state.remaining.is_none() => {
+                        return Err(anyhow!("Encrypted object is truncated"))
+                    }
+                    None => return Ok(None),
+                }
+            };
+
+            let plaintext = state.trim(plaintext);
+            if !plaintext.is_empty() {
+                return Ok(Some((plaintext, state)));
+            }
+        }
+    }))
+}
+
+#[async_trait]
+impl StorageProvider for EncryptedProvider {
+    async fn put_object(&self, name: &str, data: ObjectStream) -> Result<(), anyhow::Error> {
+        let mut data_key = [0u8; KEY_SIZE];
+        rand::thread_rng().fill_bytes(&mut data_key);
+
+        let mut nonce = vec![0u8; NONCE_PREFIX_SIZE];
+        rand::thread_rng().fill_bytes(&mut nonce);
+
+        let key = ObjectKey {
+            wrapped_key: self.wrap_key(&data_key)?,
+            nonce: nonce.clone(),
+        };
+
+        let cipher = XChaCha20Poly1305::new(Key::from_slice(&data_key));
+        self.inner
+            .put_object(name, encrypt_stream(cipher, nonce, data))
+            .await?;
+
+        // The ciphertext is useless without its key
+        if let Err(err) = self.keys.set_key(name, &key).await {
+            let _ = self.inner.delete_object(name).await;
+            return Err(err);
+        }
+
+        Ok(())
+    }
+
+    async fn delete_object(&self, name: &str) -> Result<(), anyhow::Error> {
+        self.inner.delete_object(name).await?;
+        self.keys.delete_key(name).await
+    }
+
+    async fn get_object(
+        &self,
+        path: &str,
+        range: Option<ObjectRange>,
+    ) -> Result<ObjectStream, anyhow::Error> {
+        let key = match self.keys.get_key(path).await? {
+            Some(v) => v,
+            None => return self.inner.get_object(path, range).await,
+        };
+
+        let cipher = self.unwrap_key(&key)?;
+
+        // Only the segments holding the range are read
+        let segment_size = SEGMENT_SIZE as u64;
+        let (first, skip, encrypted_range, remaining) = match range {
+            Some(range) => {
+                let first = range.start / segment_size;
+
+                (
+                    first,
+                    (range.start % segment_size) as usize,
+                    Some(ObjectRange {
+                        start: first * ENCRYPTED_SEGMENT_SIZE as u64,
+                        end: range.end.map(|end| {
+                            (end / segment_size + 1) * ENCRYPTED_SEGMENT_SIZE as u64 - 1
+                        }),
+                    }),
+                    range.end.map(|end| (end + 1).saturating_sub(range.start)),
+                )
+            }
+            None => (0, 0, None, None),
+        };
+
+        let data = self.inner.get_object(path, encrypted_range).await?;
+
+        Ok(decrypt_stream(
+            cipher, key.nonce, data, first, skip, remaining,
+        ))
+    }
+
+    /// Segment nonces don't depend on the name, so ciphertext can be moved as it is.
+    /// The key is stored under the new name first so the object is never readable without it.
+    async fn rename_object(&self, from: &str, to: &str) -> Result<(), anyhow::Error> {
+        let key = self.keys.get_key(from).await?;
+        let replaced = self.keys.get_key(to).await?;
+
+        if let Some(key) = &key {
+            self.keys.set_key(to, key).await?;
+        }
+
+        if let Err(err) = self.inner.rename_object(from, to).await {
+            // The object under the new name is still the one it would have replaced
+            if key.is_some() {
+                let _ = match &replaced {
+                    Some(replaced) => self.keys.set_key(to, replaced).await,
+                    None => self.keys.delete_key(to).await,
+                };
+            }
+
+            return Err(err);
+        }
+
+        match (key, replaced) {
+            (Some(_), _) => self.keys.delete_key(from).await,
+            (None, Some(_)) => self.keys.delete_key(to).await,
+            (None, None) => Ok(()),
+        }
+    }
+
+    async fn object_exists(&self, name: &str) -> Result<bool, anyhow::Error> {
+        self.inner.object_exists(name).await
+    }
+
+    async fn list_objects(&self, prefix: &str) -> Result<Vec<ListedObject>, anyhow::Error> {
+        self.inner.list_objects(prefix).await
+    }
+
+    /// Tiers hold the ciphertext, it is moved as it is
+    async fn move_object(&self, name: &str, tier: Tier) -> Result<(), anyhow::Error> {
+        self.inner.move_object(name, tier).await
+    }
+
+    /// Replicas hold the ciphertext, it is copied as it is
+    async fn resync_object(
+        &self,
+        name: &str,
+        replica: &str,
+        operation: MissedOperation,
+    ) -> Result<(), anyhow::Error> {
+        self.inner.resync_object(name, replica, operation).await
+    }
+
+    /// Ciphertext is never readable directly, objects are served by the application.
+    /// Plaintext objects from before encryption was enabled can still be restricted.
+    async fn set_object_public(&self, name: &str, _public: bool) -> Result<(), anyhow::Error> {
+        self.inner.set_object_public(name, false).await
+    }
+
+    fn get_presigned_url(&self, _name: &str, _expires_in: Duration) -> Option<String> {
+        None
+    }
+
+    async fn encrypt_object(&self, name: &str) -> Result<bool, anyhow::Error> {
+        if self.keys.get_key(name).await?.is_some() {
+            return Ok(true);
+        }
+
+        // Written next to the plaintext, which stays readable until it was replaced
+        let encrypted = encrypted_name(name);
+        let plaintext = self.inner.get_object(name, None).await?;
+        self.put_object(&encrypted, plaintext).await?;
+
+        if let Err(err) = self.rename_object(&encrypted, name).await {
+            let _ = self.delete_object(&encrypted).await;
+            return Err(err);
+        }
+
+        Ok(true)
+    }
+}
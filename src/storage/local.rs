@@ -1,9 +1,13 @@
-use std::path::PathBuf;
+use std::{io::SeekFrom, path::PathBuf};
 
-use super::StorageProvider;
+use super::{ListedObject, ObjectRange, ObjectStream, StorageProvider, UploadedChunk};
 
 use async_trait::async_trait;
-use tokio::io::AsyncWriteExt;
+use chrono::{DateTime, Utc};
+use futures::{StreamExt, TryStreamExt};
+use nanoid::nanoid;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio_util::io::ReaderStream;
 
 pub struct LocalProvider {
     path: PathBuf,
@@ -13,22 +17,48 @@ impl LocalProvider {
     pub fn new(path: PathBuf) -> Self {
         LocalProvider { path: path }
     }
+
+    /// Temporary directory holding the chunks of a chunked upload
+    fn chunk_path(&self, upload_id: &str) -> PathBuf {
+        let mut path = self.path.clone();
+        path.push("chunks");
+        path.push(upload_id);
+        path
+    }
 }
 
 #[async_trait]
 impl StorageProvider for LocalProvider {
-    async fn put_object(&self, name: &str, data: &Vec<u8>) -> Result<(), anyhow::Error> {
+    async fn put_object(&self, name: &str, mut data: ObjectStream) -> Result<(), anyhow::Error> {
         let mut path = self.path.clone();
         path.push(name);
 
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+
         let mut file = tokio::fs::OpenOptions::new()
             .write(true)
             .create(true)
             .truncate(true)
-            .open(path)
+            .open(&path)
             .await?;
 
-        file.write_all(&data).await?;
+        while let Some(chunk) = data.next().await {
+            let result = match chunk {
+                Ok(chunk) => file.write_all(&chunk).await.map_err(anyhow::Error::from),
+                Err(err) => Err(err),
+            };
+
+            // Don't leave a partially written file behind
+            if let Err(err) = result {
+                drop(file);
+                let _ = tokio::fs::remove_file(&path).await;
+                return Err(err);
+            }
+        }
+
+        file.flush().await?;
         Ok(())
     }
 
@@ -40,10 +70,173 @@ impl StorageProvider for LocalProvider {
         Ok(())
     }
 
-    async fn get_object(&self, path: &str) -> Result<Vec<u8>, anyhow::Error> {
+    async fn get_object(
+        &self,
+        path: &str,
+        range: Option<ObjectRange>,
+    ) -> Result<ObjectStream, anyhow::Error> {
         let mut path_buf = self.path.clone();
         path_buf.push(path);
 
-        Ok(tokio::fs::read(path_buf).await?)
+        let mut file = tokio::fs::File::open(path_buf).await?;
+
+        let (start, length) = match range {
+            Some(range) => (
+                range.start,
+                range
+                    .end
+                    .map(|end| (end + 1).saturating_sub(range.start))
+                    .unwrap_or(u64::MAX),
+            ),
+            None => (0, u64::MAX),
+        };
+
+        if start > 0 {
+            file.seek(SeekFrom::Start(start)).await?;
+        }
+
+        Ok(Box::pin(
+            ReaderStream::new(file.take(length)).map_err(anyhow::Error::from),
+        ))
+    }
+
+    async fn rename_object(&self, from: &str, to: &str) -> Result<(), anyhow::Error> {
+        let mut from_path = self.path.clone();
+        from_path.push(from);
+
+        let mut to_path = self.path.clone();
+        to_path.push(to);
+
+        if let Some(parent) = to_path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+
+        // Atomic within the storage directory
+        tokio::fs::rename(from_path, to_path).await?;
+        Ok(())
+    }
+
+    async fn object_exists(&self, name: &str) -> Result<bool, anyhow::Error> {
+        let mut path = self.path.clone();
+        path.push(name);
+
+        match tokio::fs::metadata(path).await {
+            Ok(metadata) => Ok(metadata.is_file()),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(false),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    async fn list_objects(&self, prefix: &str) -> Result<Vec<ListedObject>, anyhow::Error> {
+        let mut objects = Vec::new();
+        let mut directories = vec![self.path.clone()];
+
+        while let Some(directory) = directories.pop() {
+            let mut entries = tokio::fs::read_dir(&directory).await?;
+
+            while let Some(entry) = entries.next_entry().await? {
+                let metadata = entry.metadata().await?;
+                if metadata.is_dir() {
+                    directories.push(entry.path());
+                    continue;
+                }
+
+                // Object names always use forward slashes
+                let path = entry.path();
+                let name = match path.strip_prefix(&self.path) {
+                    Ok(v) => v
+                        .components()
+                        .map(|component| component.as_os_str().to_string_lossy())
+                        .collect::<Vec<_>>()
+                        .join("/"),
+                    Err(_) => continue,
+                };
+
+                if name.starts_with(prefix) {
+                    objects.push(ListedObject {
+                        name,
+                        size: metadata.len(),
+                        modified: metadata.modified().ok().map(DateTime::<Utc>::from),
+                    });
+                }
+            }
+        }
+
+        Ok(objects)
+    }
+
+    async fn create_chunked_upload(&self, _name: &str) -> Result<String, anyhow::Error> {
+        let upload_id = nanoid!();
+        tokio::fs::create_dir_all(self.chunk_path(&upload_id)).await?;
+
+        Ok(upload_id)
+    }
+
+    async fn put_chunk(
+        &self,
+        _name: &str,
+        upload_id: &str,
+        number: i32,
+        data: ObjectStream,
+        _size: u64,
+    ) -> Result<String, anyhow::Error> {
+        self.put_object(&format!("chunks/{}/{}", upload_id, number), data)
+            .await?;
+
+        Ok(number.to_string())
+    }
+
+    async fn complete_chunked_upload(
+        &self,
+        name: &str,
+        upload_id: &str,
+        chunks: &[UploadedChunk],
+    ) -> Result<(), anyhow::Error> {
+        let chunk_path = self.chunk_path(upload_id);
+
+        let mut path = self.path.clone();
+        path.push(name);
+
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+
+        let mut file = tokio::fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .await?;
+
+        for chunk in chunks {
+            let mut chunk_file_path = chunk_path.clone();
+            chunk_file_path.push(chunk.number.to_string());
+
+            let result = match tokio::fs::File::open(chunk_file_path).await {
+                Ok(mut chunk_file) => tokio::io::copy(&mut chunk_file, &mut file).await,
+                Err(err) => Err(err),
+            };
+
+            if let Err(err) = result {
+                drop(file);
+                let _ = tokio::fs::remove_file(&path).await;
+                return Err(err.into());
+            }
+        }
+
+        file.flush().await?;
+        tokio::fs::remove_dir_all(chunk_path).await?;
+
+        Ok(())
+    }
+
+    async fn abort_chunked_upload(
+        &self,
+        _name: &str,
+        upload_id: &str,
+        _chunks: &[UploadedChunk],
+    ) -> Result<(), anyhow::Error> {
+        tokio::fs::remove_dir_all(self.chunk_path(upload_id)).await?;
+        Ok(())
     }
 }
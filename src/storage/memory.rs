@@ -0,0 +1,104 @@
+use std::{
+    collections::HashMap,
+    sync::{Mutex, MutexGuard},
+};
+
+use super::{ListedObject, ObjectRange, ObjectStream, StorageProvider};
+
+use async_trait::async_trait;
+use bytes::{Bytes, BytesMut};
+use futures::{stream, StreamExt};
+
+/// Storage provider keeping every object in memory.
+/// Objects are lost when the process exits, this is meant for tests and trying out an instance.
+#[derive(Default)]
+pub struct MemoryProvider {
+    objects: Mutex<HashMap<String, Bytes>>,
+}
+
+impl MemoryProvider {
+    pub fn new() -> Self {
+        MemoryProvider::default()
+    }
+
+    fn objects(&self) -> MutexGuard<'_, HashMap<String, Bytes>> {
+        // A panic while holding the lock can't leave a map in an invalid state
+        self.objects
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+#[async_trait]
+impl StorageProvider for MemoryProvider {
+    async fn put_object(&self, name: &str, mut data: ObjectStream) -> Result<(), anyhow::Error> {
+        // Only stored once the whole stream was received so failed writes leave nothing behind
+        let mut buffer = BytesMut::new();
+        while let Some(chunk) = data.next().await {
+            buffer.extend_from_slice(&chunk?);
+        }
+
+        self.objects().insert(name.to_string(), buffer.freeze());
+        Ok(())
+    }
+
+    async fn delete_object(&self, name: &str) -> Result<(), anyhow::Error> {
+        match self.objects().remove(name) {
+            Some(_) => Ok(()),
+            None => Err(anyhow::anyhow!("{} does not exist", name)),
+        }
+    }
+
+    async fn get_object(
+        &self,
+        path: &str,
+        range: Option<ObjectRange>,
+    ) -> Result<ObjectStream, anyhow::Error> {
+        let object = match self.objects().get(path) {
+            Some(v) => v.clone(),
+            None => return Err(anyhow::anyhow!("{} does not exist", path)),
+        };
+
+        let object = match range {
+            Some(range) => {
+                let start = (range.start as usize).min(object.len());
+                let end = range
+                    .end
+                    .map_or(object.len(), |end| (end as usize + 1).min(object.len()));
+
+                object.slice(start..end.max(start))
+            }
+            None => object,
+        };
+
+        Ok(Box::pin(stream::once(async move { Ok(object) })))
+    }
+
+    async fn rename_object(&self, from: &str, to: &str) -> Result<(), anyhow::Error> {
+        let mut objects = self.objects();
+        match objects.remove(from) {
+            Some(object) => {
+                objects.insert(to.to_string(), object);
+                Ok(())
+            }
+            None => Err(anyhow::anyhow!("{} does not exist", from)),
+        }
+    }
+
+    async fn object_exists(&self, name: &str) -> Result<bool, anyhow::Error> {
+        Ok(self.objects().contains_key(name))
+    }
+
+    async fn list_objects(&self, prefix: &str) -> Result<Vec<ListedObject>, anyhow::Error> {
+        Ok(self
+            .objects()
+            .iter()
+            .filter(|(name, _)| name.starts_with(prefix))
+            .map(|(name, object)| ListedObject {
+                name: name.to_owned(),
+                size: object.len() as u64,
+                modified: None,
+            })
+            .collect())
+    }
+}
@@ -0,0 +1,410 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use anyhow::anyhow;
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::{channel::mpsc, future, Future, SinkExt, StreamExt};
+
+use super::{ListedObject, ObjectRange, ObjectStream, StorageProvider};
+
+/// Reads try a replica last for this long after it failed to serve an object another replica had
+const UNHEALTHY_PERIOD: Duration = Duration::from_secs(30);
+
+/// Operation a replica missed, resyncing repeats it
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MissedOperation {
+    Write,
+    Delete,
+}
+
+#[async_trait]
+/// Record of the operations replicas missed, kept until they were resynced
+pub trait ReplicaLog: Sync + Send {
+    /// Remember that a replica missed an operation, replacing what it missed on the object before
+    async fn missed(
+        &self,
+        name: &str,
+        replica: &str,
+        operation: MissedOperation,
+        error: &str,
+    ) -> Result<(), anyhow::Error>;
+
+    /// Forget everything replicas missed on an object, it was deleted or renamed
+    async fn forget(&self, name: &str) -> Result<(), anyhow::Error>;
+}
+
+/// Child provider of a mirrored provider, the name identifies it in the replica log
+pub struct Replica {
+    pub name: String,
+    pub provider: Box<dyn StorageProvider>,
+}
+
+/// Storage provider writing every object to several replicas.
+///
+/// Writes, renames and deletes succeed once `write_quorum` replicas completed them,
+/// replicas which missed one are recorded so it can be repeated by [`StorageProvider::resync_object`].
+/// Reads are served by the first healthy replica which has the object.
+pub struct MirroredProvider {
+    replicas: Vec<Replica>,
+    write_quorum: usize,
+    log: Box<dyn ReplicaLog>,
+
+    // Until when each replica is tried last by reads
+    unhealthy: Vec<Mutex<Option<Instant>>>,
+}
+
+impl MirroredProvider {
+    pub fn new(replicas: Vec<Replica>, write_quorum: usize, log: Box<dyn ReplicaLog>) -> Self {
+        let unhealthy = replicas.iter().map(|_| Mutex::new(None)).collect();
+
+        MirroredProvider {
+            replicas,
+            write_quorum,
+            log,
+            unhealthy,
+        }
+    }
+
+    /// Indices of the replicas in the order reads try them, healthy replicas first
+    fn read_order(&self) -> Vec<usize> {
+        let now = Instant::now();
+        let (mut order, unhealthy): (Vec<usize>, Vec<usize>) =
+            (0..self.replicas.len()).partition(|&index| match self.unhealthy[index].lock() {
+                Ok(until) => until.map_or(true, |until| until <= now),
+                Err(_) => true,
+            });
+
+        order.extend(unhealthy);
+        order
+    }
+
+    fn mark_unhealthy(&self, index: usize) {
+        if let Ok(mut until) = self.unhealthy[index].lock() {
+            *until = Some(Instant::now() + UNHEALTHY_PERIOD);
+        }
+    }
+
+    /// Replicas an operation failed on, with their errors
+    fn failures(&self, results: Vec<Result<(), anyhow::Error>>) -> Vec<(&Replica, anyhow::Error)> {
+        self.replicas
+            .iter()
+            .zip(results)
+            .filter_map(|(replica, result)| result.err().map(|err| (replica, err)))
+            .collect()
+    }
+
+    fn has_quorum(&self, failures: &[(&Replica, anyhow::Error)]) -> bool {
+        self.replicas.len() - failures.len() >= self.write_quorum
+    }
+
+    fn quorum_error(&self, failures: Vec<(&Replica, anyhow::Error)>) -> anyhow::Error {
+        let succeeded = self.replicas.len() - failures.len();
+
+        match failures.into_iter().next() {
+            Some((replica, err)) => err.context(format!(
+                "Write quorum not reached, {} replicas succeeded and {} are required (replica {})",
+                succeeded, self.write_quorum, replica.name
+            )),
+            None => anyhow!("Write quorum not reached"),
+        }
+    }
+
+    /// Record the replicas which failed an operation so it is repeated when resyncing
+    async fn record_missed(
+        &self,
+        name: &str,
+        operation: MissedOperation,
+        failures: &[(&Replica, anyhow::Error)],
+    ) {
+        for (replica, err) in failures {
+            log::warn!(
+                "Replica {} missed {:?} of {}: {}",
+                replica.name,
+                operation,
+                name,
+                err
+            );
+
+            if let Err(err) = self
+                .log
+                .missed(name, &replica.name, operation, &err.to_string())
+                .await
+            {
+                log::error!("Unable to record missed operation of {}: {}", name, err);
+            }
+        }
+    }
+}
+
+/// Copy a stream into one stream for every replica.
+/// Replicas whose write failed stop receiving, the others continue.
+fn fan_out(data: ObjectStream, count: usize) -> (impl Future<Output = ()>, Vec<ObjectStream>) {
+    let (mut senders, receivers): (Vec<_>, Vec<_>) = (0..count)
+        .map(|_| mpsc::channel::<Result<Bytes, anyhow::Error>>(16))
+        .unzip();
+
+    let forward = async move {
+        let mut data = data;
+
+        while let Some(item) = data.next().await {
+            match item {
+                Ok(chunk) => {
+                    let sent = future::join_all(
+                        senders
+                            .iter_mut()
+                            .map(|sender| sender.send(Ok(chunk.clone()))),
+                    )
+                    .await;
+
+                    if sent.iter().all(|result| result.is_err()) {
+                        return;
+                    }
+                }
+                Err(err) => {
+                    // Every write must fail so no replica keeps a partial object
+                    let message = err.to_string();
+                    for sender in senders.iter_mut() {
+                        let _ = sender.send(Err(anyhow!(message.clone()))).await;
+                    }
+
+                    return;
+                }
+            }
+        }
+    };
+
+    let streams = receivers
+        .into_iter()
+        .map(|receiver| Box::pin(receiver) as ObjectStream)
+        .collect();
+
+    (forward, streams)
+}
+
+#[async_trait]
+impl StorageProvider for MirroredProvider {
+    async fn put_object(&self, name: &str, data: ObjectStream) -> Result<(), anyhow::Error> {
+        let (forward, streams) = fan_out(data, self.replicas.len());
+        let writes = future::join_all(
+            self.replicas
+                .iter()
+                .zip(streams)
+                .map(|(replica, stream)| replica.provider.put_object(name, stream)),
+        );
+
+        let (_, results) = futures::join!(forward, writes);
+        let failures = self.failures(results);
+
+        // Written copies are kept even without a quorum, the object may have existed before.
+        // Resyncing brings the other replicas to the version which was written.
+        self.record_missed(name, MissedOperation::Write, &failures)
+            .await;
+
+        match self.has_quorum(&failures) {
+            true => Ok(()),
+            false => Err(self.quorum_error(failures)),
+        }
+    }
+
+    async fn delete_object(&self, name: &str) -> Result<(), anyhow::Error> {
+        let results = future::join_all(
+            self.replicas
+                .iter()
+                .map(|replica| replica.provider.delete_object(name)),
+        )
+        .await;
+        let failures = self.failures(results);
+
+        if let Err(err) = self.log.forget(name).await {
+            log::error!("Unable to forget missed operations of {}: {}", name, err);
+        }
+
+        // Deletes are repeated even without a quorum, the object is meant to be gone
+        self.record_missed(name, MissedOperation::Delete, &failures)
+            .await;
+
+        match self.has_quorum(&failures) {
+            true => Ok(()),
+            false => Err(self.quorum_error(failures)),
+        }
+    }
+
+    async fn get_object(
+        &self,
+        path: &str,
+        range: Option<ObjectRange>,
+    ) -> Result<ObjectStream, anyhow::Error> {
+        let mut failed = Vec::new();
+        let mut last_error = None;
+
+        for index in self.read_order() {
+            match self.replicas[index].provider.get_object(path, range).await {
+                Ok(stream) => {
+                    // The object exists so the replicas before this one are unavailable or out of sync
+                    for index in failed {
+                        self.mark_unhealthy(index);
+                    }
+
+                    return Ok(stream);
+                }
+                Err(err) => {
+                    failed.push(index);
+                    last_error = Some(err);
+                }
+            }
+        }
+
+        Err(last_error.unwrap_or_else(|| anyhow!("No replicas configured")))
+    }
+
+    async fn rename_object(&self, from: &str, to: &str) -> Result<(), anyhow::Error> {
+        let results = future::join_all(
+            self.replicas
+                .iter()
+                .map(|replica| replica.provider.rename_object(from, to)),
+        )
+        .await;
+        let renamed: Vec<bool> = results.iter().map(|result| result.is_ok()).collect();
+        let failures = self.failures(results);
+
+        if !self.has_quorum(&failures) {
+            // The object stays under its old name, replicas which renamed it are renamed back
+            let mut unreverted = Vec::new();
+            for (replica, renamed) in self.replicas.iter().zip(renamed) {
+                if renamed {
+                    if let Err(err) = replica.provider.rename_object(to, from).await {
+                        unreverted.push((replica, err));
+                    }
+                }
+            }
+
+            self.record_missed(from, MissedOperation::Write, &unreverted)
+                .await;
+            self.record_missed(to, MissedOperation::Delete, &unreverted)
+                .await;
+            return Err(self.quorum_error(failures));
+        }
+
+        if let Err(err) = self.log.forget(from).await {
+            log::error!("Unable to forget missed operations of {}: {}", from, err);
+        }
+
+        self.record_missed(to, MissedOperation::Write, &failures)
+            .await;
+        self.record_missed(from, MissedOperation::Delete, &failures)
+            .await;
+        Ok(())
+    }
+
+    /// Exists on any replica, reads fall back to the others
+    async fn object_exists(&self, name: &str) -> Result<bool, anyhow::Error> {
+        let mut last_error = None;
+
+        for index in self.read_order() {
+            match self.replicas[index].provider.object_exists(name).await {
+                Ok(true) => return Ok(true),
+                Ok(false) => {}
+                Err(err) => last_error = Some(err),
+            }
+        }
+
+        match last_error {
+            Some(err) => Err(err),
+            None => Ok(false),
+        }
+    }
+
+    /// Objects of every replica which could be listed
+    async fn list_objects(&self, prefix: &str) -> Result<Vec<ListedObject>, anyhow::Error> {
+        let mut objects = HashMap::new();
+        let mut last_error = None;
+        let mut listed = false;
+
+        for index in self.read_order() {
+            match self.replicas[index].provider.list_objects(prefix).await {
+                Ok(v) => {
+                    listed = true;
+                    for object in v {
+                        objects.entry(object.name.to_owned()).or_insert(object);
+                    }
+                }
+                Err(err) => last_error = Some(err),
+            }
+        }
+
+        match (listed, last_error) {
+            (false, Some(err)) => Err(err),
+            _ => Ok(objects.into_values().collect()),
+        }
+    }
+
+    async fn set_object_public(&self, name: &str, public: bool) -> Result<(), anyhow::Error> {
+        let results = future::join_all(
+            self.replicas
+                .iter()
+                .map(|replica| replica.provider.set_object_public(name, public)),
+        )
+        .await;
+        let failures = self.failures(results);
+
+        if !self.has_quorum(&failures) {
+            return Err(self.quorum_error(failures));
+        }
+
+        // Resyncing writes the object again and applies its access
+        self.record_missed(name, MissedOperation::Write, &failures)
+            .await;
+        Ok(())
+    }
+
+    fn get_presigned_url(&self, name: &str, expires_in: Duration) -> Option<String> {
+        let index = *self.read_order().first()?;
+        self.replicas[index]
+            .provider
+            .get_presigned_url(name, expires_in)
+    }
+
+    async fn resync_object(
+        &self,
+        name: &str,
+        replica: &str,
+        operation: MissedOperation,
+    ) -> Result<(), anyhow::Error> {
+        let target = self
+            .replicas
+            .iter()
+            .position(|v| v.name == replica)
+            .ok_or_else(|| anyhow!("Unknown replica {}", replica))?;
+        let provider = &self.replicas[target].provider;
+
+        match operation {
+            MissedOperation::Delete => match provider.delete_object(name).await {
+                Ok(_) => Ok(()),
+                Err(err) => {
+                    // Providers may fail to delete objects which are already gone
+                    match provider.object_exists(name).await? {
+                        true => Err(err),
+                        false => Ok(()),
+                    }
+                }
+            },
+            MissedOperation::Write => {
+                for index in self.read_order() {
+                    if index == target {
+                        continue;
+                    }
+
+                    if let Ok(data) = self.replicas[index].provider.get_object(name, None).await {
+                        return provider.put_object(name, data).await;
+                    }
+                }
+
+                Err(anyhow!("No other replica could read {}", name))
+            }
+        }
+    }
+}
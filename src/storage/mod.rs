@@ -1,16 +1,283 @@
+pub mod conformance;
+pub mod encrypted;
 pub mod local;
+pub mod memory;
+pub mod mirrored;
 pub mod s3;
+pub mod sharded;
+pub mod tiered;
+pub mod webdav;
+
+use std::{
+    pin::Pin,
+    sync::Mutex,
+    task::{Context, Poll},
+    time::Duration,
+};
 
 use async_trait::async_trait;
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use futures::{channel::mpsc, future, stream, SinkExt, Stream, StreamExt, TryStreamExt};
+use nanoid::nanoid;
+
+use self::{mirrored::MissedOperation, tiered::Tier};
+
+/// Stream of object bytes sent to or received from a storage provider
+pub type ObjectStream = Pin<Box<dyn Stream<Item = Result<Bytes, anyhow::Error>> + Send>>;
+
+/// HTTP clients require streaming bodies to be `Sync`.
+/// The stream is only ever polled by the request so the lock is never contended.
+pub(crate) struct SyncStream(pub(crate) Mutex<ObjectStream>);
+
+impl Stream for SyncStream {
+    type Item = Result<Bytes, std::io::Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        match self.get_mut().0.get_mut() {
+            Ok(stream) => stream.as_mut().poll_next(cx).map(|item| {
+                item.map(|chunk| {
+                    chunk.map_err(|err| {
+                        std::io::Error::new(std::io::ErrorKind::Other, err.to_string())
+                    })
+                })
+            }),
+            Err(_) => Poll::Ready(None),
+        }
+    }
+}
+
+/// Inclusive byte range of an object, mirrors the HTTP `Range` header
+#[derive(Clone, Copy, Debug)]
+pub struct ObjectRange {
+    pub start: u64,
+
+    // Read until the end of the object if not provided
+    pub end: Option<u64>,
+}
+
+impl ObjectRange {
+    /// Value of this range as a `Range` header
+    pub fn to_header(&self) -> String {
+        match self.end {
+            Some(end) => format!("bytes={}-{}", self.start, end),
+            None => format!("bytes={}-", self.start),
+        }
+    }
+}
+
+/// Object found when listing a storage provider
+#[derive(Clone, Debug)]
+pub struct ListedObject {
+    pub name: String,
+    pub size: u64,
+
+    // Not every storage source reports when an object was last written
+    pub modified: Option<DateTime<Utc>>,
+}
+
+/// Chunk of a chunked upload which was written to a storage provider
+#[derive(Clone, Debug)]
+pub struct UploadedChunk {
+    pub number: i32,
+
+    // Provider specific identifier of the chunk (ETag for S3)
+    pub tag: String,
+}
+
+/// Prefix of uploaded objects whose file was not committed yet
+pub const PENDING_PREFIX: &str = "pending/";
+
+/// Name an upload is written to before its file is committed and the object is promoted to `name`
+pub fn pending_name(name: &str) -> String {
+    format!("{}{}", PENDING_PREFIX, name)
+}
+
+/// Object name of a chunk when a provider has no native chunked uploads
+pub fn chunk_object_name(upload_id: &str, number: i32) -> String {
+    format!("chunks/{}/{}", upload_id, number)
+}
+
+/// Concatenate objects in order into a new object.
+/// The objects are streamed so this works with any provider regardless of object size.
+pub async fn concat_objects<S: StorageProvider + ?Sized>(
+    storage: &S,
+    name: &str,
+    parts: &[String],
+) -> Result<(), anyhow::Error> {
+    let (mut sender, receiver) = mpsc::channel::<Result<Bytes, anyhow::Error>>(16);
+
+    let read = async move {
+        for part in parts {
+            match storage.get_object(part, None).await {
+                Ok(mut stream) => {
+                    while let Some(item) = stream.next().await {
+                        if sender.send(item).await.is_err() {
+                            return;
+                        }
+                    }
+                }
+                Err(err) => {
+                    let _ = sender.send(Err(err)).await;
+                    return;
+                }
+            }
+        }
+    };
+
+    let (_, result) = futures::join!(read, storage.put_object(name, Box::pin(receiver)));
+    result
+}
 
 #[async_trait]
 /// Base storage provider type
 pub trait StorageProvider: Sync + Send {
     /// Put the object/file on the storage source
-    async fn put_object(&self, name: &str, data: &Vec<u8>) -> Result<(), anyhow::Error>;
+    ///
+    /// Objects are written private and made readable with [`StorageProvider::set_object_public`].
+    /// An error item in the stream must abort the write without leaving a partial object.
+    async fn put_object(&self, name: &str, data: ObjectStream) -> Result<(), anyhow::Error>;
 
     /// Delete the object/file on the storage source
     async fn delete_object(&self, name: &str) -> Result<(), anyhow::Error>;
 
-    async fn get_object(&self, path: &str) -> Result<Vec<u8>, anyhow::Error>;
+    /// Get a stream of the object/file, optionally only a byte range of it
+    async fn get_object(
+        &self,
+        path: &str,
+        range: Option<ObjectRange>,
+    ) -> Result<ObjectStream, anyhow::Error>;
+
+    /// Move an object to another name, replacing any object with that name
+    ///
+    /// By default the object is copied and the original deleted afterwards.
+    async fn rename_object(&self, from: &str, to: &str) -> Result<(), anyhow::Error> {
+        let data = self.get_object(from, None).await?;
+        self.put_object(to, data).await?;
+
+        // We dont care about the result of this, the object is complete under its new name
+        let _ = self.delete_object(from).await;
+        Ok(())
+    }
+
+    /// List every object whose name starts with `prefix`, in no particular order
+    async fn list_objects(&self, prefix: &str) -> Result<Vec<ListedObject>, anyhow::Error>;
+
+    /// Check whether an object exists without reading it
+    async fn object_exists(&self, name: &str) -> Result<bool, anyhow::Error> {
+        Ok(self
+            .list_objects(name)
+            .await?
+            .iter()
+            .any(|object| object.name == name))
+    }
+
+    /// Change whether an object can be read directly from the storage source
+    ///
+    /// Providers which can't restrict reads rely on the application to check access instead.
+    async fn set_object_public(&self, _name: &str, _public: bool) -> Result<(), anyhow::Error> {
+        Ok(())
+    }
+
+    /// URL which can read an object until `expires_in` has passed, even if it is not public
+    ///
+    /// `None` if the storage source can't sign URLs, the application signs them instead.
+    fn get_presigned_url(&self, _name: &str, _expires_in: Duration) -> Option<String> {
+        None
+    }
+
+    /// Encrypt an object which was written before the storage source encrypted at rest, keeping its name
+    ///
+    /// `false` if the storage source stores objects as they are.
+    async fn encrypt_object(&self, _name: &str) -> Result<bool, anyhow::Error> {
+        Ok(false)
+    }
+
+    /// Repeat an operation on an object which a replica of a mirrored provider missed
+    async fn resync_object(
+        &self,
+        _name: &str,
+        _replica: &str,
+        _operation: MissedOperation,
+    ) -> Result<(), anyhow::Error> {
+        anyhow::bail!("The storage provider has no replicas")
+    }
+
+    /// Move an object to another tier of a tiered provider
+    async fn move_object(&self, _name: &str, _tier: Tier) -> Result<(), anyhow::Error> {
+        anyhow::bail!("The storage provider has no tiers")
+    }
+
+    /// Put an object which is already fully in memory
+    async fn put_object_bytes(&self, name: &str, data: Vec<u8>) -> Result<(), anyhow::Error> {
+        self.put_object(name, Box::pin(stream::once(future::ok(Bytes::from(data)))))
+            .await
+    }
+
+    /// Read the entire object into memory
+    /// Only use this when the whole object is needed at once (image decoding)
+    async fn get_object_bytes(&self, path: &str) -> Result<Vec<u8>, anyhow::Error> {
+        self.get_object(path, None)
+            .await?
+            .try_fold(Vec::new(), |mut buf, chunk| {
+                buf.extend_from_slice(&chunk);
+                future::ok(buf)
+            })
+            .await
+    }
+
+    /// Start a chunked upload for an object, returns the upload ID
+    ///
+    /// By default chunks are stored as separate objects and concatenated on completion.
+    async fn create_chunked_upload(&self, _name: &str) -> Result<String, anyhow::Error> {
+        Ok(nanoid!())
+    }
+
+    /// Put a numbered chunk of a chunked upload, returns the chunk tag
+    /// Uploading the same chunk number again replaces it
+    async fn put_chunk(
+        &self,
+        _name: &str,
+        upload_id: &str,
+        number: i32,
+        data: ObjectStream,
+        _size: u64,
+    ) -> Result<String, anyhow::Error> {
+        self.put_object(&chunk_object_name(upload_id, number), data)
+            .await?;
+
+        Ok(number.to_string())
+    }
+
+    /// Assemble the chunks in order into the final object
+    async fn complete_chunked_upload(
+        &self,
+        name: &str,
+        upload_id: &str,
+        chunks: &[UploadedChunk],
+    ) -> Result<(), anyhow::Error> {
+        let parts: Vec<String> = chunks
+            .iter()
+            .map(|chunk| chunk_object_name(upload_id, chunk.number))
+            .collect();
+
+        concat_objects(self, name, &parts).await?;
+        self.abort_chunked_upload(name, upload_id, chunks).await
+    }
+
+    /// Discard every chunk of a chunked upload
+    async fn abort_chunked_upload(
+        &self,
+        _name: &str,
+        upload_id: &str,
+        chunks: &[UploadedChunk],
+    ) -> Result<(), anyhow::Error> {
+        for chunk in chunks {
+            let _ = self
+                .delete_object(&chunk_object_name(upload_id, chunk.number))
+                .await;
+        }
+
+        Ok(())
+    }
 }
@@ -1,15 +1,86 @@
-use super::StorageProvider;
+use std::{future::Future, sync::Mutex, time::Duration};
+
+use super::{ListedObject, ObjectRange, ObjectStream, StorageProvider, SyncStream, UploadedChunk};
 use async_trait::async_trait;
-use futures::TryStreamExt;
+use bytes::BytesMut;
+use chrono::{DateTime, Utc};
+use futures::{StreamExt, TryStreamExt};
 use infer;
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use rand::Rng;
+
+use rusoto_core::{
+    credential::{self, AwsCredentials},
+    ByteStream, HttpClient, Region, RusotoError,
+};
+
+use rusoto_s3::{
+    util::{PreSignedRequest, PreSignedRequestOption},
+    AbortMultipartUploadRequest, CompleteMultipartUploadRequest, CompletedMultipartUpload,
+    CompletedPart, CopyObjectRequest, CreateMultipartUploadRequest, DeleteObjectRequest,
+    GetObjectRequest, HeadObjectError, HeadObjectRequest, ListObjectsV2Request,
+    PutObjectAclRequest, PutObjectRequest, S3Client, UploadPartRequest, S3,
+};
+
+/// Size of each part in a multipart upload, S3 requires at least 5MB per part.
+/// This is the most that will be buffered in memory per upload.
+const PART_SIZE: usize = 8 * 1024 * 1024;
 
-use rusoto_core::{credential, ByteStream, HttpClient, Region};
+/// Attempts of a request before a transient error is returned
+const MAX_ATTEMPTS: u32 = 4;
 
-use rusoto_s3::{DeleteObjectRequest, GetObjectRequest, PutObjectRequest, S3Client, S3};
+/// Delay before the first retry, doubled after every further attempt
+const RETRY_DELAY: Duration = Duration::from_millis(200);
+
+/// Characters escaped in the source key of a copy, slashes separate the bucket and key
+const COPY_SOURCE: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'/')
+    .remove(b'-')
+    .remove(b'_')
+    .remove(b'.');
+
+/// Errors which may not happen again when the same request is sent again,
+/// such as dropped connections, throttling and internal errors of the storage service
+fn is_transient<E>(err: &RusotoError<E>) -> bool {
+    match err {
+        RusotoError::HttpDispatch(_) => true,
+        RusotoError::Unknown(response) => {
+            response.status.is_server_error() || response.status.as_u16() == 429
+        }
+        _ => false,
+    }
+}
+
+/// Send a request until it succeeds or fails with an error which is not transient.
+/// Retries back off exponentially with jitter so concurrent uploads don't retry in lockstep.
+async fn with_retries<T, E, F, R>(mut request: F) -> Result<T, RusotoError<E>>
+where
+    F: FnMut() -> R,
+    R: Future<Output = Result<T, RusotoError<E>>>,
+{
+    let mut attempt = 1;
+
+    loop {
+        match request().await {
+            Err(err) if attempt < MAX_ATTEMPTS && is_transient(&err) => {
+                let delay = RETRY_DELAY * 2u32.pow(attempt - 1);
+                let jitter = rand::thread_rng().gen_range(0..=delay.as_millis() as u64 / 2);
+                tokio::time::sleep(delay + Duration::from_millis(jitter)).await;
+
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
 
 pub struct S3Provider {
     bucket: String,
     client: S3Client,
+
+    // Needed to presign URLs without a request
+    region: Region,
+    credentials: AwsCredentials,
 }
 
 impl S3Provider {
@@ -20,62 +91,401 @@ impl S3Provider {
             client: S3Client::new_with(
                 HttpClient::new().expect("S3 dispatcher could not be created"),
                 credential_provider,
-                s3_region,
+                s3_region.clone(),
             ),
             bucket: bucket.into(),
+            region: s3_region,
+            credentials: AwsCredentials::new(access_key, secret_key, None, None),
         }
     }
+
+    /// Fill the buffer from the stream until it reaches [`PART_SIZE`] or the stream ends.
+    /// Returns false when the stream has ended.
+    async fn fill_part(
+        buffer: &mut BytesMut,
+        data: &mut ObjectStream,
+    ) -> Result<bool, anyhow::Error> {
+        while buffer.len() < PART_SIZE {
+            match data.next().await {
+                Some(chunk) => buffer.extend_from_slice(&chunk?),
+                None => return Ok(false),
+            }
+        }
+
+        Ok(true)
+    }
+
+    /// Upload every remaining part of a multipart upload
+    async fn upload_parts(
+        &self,
+        name: &str,
+        upload_id: &str,
+        mut buffer: BytesMut,
+        mut data: ObjectStream,
+    ) -> Result<Vec<CompletedPart>, anyhow::Error> {
+        let mut parts = Vec::new();
+        let mut more = true;
+
+        while more {
+            more = Self::fill_part(&mut buffer, &mut data).await?;
+            if buffer.is_empty() {
+                break;
+            }
+
+            let part_number = parts.len() as i64 + 1;
+            let body = buffer.split().to_vec();
+
+            // Parts are buffered so they can be sent again
+            let output = with_retries(|| {
+                self.client.upload_part(UploadPartRequest {
+                    bucket: self.bucket.clone(),
+                    key: name.to_string(),
+                    upload_id: upload_id.to_string(),
+                    part_number,
+                    content_length: Some(body.len() as i64),
+                    body: Some(ByteStream::from(body.clone())),
+                    ..Default::default()
+                })
+            })
+            .await?;
+
+            parts.push(CompletedPart {
+                e_tag: output.e_tag,
+                part_number: Some(part_number),
+                ..Default::default()
+            });
+        }
+
+        Ok(parts)
+    }
 }
 
 #[async_trait]
 impl StorageProvider for S3Provider {
-    async fn put_object(&self, name: &str, data: &Vec<u8>) -> Result<(), anyhow::Error> {
+    async fn put_object(&self, name: &str, mut data: ObjectStream) -> Result<(), anyhow::Error> {
+        let mut buffer = BytesMut::new();
+        let more = Self::fill_part(&mut buffer, &mut data).await?;
+
         // Attempt to detect content type
-        let content_type = match infer::get(&data) {
+        let content_type = match infer::get(&buffer) {
             Some(kind) => Some(kind.mime_type().to_string()),
             None => None,
         };
 
-        self.client
-            .put_object(PutObjectRequest {
+        // Small objects fit in a single request
+        if !more {
+            with_retries(|| {
+                self.client.put_object(PutObjectRequest {
+                    bucket: self.bucket.clone(),
+                    body: Some(ByteStream::from(buffer.to_vec())),
+                    key: name.to_string(),
+                    acl: Some("private".into()),
+                    content_type: content_type.clone(),
+                    ..Default::default()
+                })
+            })
+            .await?;
+
+            return Ok(());
+        }
+
+        let upload_id = with_retries(|| {
+            self.client
+                .create_multipart_upload(CreateMultipartUploadRequest {
+                    bucket: self.bucket.clone(),
+                    key: name.to_string(),
+                    acl: Some("private".into()),
+                    content_type: content_type.clone(),
+                    ..Default::default()
+                })
+        })
+        .await?
+        .upload_id
+        .ok_or(anyhow::anyhow!("No upload ID was returned for {}", name))?;
+
+        match self.upload_parts(name, &upload_id, buffer, data).await {
+            Ok(parts) => {
+                with_retries(|| {
+                    self.client
+                        .complete_multipart_upload(CompleteMultipartUploadRequest {
+                            bucket: self.bucket.clone(),
+                            key: name.to_string(),
+                            upload_id: upload_id.clone(),
+                            multipart_upload: Some(CompletedMultipartUpload {
+                                parts: Some(parts.clone()),
+                            }),
+                            ..Default::default()
+                        })
+                })
+                .await?;
+
+                Ok(())
+            }
+            Err(err) => {
+                // Parts that were already uploaded are discarded by aborting
+                let _ = with_retries(|| {
+                    self.client
+                        .abort_multipart_upload(AbortMultipartUploadRequest {
+                            bucket: self.bucket.clone(),
+                            key: name.to_string(),
+                            upload_id: upload_id.clone(),
+                            ..Default::default()
+                        })
+                })
+                .await;
+
+                Err(err)
+            }
+        }
+    }
+
+    async fn delete_object(&self, name: &str) -> Result<(), anyhow::Error> {
+        with_retries(|| {
+            self.client.delete_object(DeleteObjectRequest {
                 bucket: self.bucket.clone(),
-                body: Some(ByteStream::from(data.clone())),
                 key: name.to_string(),
-                acl: Some("public-read".into()),
-                content_type: content_type,
                 ..Default::default()
             })
-            .await?;
+        })
+        .await?;
 
         Ok(())
     }
 
-    async fn delete_object(&self, name: &str) -> Result<(), anyhow::Error> {
-        self.client
-            .delete_object(DeleteObjectRequest {
+    async fn rename_object(&self, from: &str, to: &str) -> Result<(), anyhow::Error> {
+        let copy_source = format!("{}/{}", self.bucket, utf8_percent_encode(from, COPY_SOURCE));
+
+        // Copied within the bucket without passing through the application
+        let copied = with_retries(|| {
+            self.client.copy_object(CopyObjectRequest {
+                bucket: self.bucket.clone(),
+                key: to.to_string(),
+                copy_source: copy_source.clone(),
+                acl: Some("private".into()),
+                ..Default::default()
+            })
+        })
+        .await;
+
+        // Objects over 5GB can't be copied in one request and not every S3 compatible service supports copying
+        if let Err(err) = copied {
+            log::warn!(
+                "Unable to copy {} to {}, streaming it instead: {}",
+                from,
+                to,
+                err
+            );
+            let data = self.get_object(from, None).await?;
+            self.put_object(to, data).await?;
+        }
+
+        // We dont care about the result of this, the object is complete under its new name
+        let _ = self.delete_object(from).await;
+        Ok(())
+    }
+
+    async fn object_exists(&self, name: &str) -> Result<bool, anyhow::Error> {
+        let result = with_retries(|| {
+            self.client.head_object(HeadObjectRequest {
                 bucket: self.bucket.clone(),
                 key: name.to_string(),
                 ..Default::default()
             })
+        })
+        .await;
+
+        match result {
+            Ok(_) => Ok(true),
+            // Responses to HEAD requests have no body, a missing object is only told apart by its status
+            Err(RusotoError::Service(HeadObjectError::NoSuchKey(_))) => Ok(false),
+            Err(RusotoError::Unknown(response)) if response.status.as_u16() == 404 => Ok(false),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    async fn list_objects(&self, prefix: &str) -> Result<Vec<ListedObject>, anyhow::Error> {
+        let mut objects = Vec::new();
+        let mut continuation_token = None;
+
+        // Results are paginated by 1000 keys
+        loop {
+            let output = with_retries(|| {
+                self.client.list_objects_v2(ListObjectsV2Request {
+                    bucket: self.bucket.clone(),
+                    prefix: Some(prefix.to_string()),
+                    continuation_token: continuation_token.clone(),
+                    ..Default::default()
+                })
+            })
             .await?;
 
+            for object in output.contents.unwrap_or_default() {
+                if let Some(name) = object.key {
+                    objects.push(ListedObject {
+                        name,
+                        size: object.size.unwrap_or(0) as u64,
+                        modified: object
+                            .last_modified
+                            .and_then(|modified| DateTime::parse_from_rfc3339(&modified).ok())
+                            .map(|modified| modified.with_timezone(&Utc)),
+                    });
+                }
+            }
+
+            match output.next_continuation_token {
+                Some(token) if output.is_truncated == Some(true) => {
+                    continuation_token = Some(token)
+                }
+                _ => break,
+            }
+        }
+
+        Ok(objects)
+    }
+
+    async fn set_object_public(&self, name: &str, public: bool) -> Result<(), anyhow::Error> {
+        with_retries(|| {
+            self.client.put_object_acl(PutObjectAclRequest {
+                bucket: self.bucket.clone(),
+                key: name.to_string(),
+                acl: Some(if public { "public-read" } else { "private" }.into()),
+                ..Default::default()
+            })
+        })
+        .await?;
+
         Ok(())
     }
 
-    async fn get_object(&self, path: &str) -> Result<Vec<u8>, anyhow::Error> {
-        match self
-            .client
-            .get_object(GetObjectRequest {
+    fn get_presigned_url(&self, name: &str, expires_in: Duration) -> Option<String> {
+        Some(
+            GetObjectRequest {
+                bucket: self.bucket.clone(),
+                key: name.to_string(),
+                ..Default::default()
+            }
+            .get_presigned_url(
+                &self.region,
+                &self.credentials,
+                &PreSignedRequestOption { expires_in },
+            ),
+        )
+    }
+
+    async fn get_object(
+        &self,
+        path: &str,
+        range: Option<ObjectRange>,
+    ) -> Result<ObjectStream, anyhow::Error> {
+        // Only the request is retried, errors while streaming the body are not
+        match with_retries(|| {
+            self.client.get_object(GetObjectRequest {
                 bucket: self.bucket.clone(),
                 key: path.to_string(),
+                range: range.map(|range| range.to_header()),
                 ..Default::default()
             })
-            .await?
-            .body
-            .take()
+        })
+        .await?
+        .body
+        .take()
         {
-            Some(stream) => Ok(stream.map_ok(|b| b.to_vec()).try_concat().await?),
+            Some(stream) => Ok(Box::pin(stream.map_err(anyhow::Error::from))),
             None => Err(anyhow::anyhow!(format!("No file stream found on {}", path))),
         }
     }
+
+    async fn create_chunked_upload(&self, name: &str) -> Result<String, anyhow::Error> {
+        Ok(with_retries(|| {
+            self.client
+                .create_multipart_upload(CreateMultipartUploadRequest {
+                    bucket: self.bucket.clone(),
+                    key: name.to_string(),
+                    acl: Some("private".into()),
+                    ..Default::default()
+                })
+        })
+        .await?
+        .upload_id
+        .ok_or(anyhow::anyhow!("No upload ID was returned for {}", name))?)
+    }
+
+    async fn put_chunk(
+        &self,
+        name: &str,
+        upload_id: &str,
+        number: i32,
+        data: ObjectStream,
+        size: u64,
+    ) -> Result<String, anyhow::Error> {
+        // Chunks are streamed through so they can't be sent again, clients retry failed chunks
+        self.client
+            .upload_part(UploadPartRequest {
+                bucket: self.bucket.clone(),
+                key: name.to_string(),
+                upload_id: upload_id.to_string(),
+                part_number: number as i64,
+                content_length: Some(size as i64),
+                body: Some(ByteStream::new_with_size(
+                    SyncStream(Mutex::new(data)),
+                    size as usize,
+                )),
+                ..Default::default()
+            })
+            .await?
+            .e_tag
+            .ok_or(anyhow::anyhow!("No ETag was returned for part {}", number))
+    }
+
+    async fn complete_chunked_upload(
+        &self,
+        name: &str,
+        upload_id: &str,
+        chunks: &[UploadedChunk],
+    ) -> Result<(), anyhow::Error> {
+        let parts: Vec<CompletedPart> = chunks
+            .iter()
+            .map(|chunk| CompletedPart {
+                e_tag: Some(chunk.tag.clone()),
+                part_number: Some(chunk.number as i64),
+                ..Default::default()
+            })
+            .collect();
+
+        with_retries(|| {
+            self.client
+                .complete_multipart_upload(CompleteMultipartUploadRequest {
+                    bucket: self.bucket.clone(),
+                    key: name.to_string(),
+                    upload_id: upload_id.to_string(),
+                    multipart_upload: Some(CompletedMultipartUpload {
+                        parts: Some(parts.clone()),
+                    }),
+                    ..Default::default()
+                })
+        })
+        .await?;
+
+        Ok(())
+    }
+
+    async fn abort_chunked_upload(
+        &self,
+        name: &str,
+        upload_id: &str,
+        _chunks: &[UploadedChunk],
+    ) -> Result<(), anyhow::Error> {
+        with_retries(|| {
+            self.client
+                .abort_multipart_upload(AbortMultipartUploadRequest {
+                    bucket: self.bucket.clone(),
+                    key: name.to_string(),
+                    upload_id: upload_id.to_string(),
+                    ..Default::default()
+                })
+        })
+        .await?;
+
+        Ok(())
+    }
 }
@@ -0,0 +1,126 @@
+use std::{path::PathBuf, time::Duration};
+
+use super::{
+    local::LocalProvider, ListedObject, ObjectRange, ObjectStream, StorageProvider, UploadedChunk,
+};
+
+use async_trait::async_trait;
+use sha2::{Digest, Sha256};
+
+/// Path of an object below the storage directory.
+/// Two levels of directories named after the hash of the name spread objects evenly,
+/// `abc.png` is stored as `ab/b1/abc.png`.
+pub fn shard_name(name: &str) -> String {
+    let hash = format!("{:x}", Sha256::digest(name.as_bytes()));
+    format!("{}/{}/{}", &hash[..2], &hash[2..4], name)
+}
+
+/// Local filesystem provider which spreads objects over nested directories,
+/// so a single directory doesn't have to hold millions of files
+pub struct ShardedLocalProvider {
+    inner: LocalProvider,
+}
+
+impl ShardedLocalProvider {
+    pub fn new(path: PathBuf) -> Self {
+        ShardedLocalProvider {
+            inner: LocalProvider::new(path),
+        }
+    }
+}
+
+#[async_trait]
+impl StorageProvider for ShardedLocalProvider {
+    async fn put_object(&self, name: &str, data: ObjectStream) -> Result<(), anyhow::Error> {
+        self.inner.put_object(&shard_name(name), data).await
+    }
+
+    async fn delete_object(&self, name: &str) -> Result<(), anyhow::Error> {
+        self.inner.delete_object(&shard_name(name)).await
+    }
+
+    async fn get_object(
+        &self,
+        path: &str,
+        range: Option<ObjectRange>,
+    ) -> Result<ObjectStream, anyhow::Error> {
+        self.inner.get_object(&shard_name(path), range).await
+    }
+
+    async fn rename_object(&self, from: &str, to: &str) -> Result<(), anyhow::Error> {
+        self.inner
+            .rename_object(&shard_name(from), &shard_name(to))
+            .await
+    }
+
+    async fn object_exists(&self, name: &str) -> Result<bool, anyhow::Error> {
+        self.inner.object_exists(&shard_name(name)).await
+    }
+
+    async fn list_objects(&self, prefix: &str) -> Result<Vec<ListedObject>, anyhow::Error> {
+        // Files which aren't where their name would be sharded to are not objects, such as chunks
+        Ok(self
+            .inner
+            .list_objects("")
+            .await?
+            .into_iter()
+            .filter_map(|object| {
+                let name = object.name.splitn(3, '/').nth(2)?.to_string();
+                match shard_name(&name) == object.name && name.starts_with(prefix) {
+                    true => Some(ListedObject { name, ..object }),
+                    false => None,
+                }
+            })
+            .collect())
+    }
+
+    async fn set_object_public(&self, name: &str, public: bool) -> Result<(), anyhow::Error> {
+        self.inner
+            .set_object_public(&shard_name(name), public)
+            .await
+    }
+
+    fn get_presigned_url(&self, name: &str, expires_in: Duration) -> Option<String> {
+        self.inner.get_presigned_url(&shard_name(name), expires_in)
+    }
+
+    // Chunks live in their own temporary directory, only the assembled object is sharded
+    async fn create_chunked_upload(&self, name: &str) -> Result<String, anyhow::Error> {
+        self.inner.create_chunked_upload(&shard_name(name)).await
+    }
+
+    async fn put_chunk(
+        &self,
+        name: &str,
+        upload_id: &str,
+        number: i32,
+        data: ObjectStream,
+        size: u64,
+    ) -> Result<String, anyhow::Error> {
+        self.inner
+            .put_chunk(&shard_name(name), upload_id, number, data, size)
+            .await
+    }
+
+    async fn complete_chunked_upload(
+        &self,
+        name: &str,
+        upload_id: &str,
+        chunks: &[UploadedChunk],
+    ) -> Result<(), anyhow::Error> {
+        self.inner
+            .complete_chunked_upload(&shard_name(name), upload_id, chunks)
+            .await
+    }
+
+    async fn abort_chunked_upload(
+        &self,
+        name: &str,
+        upload_id: &str,
+        chunks: &[UploadedChunk],
+    ) -> Result<(), anyhow::Error> {
+        self.inner
+            .abort_chunked_upload(&shard_name(name), upload_id, chunks)
+            .await
+    }
+}
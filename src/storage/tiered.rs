@@ -0,0 +1,175 @@
+use std::{collections::HashMap, time::Duration};
+
+use async_trait::async_trait;
+
+use super::{
+    mirrored::MissedOperation, ListedObject, ObjectRange, ObjectStream, StorageProvider,
+    UploadedChunk,
+};
+
+/// Tier of a tiered provider an object is stored on
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Tier {
+    Hot,
+    Cold,
+}
+
+/// Storage provider moving objects which are rarely read to a cheaper provider.
+///
+/// Objects are written to the hot tier and only moved by [`StorageProvider::move_object`].
+/// Reads fall back to the cold tier, so the tier of an object doesn't have to be known to read it.
+/// Cold objects are never public, they are served by the application.
+pub struct TieredProvider {
+    hot: Box<dyn StorageProvider>,
+    cold: Box<dyn StorageProvider>,
+}
+
+impl TieredProvider {
+    pub fn new(hot: Box<dyn StorageProvider>, cold: Box<dyn StorageProvider>) -> Self {
+        TieredProvider { hot, cold }
+    }
+
+    /// Is an object stored on the cold tier
+    async fn is_cold(&self, name: &str) -> bool {
+        self.cold.object_exists(name).await.unwrap_or(false)
+    }
+}
+
+#[async_trait]
+impl StorageProvider for TieredProvider {
+    async fn put_object(&self, name: &str, data: ObjectStream) -> Result<(), anyhow::Error> {
+        self.hot.put_object(name, data).await
+    }
+
+    /// Deleted from both tiers, a move may have been interrupted before the original was deleted
+    async fn delete_object(&self, name: &str) -> Result<(), anyhow::Error> {
+        let hot = self.hot.delete_object(name).await;
+        let cold = self.cold.delete_object(name).await;
+
+        match (hot, cold) {
+            (Err(err), Err(_)) => Err(err),
+            _ => Ok(()),
+        }
+    }
+
+    async fn get_object(
+        &self,
+        path: &str,
+        range: Option<ObjectRange>,
+    ) -> Result<ObjectStream, anyhow::Error> {
+        match self.hot.get_object(path, range).await {
+            Ok(v) => Ok(v),
+            Err(err) => self.cold.get_object(path, range).await.map_err(|_| err),
+        }
+    }
+
+    /// Only uploads are renamed, they are always hot
+    async fn rename_object(&self, from: &str, to: &str) -> Result<(), anyhow::Error> {
+        self.hot.rename_object(from, to).await
+    }
+
+    async fn object_exists(&self, name: &str) -> Result<bool, anyhow::Error> {
+        Ok(self.hot.object_exists(name).await? || self.cold.object_exists(name).await?)
+    }
+
+    async fn list_objects(&self, prefix: &str) -> Result<Vec<ListedObject>, anyhow::Error> {
+        let mut objects = HashMap::new();
+        for object in self
+            .hot
+            .list_objects(prefix)
+            .await?
+            .into_iter()
+            .chain(self.cold.list_objects(prefix).await?)
+        {
+            objects.entry(object.name.to_owned()).or_insert(object);
+        }
+
+        Ok(objects.into_values().collect())
+    }
+
+    async fn set_object_public(&self, name: &str, public: bool) -> Result<(), anyhow::Error> {
+        match self.hot.set_object_public(name, public).await {
+            Err(_) if self.is_cold(name).await => Ok(()),
+            result => result,
+        }
+    }
+
+    /// Cold objects are read through the application, so only hot objects are presigned
+    fn get_presigned_url(&self, name: &str, expires_in: Duration) -> Option<String> {
+        self.hot.get_presigned_url(name, expires_in)
+    }
+
+    async fn move_object(&self, name: &str, tier: Tier) -> Result<(), anyhow::Error> {
+        let (from, to) = match tier {
+            Tier::Hot => (&self.cold, &self.hot),
+            Tier::Cold => (&self.hot, &self.cold),
+        };
+
+        let data = match from.get_object(name, None).await {
+            Ok(v) => v,
+            // A previous move may have been interrupted after deleting the original
+            Err(_) if to.object_exists(name).await.unwrap_or(false) => return Ok(()),
+            Err(err) => return Err(err),
+        };
+
+        to.put_object(name, data).await?;
+
+        // Reads fall back to the other tier, the copy is complete before the original is gone
+        let _ = from.delete_object(name).await;
+        Ok(())
+    }
+
+    /// Replicas of either tier may be mirrored
+    async fn resync_object(
+        &self,
+        name: &str,
+        replica: &str,
+        operation: MissedOperation,
+    ) -> Result<(), anyhow::Error> {
+        match self.hot.resync_object(name, replica, operation).await {
+            Ok(_) => Ok(()),
+            Err(err) => self
+                .cold
+                .resync_object(name, replica, operation)
+                .await
+                .map_err(|_| err),
+        }
+    }
+
+    async fn create_chunked_upload(&self, name: &str) -> Result<String, anyhow::Error> {
+        self.hot.create_chunked_upload(name).await
+    }
+
+    async fn put_chunk(
+        &self,
+        name: &str,
+        upload_id: &str,
+        number: i32,
+        data: ObjectStream,
+        size: u64,
+    ) -> Result<String, anyhow::Error> {
+        self.hot
+            .put_chunk(name, upload_id, number, data, size)
+            .await
+    }
+
+    async fn complete_chunked_upload(
+        &self,
+        name: &str,
+        upload_id: &str,
+        chunks: &[UploadedChunk],
+    ) -> Result<(), anyhow::Error> {
+        self.hot
+            .complete_chunked_upload(name, upload_id, chunks)
+            .await
+    }
+
+    async fn abort_chunked_upload(
+        &self,
+        name: &str,
+        upload_id: &str,
+        chunks: &[UploadedChunk],
+    ) -> Result<(), anyhow::Error> {
+        self.hot.abort_chunked_upload(name, upload_id, chunks).await
+    }
+}
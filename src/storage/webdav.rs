@@ -0,0 +1,298 @@
+use std::sync::Mutex;
+
+use super::{ListedObject, ObjectRange, ObjectStream, StorageProvider, SyncStream};
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use futures::{future, StreamExt, TryStreamExt};
+use percent_encoding::percent_decode_str;
+use regex::Regex;
+use reqwest::{header, Client, Method, RequestBuilder, StatusCode, Url};
+
+/// Properties requested for every member of a collection when listing
+const PROPFIND_BODY: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<d:propfind xmlns:d="DAV:">
+  <d:prop><d:resourcetype/><d:getcontentlength/><d:getlastmodified/></d:prop>
+</d:propfind>"#;
+
+// Servers pick their own namespace prefixes, so elements are matched by local name
+lazy_static! {
+    static ref RESPONSE_REGEX: Regex =
+        Regex::new(r"(?s)<(?:[\w-]+:)?response\b.*?</(?:[\w-]+:)?response>").unwrap();
+    static ref HREF_REGEX: Regex = Regex::new(r"<(?:[\w-]+:)?href>\s*([^<]*?)\s*</").unwrap();
+    static ref COLLECTION_REGEX: Regex = Regex::new(r"<(?:[\w-]+:)?collection\s*/?>").unwrap();
+    static ref LENGTH_REGEX: Regex =
+        Regex::new(r"<(?:[\w-]+:)?getcontentlength>\s*(\d+)\s*</").unwrap();
+    static ref MODIFIED_REGEX: Regex =
+        Regex::new(r"<(?:[\w-]+:)?getlastmodified>\s*([^<]*?)\s*</").unwrap();
+}
+
+/// Storage provider writing objects to a WebDAV server, such as Nextcloud or Apache mod_dav
+pub struct WebDavProvider {
+    client: Client,
+
+    // Collection objects are stored in, always ends with a slash
+    url: String,
+    username: Option<String>,
+    password: Option<String>,
+}
+
+impl WebDavProvider {
+    pub fn new(url: &str, username: Option<String>, password: Option<String>) -> Self {
+        WebDavProvider {
+            client: Client::new(),
+            url: format!("{}/", url.trim_end_matches('/')),
+            username,
+            password,
+        }
+    }
+
+    fn request(&self, method: Method, path: &str) -> RequestBuilder {
+        let request = self.client.request(
+            method,
+            format!("{}{}", self.url, path.trim_start_matches('/')),
+        );
+
+        match &self.username {
+            Some(username) => request.basic_auth(username, self.password.as_ref()),
+            None => request,
+        }
+    }
+
+    /// Create every collection above an object, PUT fails if the parent collection is missing
+    async fn create_collections(&self, name: &str) -> Result<(), anyhow::Error> {
+        let segments: Vec<&str> = name.split('/').collect();
+        let mut path = String::new();
+
+        for segment in &segments[..segments.len() - 1] {
+            path.push_str(segment);
+            path.push('/');
+
+            let method = Method::from_bytes(b"MKCOL").expect("MKCOL is a valid method");
+            let status = self.request(method, &path).send().await?.status();
+
+            // 405 is returned for collections which already exist
+            if !status.is_success() && status != StatusCode::METHOD_NOT_ALLOWED {
+                return Err(anyhow::anyhow!(
+                    "Unable to create collection {}: {}",
+                    path,
+                    status
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Members of a collection, names are relative to the root collection
+    async fn list_collection(
+        &self,
+        path: &str,
+    ) -> Result<Vec<(String, Option<ListedObject>)>, anyhow::Error> {
+        let method = Method::from_bytes(b"PROPFIND").expect("PROPFIND is a valid method");
+        let response = self
+            .request(method, path)
+            .header("Depth", "1")
+            .header(header::CONTENT_TYPE, "application/xml")
+            .body(PROPFIND_BODY)
+            .send()
+            .await?;
+
+        // Nothing was ever written below the root collection
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(Vec::new());
+        }
+
+        if !response.status().is_success() {
+            return Err(anyhow::anyhow!(
+                "Unable to list {}: {}",
+                path,
+                response.status()
+            ));
+        }
+
+        let root = Url::parse(&self.url)?;
+        let body = response.text().await?;
+        let mut members = Vec::new();
+
+        for member in RESPONSE_REGEX.find_iter(&body).map(|m| m.as_str()) {
+            let href = match HREF_REGEX.captures(member) {
+                Some(v) => v[1].replace("&amp;", "&"),
+                None => continue,
+            };
+
+            // Hrefs are either absolute URLs or absolute paths on the same server
+            let href = root.join(&href)?;
+            let name = match href.path().strip_prefix(root.path()) {
+                Some(v) => percent_decode_str(v).decode_utf8_lossy().to_string(),
+                None => continue,
+            };
+
+            // The collection itself is listed as well
+            if name.trim_end_matches('/') == path.trim_end_matches('/') {
+                continue;
+            }
+
+            if COLLECTION_REGEX.is_match(member) {
+                members.push((format!("{}/", name.trim_end_matches('/')), None));
+                continue;
+            }
+
+            let object = ListedObject {
+                name: name.to_owned(),
+                size: LENGTH_REGEX
+                    .captures(member)
+                    .and_then(|v| v[1].parse().ok())
+                    .unwrap_or(0),
+                modified: MODIFIED_REGEX
+                    .captures(member)
+                    .and_then(|v| DateTime::parse_from_rfc2822(&v[1]).ok())
+                    .map(|modified| modified.with_timezone(&Utc)),
+            };
+
+            members.push((name, Some(object)));
+        }
+
+        Ok(members)
+    }
+}
+
+#[async_trait]
+impl StorageProvider for WebDavProvider {
+    async fn put_object(&self, name: &str, data: ObjectStream) -> Result<(), anyhow::Error> {
+        self.create_collections(name).await?;
+
+        let result = self
+            .request(Method::PUT, name)
+            .body(reqwest::Body::wrap_stream(SyncStream(Mutex::new(data))))
+            .send()
+            .await
+            .map_err(anyhow::Error::from)
+            .and_then(|response| match response.status().is_success() {
+                true => Ok(()),
+                false => Err(anyhow::anyhow!(
+                    "Unable to put {}: {}",
+                    name,
+                    response.status()
+                )),
+            });
+
+        // Servers may keep what was received before the stream failed
+        if result.is_err() {
+            let _ = self.delete_object(name).await;
+        }
+
+        result
+    }
+
+    async fn delete_object(&self, name: &str) -> Result<(), anyhow::Error> {
+        let status = self.request(Method::DELETE, name).send().await?.status();
+
+        if !status.is_success() {
+            return Err(anyhow::anyhow!("Unable to delete {}: {}", name, status));
+        }
+
+        Ok(())
+    }
+
+    async fn get_object(
+        &self,
+        path: &str,
+        range: Option<ObjectRange>,
+    ) -> Result<ObjectStream, anyhow::Error> {
+        let mut request = self.request(Method::GET, path);
+        if let Some(range) = range {
+            request = request.header(header::RANGE, range.to_header());
+        }
+
+        let response = request.send().await?;
+        let status = response.status();
+
+        if !status.is_success() {
+            return Err(anyhow::anyhow!("Unable to get {}: {}", path, status));
+        }
+
+        let stream = response.bytes_stream().map_err(anyhow::Error::from);
+
+        // Servers without range support send the whole object, the range is cut out here instead
+        match range {
+            Some(range) if status != StatusCode::PARTIAL_CONTENT => {
+                let remaining = range
+                    .end
+                    .map_or(u64::MAX, |end| (end + 1).saturating_sub(range.start));
+
+                Ok(Box::pin(
+                    stream
+                        .scan((range.start, remaining), |(skip, remaining), chunk| {
+                            let mut chunk = match chunk {
+                                Ok(v) => v,
+                                Err(err) => return future::ready(Some(Err(err))),
+                            };
+
+                            if *remaining == 0 {
+                                return future::ready(None);
+                            }
+
+                            let skipped = (*skip).min(chunk.len() as u64);
+                            *skip -= skipped;
+                            let _ = chunk.split_to(skipped as usize);
+
+                            chunk.truncate((*remaining).min(chunk.len() as u64) as usize);
+                            *remaining -= chunk.len() as u64;
+
+                            future::ready(Some(Ok(chunk)))
+                        })
+                        .try_filter(|chunk| future::ready(!chunk.is_empty())),
+                ))
+            }
+            _ => Ok(Box::pin(stream)),
+        }
+    }
+
+    async fn rename_object(&self, from: &str, to: &str) -> Result<(), anyhow::Error> {
+        self.create_collections(to).await?;
+
+        let method = Method::from_bytes(b"MOVE").expect("MOVE is a valid method");
+        let destination = Url::parse(&self.url)?.join(to.trim_start_matches('/'))?;
+        let status = self
+            .request(method, from)
+            .header("Destination", destination.as_str())
+            .header("Overwrite", "T")
+            .send()
+            .await?
+            .status();
+
+        if !status.is_success() {
+            return Err(anyhow::anyhow!(
+                "Unable to move {} to {}: {}",
+                from,
+                to,
+                status
+            ));
+        }
+
+        Ok(())
+    }
+
+    async fn list_objects(&self, prefix: &str) -> Result<Vec<ListedObject>, anyhow::Error> {
+        let mut objects = Vec::new();
+        let mut collections = vec![String::new()];
+
+        // Depth infinity is disabled on most servers, collections are walked one level at a time
+        while let Some(collection) = collections.pop() {
+            for (name, object) in self.list_collection(&collection).await? {
+                match object {
+                    Some(object) if name.starts_with(prefix) => objects.push(object),
+                    Some(_) => {}
+                    // Collections which can't contain a matching object are skipped
+                    None if name.starts_with(prefix) || prefix.starts_with(&name) => {
+                        collections.push(name)
+                    }
+                    None => {}
+                }
+            }
+        }
+
+        Ok(objects)
+    }
+}
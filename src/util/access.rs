@@ -0,0 +1,178 @@
+use std::{path::PathBuf, time::Duration};
+
+use actix_web::{http::StatusCode, HttpRequest};
+use argon2::{Argon2, PasswordHash, PasswordVerifier};
+use chrono::Utc;
+
+use crate::{
+    database::entity::{file_shares, files, sea_orm_active_enums::StorageTier},
+    models::{FileData, FileVisibility, MessageResponse},
+    state::State,
+    util::signature::{object_token, share_signature},
+};
+
+/// Header carrying the password of a password protected file, URLs with it would end up in logs
+const PASSWORD_HEADER: &str = "X-File-Password";
+
+/// How long signed URLs for files which are not directly readable stay valid
+pub const SIGNED_URL_LIFETIME: Duration = Duration::from_secs(60 * 60);
+
+/// URL which can read an object until it expires, even if it is not public.
+/// Presigned by the storage provider if supported, otherwise a token checked when serving.
+pub fn signed_object_url(state: &State, name: &str) -> String {
+    if let Some(url) = state.storage.get_presigned_url(name, SIGNED_URL_LIFETIME) {
+        return url;
+    }
+
+    let mut url = PathBuf::from(&state.storage_url);
+    url.push(name);
+
+    format!(
+        "{}?token={}",
+        url.as_path().display().to_string().replace("\\", "/"),
+        object_token(
+            &state.jwt_key,
+            name,
+            Utc::now().timestamp() + SIGNED_URL_LIFETIME.as_secs() as i64
+        )
+    )
+}
+
+/// URL of a route serving an object through the application, with a token reading `name` until it expires
+pub fn signed_route_url(state: &State, url: String, name: &str) -> String {
+    format!(
+        "{}?token={}",
+        url,
+        object_token(
+            &state.jwt_key,
+            name,
+            Utc::now().timestamp() + SIGNED_URL_LIFETIME.as_secs() as i64
+        )
+    )
+}
+
+/// Public URL of a share link, the parameters are signed so they can't be altered
+pub fn share_url(state: &State, share: &file_shares::Model) -> String {
+    signed_share_url(state, share, "")
+}
+
+/// URL of the page decrypting a file encrypted by the client.
+/// The key is appended by the client as the URL fragment so it is never sent to the server.
+pub fn share_page_url(state: &State, share: &file_shares::Model) -> String {
+    signed_share_url(state, share, "/page")
+}
+
+fn signed_share_url(state: &State, share: &file_shares::Model, route: &str) -> String {
+    let expires = share.expires.timestamp();
+
+    let mut url = format!(
+        "{}api/share/{}{}?file={}&expires={}",
+        state.base_url, share.id, route, share.file_id, expires
+    );
+
+    if let Some(max_downloads) = share.max_downloads {
+        url.push_str(&format!("&downloads={}", max_downloads));
+    }
+
+    url.push_str(&format!(
+        "&signature={}",
+        share_signature(
+            &state.jwt_key,
+            &share.id,
+            &share.file_id,
+            expires,
+            share.max_downloads
+        )
+    ));
+
+    url
+}
+
+/// URL of the download route, for files whose contents can't be read from the storage URL
+fn download_url(state: &State, file_data: &FileData) -> String {
+    format!("{}api/file/{}/download", state.base_url, file_data.id)
+}
+
+/// Contents can't be read from the storage URL, the application streams them.
+/// This is the case for objects encrypted at rest or in cold storage, and for files sharing another file's object.
+pub fn served_by_application(state: &State, file_data: &FileData) -> bool {
+    state.encrypted_at_rest || file_data.tier == StorageTier::Cold || !file_data.owns_object()
+}
+
+/// Set the URL anyone allowed to read a file can use
+pub fn set_public_url(state: &State, file_data: &mut FileData) {
+    if served_by_application(state, file_data) {
+        file_data.url = Some(download_url(state, file_data));
+    } else {
+        file_data.set_url(PathBuf::from(&state.storage_url));
+    }
+}
+
+/// File data for the uploader with URLs they can read the file from.
+/// Thumbnails are filled in separately with `load_thumbnails`.
+pub fn owner_file_data(state: &State, file: files::Model) -> FileData {
+    let mut file_data = FileData::from(file);
+    set_public_url(state, &mut file_data);
+
+    if !file_data.visibility.is_direct() {
+        file_data.url = Some(if served_by_application(state, &file_data) {
+            signed_route_url(state, download_url(state, &file_data), &file_data.id)
+        } else {
+            signed_object_url(state, &file_data.object)
+        });
+    }
+
+    file_data
+}
+
+/// Password sent with a request for a password protected file
+pub fn request_password(req: &HttpRequest) -> Option<&str> {
+    req.headers()
+        .get(PASSWORD_HEADER)
+        .and_then(|value| value.to_str().ok())
+}
+
+/// Check if a file may be downloaded by a user or with a password
+pub fn check_access(
+    file: &files::Model,
+    user_id: Option<&str>,
+    password: Option<&str>,
+) -> Result<(), MessageResponse> {
+    if user_id == Some(file.uploader.as_str()) {
+        return Ok(());
+    }
+
+    match FileVisibility::from(file.visibility.clone()) {
+        FileVisibility::Public | FileVisibility::Unlisted => Ok(()),
+        // Private files should not be known to exist
+        FileVisibility::Private => Err(MessageResponse::new(
+            StatusCode::NOT_FOUND,
+            "That file was not found",
+        )),
+        FileVisibility::Password => {
+            let password = password.ok_or(MessageResponse::new(
+                StatusCode::UNAUTHORIZED,
+                "This file is protected by a password",
+            ))?;
+
+            let valid = match &file.password {
+                Some(hash) => match PasswordHash::new(hash) {
+                    Ok(hash) => Argon2::default()
+                        .verify_password(password.as_bytes(), &hash)
+                        .is_ok(),
+                    Err(_) => false,
+                },
+                None => false,
+            };
+
+            if valid {
+                Ok(())
+            } else {
+                Err(MessageResponse::new(
+                    StatusCode::FORBIDDEN,
+                    "Incorrect password",
+                ))
+            }
+        }
+    }
+}
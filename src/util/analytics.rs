@@ -0,0 +1,241 @@
+use actix_http::Uri;
+use actix_web::{http::header, HttpRequest};
+use sea_orm::{ConnectionTrait, DatabaseConnection, DbBackend, DbErr, Statement, Value};
+
+use crate::models::{AnalyticsData, DailyViews, UserAgentClass, ViewCount};
+
+/// Most referrers returned in analytics
+const MAX_REFERRERS: i64 = 20;
+
+/// Classify a client from its `User-Agent` header
+pub fn user_agent_class(user_agent: Option<&str>) -> UserAgentClass {
+    let user_agent = match user_agent {
+        Some(v) => v.to_lowercase(),
+        None => return UserAgentClass::Other,
+    };
+
+    // Link previews in chat apps identify themselves as bots or by their name
+    if ["bot", "crawler", "spider", "preview", "facebookexternalhit"]
+        .iter()
+        .any(|pattern| user_agent.contains(pattern))
+    {
+        UserAgentClass::Bot
+    } else if [
+        "curl/",
+        "wget/",
+        "httpie/",
+        "python-requests/",
+        "python-urllib/",
+        "go-http-client/",
+        "okhttp/",
+        "axios/",
+        "node-fetch/",
+    ]
+    .iter()
+    .any(|pattern| user_agent.starts_with(pattern))
+    {
+        UserAgentClass::Cli
+    } else if user_agent.contains("mobile")
+        || user_agent.contains("android")
+        || user_agent.contains("iphone")
+        || user_agent.contains("ipad")
+    {
+        UserAgentClass::Mobile
+    } else if user_agent.starts_with("mozilla/") {
+        UserAgentClass::Desktop
+    } else {
+        UserAgentClass::Other
+    }
+}
+
+/// Host of the page which linked to the file, the rest of the referrer is not stored
+fn referrer_host(req: &HttpRequest) -> Option<String> {
+    let referrer = req.headers().get(header::REFERER)?.to_str().ok()?;
+    let host = referrer.parse::<Uri>().ok()?.host()?.to_lowercase();
+
+    if host.len() > 255 {
+        return None;
+    }
+
+    Some(host)
+}
+
+/// Record that a file was served and add it to the counters of the day.
+/// Failing to record should never fail serving the file, so errors are only logged.
+pub async fn record_view(
+    database: &DatabaseConnection,
+    file_id: &str,
+    req: &HttpRequest,
+    bytes: u64,
+) {
+    let user_agent = user_agent_class(
+        req.headers()
+            .get(header::USER_AGENT)
+            .and_then(|user_agent| user_agent.to_str().ok()),
+    );
+
+    let result = database
+        .execute(Statement::from_sql_and_values(
+            DbBackend::Postgres,
+            r#"WITH view AS (
+                   INSERT INTO file_views (file_id, referrer, user_agent, bytes)
+                   VALUES ($1, $2, $3, $4)
+               )
+               INSERT INTO file_daily_views (file_id, day, views, bytes)
+               VALUES ($1, current_date, 1, $4)
+               ON CONFLICT (file_id, day) DO UPDATE
+               SET views = file_daily_views.views + 1, bytes = file_daily_views.bytes + $4"#,
+            vec![
+                file_id.into(),
+                referrer_host(req).into(),
+                user_agent.as_str().into(),
+                (bytes as i64).into(),
+            ],
+        ))
+        .await;
+
+    if let Err(err) = result {
+        log::error!("Error recording view of {}: {}", file_id, err);
+    }
+}
+
+/// Delete single views older than the retention period, daily counters are kept
+pub async fn prune_views(database: &DatabaseConnection, retention_days: i32) -> Result<u64, DbErr> {
+    Ok(database
+        .execute(Statement::from_sql_and_values(
+            DbBackend::Postgres,
+            r#"DELETE FROM file_views WHERE viewed < now() - make_interval(days => $1)"#,
+            vec![retention_days.into()],
+        ))
+        .await?
+        .rows_affected())
+}
+
+/// Which files analytics are collected over
+pub enum AnalyticsScope<'a> {
+    File(&'a str),
+    User(&'a str),
+}
+
+impl AnalyticsScope<'_> {
+    /// Condition on a table with a `file_id` column and the value it is compared to as `$1`
+    fn condition(&self, table: &str) -> (String, Value) {
+        match self {
+            AnalyticsScope::File(file_id) => (format!("{}.file_id = $1", table), (*file_id).into()),
+            AnalyticsScope::User(user_id) => (
+                format!(
+                    "{}.file_id IN (SELECT id FROM files WHERE uploader = $1)",
+                    table
+                ),
+                (*user_id).into(),
+            ),
+        }
+    }
+}
+
+/// Totals, a daily time series and breakdowns of the last `days` days
+pub async fn get_analytics(
+    database: &DatabaseConnection,
+    scope: AnalyticsScope<'_>,
+    days: i32,
+) -> Result<AnalyticsData, DbErr> {
+    let (daily_condition, value) = scope.condition("file_daily_views");
+    let (view_condition, _) = scope.condition("file_views");
+
+    let totals = database
+        .query_one(Statement::from_sql_and_values(
+            DbBackend::Postgres,
+            &format!(
+                r#"SELECT COALESCE(SUM(views), 0)::BIGINT AS views, COALESCE(SUM(bytes), 0)::BIGINT AS bytes
+                   FROM file_daily_views WHERE {}"#,
+                daily_condition
+            ),
+            vec![value.clone()],
+        ))
+        .await?;
+
+    let (total_views, total_bytes) = match totals {
+        Some(row) => (row.try_get("", "views")?, row.try_get("", "bytes")?),
+        None => (0, 0),
+    };
+
+    // Days without views are filled in with zeros
+    let series = database
+        .query_all(Statement::from_sql_and_values(
+            DbBackend::Postgres,
+            &format!(
+                r#"SELECT days.day::DATE AS date,
+                          COALESCE(SUM(file_daily_views.views), 0)::BIGINT AS views,
+                          COALESCE(SUM(file_daily_views.bytes), 0)::BIGINT AS bytes
+                   FROM generate_series(current_date - ($2::INTEGER - 1), current_date, INTERVAL '1 day') AS days(day)
+                   LEFT JOIN file_daily_views ON file_daily_views.day = days.day::DATE AND {}
+                   GROUP BY days.day ORDER BY days.day"#,
+                daily_condition
+            ),
+            vec![value.clone(), days.into()],
+        ))
+        .await?
+        .iter()
+        .map(|row| {
+            Ok(DailyViews {
+                date: row.try_get("", "date")?,
+                views: row.try_get("", "views")?,
+                bytes: row.try_get("", "bytes")?,
+            })
+        })
+        .collect::<Result<_, DbErr>>()?;
+
+    let referrers = view_breakdown(
+        database,
+        "referrer",
+        &view_condition,
+        value.clone(),
+        days,
+        Some(MAX_REFERRERS),
+    )
+    .await?;
+    let user_agents =
+        view_breakdown(database, "user_agent", &view_condition, value, days, None).await?;
+
+    Ok(AnalyticsData {
+        total_views,
+        total_bytes,
+        series,
+        referrers,
+        user_agents,
+    })
+}
+
+/// Views of the last `days` days grouped by a column of `file_views`, most viewed first
+async fn view_breakdown(
+    database: &DatabaseConnection,
+    column: &str,
+    condition: &str,
+    value: Value,
+    days: i32,
+    limit: Option<i64>,
+) -> Result<Vec<ViewCount>, DbErr> {
+    database
+        .query_all(Statement::from_sql_and_values(
+            DbBackend::Postgres,
+            &format!(
+                r#"SELECT {column} AS value, COUNT(*) AS views FROM file_views
+                   WHERE {condition} AND {column} IS NOT NULL
+                     AND viewed >= current_date - ($2::INTEGER - 1)
+                   GROUP BY {column} ORDER BY views DESC, value{limit}"#,
+                column = column,
+                condition = condition,
+                limit = limit.map_or(String::new(), |limit| format!(" LIMIT {}", limit))
+            ),
+            vec![value, days.into()],
+        ))
+        .await?
+        .iter()
+        .map(|row| {
+            Ok(ViewCount {
+                value: row.try_get("", "value")?,
+                views: row.try_get("", "views")?,
+            })
+        })
+        .collect()
+}
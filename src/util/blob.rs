@@ -0,0 +1,141 @@
+use chrono::{Duration, Utc};
+use sea_orm::{
+    ActiveModelTrait, ColumnTrait, ConnectionTrait, DatabaseConnection, DbBackend, DbErr,
+    EntityTrait, QueryFilter, Set, Statement,
+};
+
+use crate::{
+    database::entity::{blobs, sea_orm_active_enums::Visibility},
+    state::State,
+    storage::PENDING_PREFIX,
+};
+
+/// Find a blob with these contents which is still referenced
+pub async fn find_blob(
+    database: &DatabaseConnection,
+    hash: &str,
+) -> Result<Option<blobs::Model>, DbErr> {
+    blobs::Entity::find()
+        .filter(blobs::Column::Hash.eq(hash))
+        .filter(blobs::Column::Refs.gt(0))
+        .one(database)
+        .await
+}
+
+/// Record an object as a blob, it is referenced once a file points at it
+pub async fn create_blob<C: ConnectionTrait>(
+    database: &C,
+    name: &str,
+    hash: &str,
+    size: i64,
+) -> Result<blobs::Model, DbErr> {
+    blobs::ActiveModel {
+        name: Set(name.to_owned()),
+        hash: Set(hash.to_owned()),
+        size: Set(size),
+        ..Default::default()
+    }
+    .insert(database)
+    .await
+}
+
+/// Can a blob be read directly from the storage URL.
+/// Only the file it was uploaded as decides this, files sharing it later are served by the application.
+pub async fn is_object_public(database: &DatabaseConnection, name: &str) -> Result<bool, DbErr> {
+    let row = database
+        .query_one(Statement::from_sql_and_values(
+            DbBackend::Postgres,
+            r#"SELECT EXISTS(
+                   SELECT 1 FROM files
+                   WHERE object = $1 AND name = $1 AND visibility IN ($2, $3)
+               ) AS direct"#,
+            vec![
+                name.into(),
+                Visibility::Public.into(),
+                Visibility::Unlisted.into(),
+            ],
+        ))
+        .await?;
+
+    match row {
+        Some(row) => row.try_get("", "direct"),
+        None => Ok(false),
+    }
+}
+
+/// Delete a blob and its object if no file points at it anymore.
+/// Returns false if the blob is still referenced.
+pub async fn release_blob(state: &State, name: &str) -> Result<bool, DbErr> {
+    let released = state
+        .database
+        .execute(Statement::from_sql_and_values(
+            DbBackend::Postgres,
+            r#"DELETE FROM blobs WHERE name = $1 AND refs <= 0"#,
+            vec![name.into()],
+        ))
+        .await?
+        .rows_affected()
+        > 0;
+
+    if released {
+        if let Err(err) = state.storage.delete_object(name).await {
+            log::warn!("Unable to delete released blob {}: {}", name, err);
+        }
+    }
+
+    Ok(released)
+}
+
+/// Delete blobs which lost their references without being released, such as when a user is deleted.
+/// Recently created blobs are kept since the file pointing at them may not be inserted yet.
+pub async fn collect_blobs(state: &State) -> Result<usize, DbErr> {
+    let names: Vec<String> = state
+        .database
+        .query_all(Statement::from_sql(
+            DbBackend::Postgres,
+            r#"DELETE FROM blobs WHERE refs <= 0 AND created < now() - INTERVAL '1 hour'
+               RETURNING name"#,
+        ))
+        .await?
+        .iter()
+        .map(|row| row.try_get("", "name"))
+        .collect::<Result<_, DbErr>>()?;
+
+    for name in &names {
+        let _ = state.storage.delete_object(name).await;
+    }
+
+    Ok(names.len())
+}
+
+/// Finish or discard uploads which were interrupted between writing their object and committing their file.
+/// Uploads are only touched an hour after they were written so ones which are still being committed are left alone.
+pub async fn recover_pending_uploads(state: &State) -> Result<usize, anyhow::Error> {
+    let cutoff = Utc::now() - Duration::hours(1);
+    let mut recovered = 0;
+
+    for pending in state.storage.list_objects(PENDING_PREFIX).await? {
+        if pending.modified.map_or(true, |modified| modified > cutoff) {
+            continue;
+        }
+
+        let object = &pending.name[PENDING_PREFIX.len()..];
+        let committed = blobs::Entity::find_by_id(object.to_owned())
+            .one(&state.database)
+            .await?
+            .is_some();
+
+        if committed {
+            // The rows were committed while moving the object, its copy may not have been completed
+            state.storage.rename_object(&pending.name, object).await?;
+        } else {
+            // Nothing was committed so no file points at either name
+            let _ = state.storage.delete_object(&pending.name).await;
+            let _ = state.storage.delete_object(object).await;
+        }
+
+        recovered += 1;
+    }
+
+    Ok(recovered)
+}
@@ -0,0 +1,57 @@
+use async_trait::async_trait;
+use sea_orm::{ConnectionTrait, DatabaseConnection, DbBackend, EntityTrait, Statement};
+
+use crate::{
+    database::entity::object_keys,
+    storage::encrypted::{ObjectKey, ObjectKeys},
+};
+
+/// Keys of encrypted objects stored in the database under the name of their object
+pub struct DatabaseKeys {
+    database: DatabaseConnection,
+}
+
+impl DatabaseKeys {
+    pub fn new(database: DatabaseConnection) -> Self {
+        DatabaseKeys { database }
+    }
+}
+
+#[async_trait]
+impl ObjectKeys for DatabaseKeys {
+    async fn get_key(&self, name: &str) -> Result<Option<ObjectKey>, anyhow::Error> {
+        Ok(object_keys::Entity::find_by_id(name.to_owned())
+            .one(&self.database)
+            .await?
+            .map(|key| ObjectKey {
+                wrapped_key: key.wrapped_key,
+                nonce: key.nonce,
+            }))
+    }
+
+    async fn set_key(&self, name: &str, key: &ObjectKey) -> Result<(), anyhow::Error> {
+        self.database
+            .execute(Statement::from_sql_and_values(
+                DbBackend::Postgres,
+                r#"INSERT INTO object_keys (name, wrapped_key, nonce) VALUES ($1, $2, $3)
+                   ON CONFLICT (name) DO UPDATE
+                   SET wrapped_key = EXCLUDED.wrapped_key, nonce = EXCLUDED.nonce"#,
+                vec![
+                    name.into(),
+                    key.wrapped_key.to_owned().into(),
+                    key.nonce.to_owned().into(),
+                ],
+            ))
+            .await?;
+
+        Ok(())
+    }
+
+    async fn delete_key(&self, name: &str) -> Result<(), anyhow::Error> {
+        object_keys::Entity::delete_by_id(name.to_owned())
+            .exec(&self.database)
+            .await?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,128 @@
+use chrono::{Duration, Utc};
+use sea_orm::{
+    sea_query::SimpleExpr, ColumnTrait, ConnectionTrait, DatabaseConnection, DbBackend, DbErr,
+    EntityTrait, ModelTrait, QueryFilter, Statement,
+};
+
+use crate::{
+    database::entity::{files, upload_chunks, upload_sessions},
+    models::FileVisibility,
+    state::State,
+    storage::{pending_name, UploadedChunk},
+    util::{blob::release_blob, thumbnail::thumbnail_names, transform::transform_names},
+};
+
+/// Hours an upload session may take to be finalized before it is abandoned
+const UPLOAD_SESSION_LIFETIME: i64 = 24;
+
+/// SQL condition matching files which expired by time or download count
+const EXPIRED_CONDITION: &str =
+    "(COALESCE(expires <= now(), false) OR COALESCE(downloads >= max_downloads, false))";
+
+pub fn expired() -> SimpleExpr {
+    SimpleExpr::Custom(EXPIRED_CONDITION.to_string())
+}
+
+pub fn not_expired() -> SimpleExpr {
+    SimpleExpr::Custom(format!("NOT {}", EXPIRED_CONDITION))
+}
+
+/// Expired files are unavailable even before the purge worker deleted them
+pub fn is_expired(file: &files::Model) -> bool {
+    file.expires.map_or(false, |expires| expires <= Utc::now())
+        || file
+            .max_downloads
+            .map_or(false, |max_downloads| file.downloads >= max_downloads)
+}
+
+/// Count a download of a file, which also keeps it in hot storage
+pub async fn count_download(database: &DatabaseConnection, file_id: &str) -> Result<(), DbErr> {
+    // Incremented in the database so concurrent downloads are all counted
+    database
+        .execute(Statement::from_sql_and_values(
+            DbBackend::Postgres,
+            r#"UPDATE files SET downloads = downloads + 1, last_accessed = now() WHERE id = $1"#,
+            vec![file_id.into()],
+        ))
+        .await?;
+
+    Ok(())
+}
+
+/// Record a read of a file which doesn't count as a download, which keeps it in hot storage
+pub async fn record_access(database: &DatabaseConnection, file_id: &str) -> Result<(), DbErr> {
+    database
+        .execute(Statement::from_sql_and_values(
+            DbBackend::Postgres,
+            r#"UPDATE files SET last_accessed = now() WHERE id = $1"#,
+            vec![file_id.into()],
+        ))
+        .await?;
+
+    Ok(())
+}
+
+/// Delete a file entry along with its thumbnails and transformed images.
+/// The object is only deleted once no other file shares it.
+pub async fn purge_file(state: &State, file: files::Model) -> Result<(), DbErr> {
+    // Records of derived objects are deleted along with the entry
+    let mut derived = thumbnail_names(&state.database, &file.id, &file.name).await?;
+    derived.extend(transform_names(&state.database, &file.id).await?);
+    file.clone().delete(&state.database).await?;
+
+    for name in derived {
+        if let Err(err) = state.storage.delete_object(&name).await {
+            log::warn!("Unable to delete derived object {}: {}", name, err);
+        }
+    }
+
+    let released = release_blob(state, &file.object).await?;
+
+    // The remaining files were not uploaded as the shared object, they are served by the application
+    if !released && file.object == file.name && FileVisibility::from(file.visibility).is_direct() {
+        let _ = state.storage.set_object_public(&file.object, false).await;
+    }
+
+    Ok(())
+}
+
+/// Delete upload sessions which were not finalized in time and discard their chunks.
+/// Returns how many sessions were expired.
+pub async fn expire_upload_sessions(state: &State) -> Result<usize, DbErr> {
+    let sessions = upload_sessions::Entity::find()
+        .filter(
+            upload_sessions::Column::Created
+                .lt(Utc::now() - Duration::hours(UPLOAD_SESSION_LIFETIME)),
+        )
+        .all(&state.database)
+        .await?;
+
+    for session in &sessions {
+        let chunks: Vec<UploadedChunk> = session
+            .find_related(upload_chunks::Entity)
+            .all(&state.database)
+            .await?
+            .into_iter()
+            .map(|chunk| UploadedChunk {
+                number: chunk.number,
+                tag: chunk.tag,
+            })
+            .collect();
+
+        session.clone().delete(&state.database).await?;
+
+        if let Err(err) = state
+            .storage
+            .abort_chunked_upload(
+                &pending_name(&session.name),
+                &session.storage_upload_id,
+                &chunks,
+            )
+            .await
+        {
+            log::warn!("Unable to abort upload session {}: {}", session.id, err);
+        }
+    }
+
+    Ok(sessions.len())
+}
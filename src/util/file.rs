@@ -1,13 +1,21 @@
-use std::io::Cursor;
+use std::{ffi::OsStr, fmt::Display, future::Future, io::Cursor, path::Path};
 
-use actix_multipart::Multipart;
-use image::ImageError;
+use actix_multipart::{Field, Multipart};
+use bytes::Bytes;
+use image::{codecs::avif::AvifEncoder, ColorType, DynamicImage, ImageEncoder, ImageOutputFormat};
+use nanoid::nanoid;
+use sha2::{Digest, Sha256};
 use thiserror::Error;
 
-use futures::{AsyncWriteExt, TryStreamExt};
+use futures::{channel::mpsc, SinkExt, Stream, StreamExt, TryStreamExt};
 
-pub const IMAGE_EXTS: &'static [&'static str] =
-    &["PNG", "JPG", "JPEG", "GIF", "WEBP", "JFIF", "PJPEG", "PJP"];
+use crate::{
+    storage::{ObjectStream, StorageProvider},
+    util::thumbnail::ThumbnailFormat,
+};
+
+/// Amount of chunks which can be waiting to be written to the storage provider
+const STREAM_BUFFER: usize = 16;
 
 #[derive(Error, Debug)]
 pub enum MultipartError {
@@ -17,30 +25,98 @@ pub enum MultipartError {
     PayloadTooLarge(usize),
     #[error("there was a problem writing from the payload: `{0}`")]
     WriteError(std::io::Error),
+    #[error("there was a problem writing to the storage provider: `{0}`")]
+    StorageError(anyhow::Error),
 }
 
+/// File field of a multipart payload which has not been read yet
 pub struct File {
     pub filename: String,
-    pub bytes: Vec<u8>,
+    pub field: Field,
+}
+
+/// Object which was written to the storage provider
+pub struct StoredObject {
+    pub hash: String,
     pub size: usize,
+
+    // Image metadata was removed before it was written
+    pub metadata_removed: bool,
+}
+
+/// Generate a new unique storage name which keeps the extension of the original name
+pub fn new_file_name(original_name: &str) -> String {
+    let extension = Path::new(original_name)
+        .extension()
+        .and_then(OsStr::to_str)
+        .unwrap_or("");
+
+    // Collision not likely with NanoID
+    nanoid!(10) + "." + extension
 }
 
-pub fn get_thumbnail_image(bytes: &[u8]) -> Result<Vec<u8>, ImageError> {
+/// Quality used when encoding thumbnails in a lossy format
+const THUMBNAIL_QUALITY: u8 = 80;
+
+/// Scale an image down to fit within a `size` square and encode it
+pub fn get_thumbnail_image(
+    image: &DynamicImage,
+    size: u32,
+    format: ThumbnailFormat,
+) -> Result<Vec<u8>, anyhow::Error> {
+    // Small images are kept at their original size rather than upscaled
+    let thumbnail = if image.width() <= size && image.height() <= size {
+        image.clone()
+    } else {
+        image.thumbnail(size, size)
+    };
+
+    encode_image(&thumbnail, format, THUMBNAIL_QUALITY)
+}
+
+/// Encode an image, quality between 1 and 100 is ignored by lossless formats
+pub fn encode_image(
+    image: &DynamicImage,
+    format: ThumbnailFormat,
+    quality: u8,
+) -> Result<Vec<u8>, anyhow::Error> {
     let mut buf = Vec::new();
 
-    image::load_from_memory(&bytes)?
-        .thumbnail(500, 500)
-        .write_to(&mut Cursor::new(&mut buf), image::ImageOutputFormat::Png)?;
+    match format {
+        ThumbnailFormat::Png => {
+            image.write_to(&mut Cursor::new(&mut buf), ImageOutputFormat::Png)?;
+        }
+        ThumbnailFormat::Jpeg => {
+            // JPEG has no alpha channel
+            DynamicImage::ImageRgb8(image.to_rgb8())
+                .write_to(&mut Cursor::new(&mut buf), ImageOutputFormat::Jpeg(quality))?;
+        }
+        ThumbnailFormat::Webp => {
+            // The encoder only accepts 8 bit RGB(A)
+            let image = DynamicImage::ImageRgba8(image.to_rgba8());
+            let encoder = webp::Encoder::from_image(&image)
+                .map_err(|err| anyhow::anyhow!(err.to_string()))?;
+            buf = encoder.encode(quality as f32).to_vec();
+        }
+        ThumbnailFormat::Avif => {
+            let image = image.to_rgba8();
+            AvifEncoder::new_with_speed_quality(&mut buf, 8, quality).write_image(
+                image.as_raw(),
+                image.width(),
+                image.height(),
+                ColorType::Rgba8,
+            )?;
+        }
+    }
 
     Ok(buf)
 }
 
 pub async fn get_file_from_payload(
     payload: &mut Multipart,
-    size_limit: usize,
     field_name: &str,
 ) -> Result<File, MultipartError> {
-    while let Ok(Some(mut field)) = payload.try_next().await {
+    while let Ok(Some(field)) = payload.try_next().await {
         let disposition = field.content_disposition().clone();
         let filename_param = match disposition.get_filename() {
             Some(v) => v,
@@ -56,27 +132,147 @@ pub async fn get_file_from_payload(
             continue;
         }
 
-        let mut bytes = Vec::<u8>::new();
+        return Ok(File {
+            filename: filename_param.to_string(),
+            field,
+        });
+    }
+
+    Err(MultipartError::FieldNotFound(field_name.to_string()))
+}
+
+/// Stream a payload to the storage provider while computing its SHA-256 hash.
+///
+/// Only [`STREAM_BUFFER`] chunks are held in memory at once regardless of the payload size.
+/// If the payload errors or goes over the size limit the write is aborted.
+pub async fn store_stream<S, E>(
+    stream: S,
+    storage: &dyn StorageProvider,
+    name: &str,
+    size_limit: usize,
+) -> Result<StoredObject, MultipartError>
+where
+    S: Stream<Item = Result<Bytes, E>> + Unpin,
+    E: Display,
+{
+    let (stored, _) =
+        pipe_stream(stream, size_limit, |data| storage.put_object(name, data)).await?;
+    Ok(stored)
+}
+
+/// Read the start of a payload without consuming it, the returned stream still yields every chunk.
+/// Less than `size` bytes are returned if the payload ends or fails first.
+pub async fn peek_stream<S, E>(
+    mut stream: S,
+    size: usize,
+) -> (Vec<u8>, impl Stream<Item = Result<Bytes, E>> + Unpin)
+where
+    S: Stream<Item = Result<Bytes, E>> + Unpin,
+{
+    let mut header = Vec::new();
+    let mut chunks = Vec::new();
+
+    while header.len() < size {
+        match stream.next().await {
+            Some(Ok(chunk)) => {
+                header.extend_from_slice(&chunk);
+                chunks.push(Ok(chunk));
+            }
+            Some(Err(err)) => {
+                chunks.push(Err(err));
+                break;
+            }
+            None => break,
+        }
+    }
+
+    (header, futures::stream::iter(chunks).chain(stream))
+}
+
+/// Pipe a payload into a storage write while computing its SHA-256 hash.
+/// The write receives an [`ObjectStream`] which ends with an error if the payload failed.
+pub async fn pipe_stream<S, E, F, Fut, T>(
+    mut stream: S,
+    size_limit: usize,
+    write: F,
+) -> Result<(StoredObject, T), MultipartError>
+where
+    S: Stream<Item = Result<Bytes, E>> + Unpin,
+    E: Display,
+    F: FnOnce(ObjectStream) -> Fut,
+    Fut: Future<Output = Result<T, anyhow::Error>>,
+{
+    // Actix payloads are not Send, so chunks are passed to the provider through a channel
+    let (mut sender, receiver) = mpsc::channel::<Result<Bytes, anyhow::Error>>(STREAM_BUFFER);
+
+    let read = async move {
+        let mut hasher = Sha256::new();
         let mut size = 0;
 
-        while let Ok(Some(chunk)) = field.try_next().await {
+        while let Some(chunk) = stream.next().await {
+            let chunk = match chunk {
+                Ok(v) => v,
+                Err(err) => {
+                    let _ = sender.send(Err(anyhow::anyhow!(err.to_string()))).await;
+                    return Err(MultipartError::WriteError(std::io::Error::new(
+                        std::io::ErrorKind::Other,
+                        err.to_string(),
+                    )));
+                }
+            };
+
             size += chunk.len();
 
             if size > size_limit {
+                let _ = sender
+                    .send(Err(anyhow::anyhow!(
+                        "payload was larger than the size limit"
+                    )))
+                    .await;
                 return Err(MultipartError::PayloadTooLarge(size_limit));
             }
 
-            if let Err(err) = bytes.write(&chunk).await {
-                return Err(MultipartError::WriteError(err));
+            hasher.update(&chunk);
+
+            // The provider stopped reading, its error is returned from the write
+            if sender.send(Ok(chunk)).await.is_err() {
+                break;
             }
         }
 
-        return Ok(File {
-            filename: filename_param.to_string(),
-            bytes: bytes,
-            size: size,
-        });
+        Ok(StoredObject {
+            hash: format!("{:x}", hasher.finalize()),
+            size,
+            metadata_removed: false,
+        })
+    };
+
+    let (read_result, write_result) = futures::join!(read, write(Box::pin(receiver)));
+
+    let stored = read_result?;
+    let output = write_result.map_err(MultipartError::StorageError)?;
+
+    Ok((stored, output))
+}
+
+/// Compute the SHA-256 hash and size of an object by streaming it from the storage provider
+pub async fn hash_object(
+    storage: &dyn StorageProvider,
+    name: &str,
+) -> Result<StoredObject, anyhow::Error> {
+    let mut stream = storage.get_object(name, None).await?;
+    let mut hasher = Sha256::new();
+    let mut size = 0;
+
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk?;
+        size += chunk.len();
+        hasher.update(&chunk);
     }
 
-    Err(MultipartError::FieldNotFound(field_name.to_string()))
+    Ok(StoredObject {
+        hash: format!("{:x}", hasher.finalize()),
+        size,
+        metadata_removed: false,
+    })
 }
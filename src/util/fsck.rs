@@ -0,0 +1,171 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+
+use chrono::{Duration, Utc};
+use sea_orm::{sea_query::Expr, ColumnTrait, DbErr, EntityTrait, QueryFilter};
+
+use crate::{
+    database::entity::{blobs, file_thumbnails, file_transforms, files},
+    models::admin::fsck::{FsckParams, FsckReport},
+    state::State,
+    storage::PENDING_PREFIX,
+    util::{
+        file::hash_object,
+        thumbnail::{regenerate_thumbnails, thumbnail_name},
+    },
+};
+
+/// Compare the objects on the storage provider with the files referring to them.
+/// Objects written in the last hour are never orphans, their upload may not have finished yet.
+/// Neither are objects whose providers don't report when they were written.
+pub async fn check_storage(
+    state: &State,
+    params: &FsckParams,
+) -> Result<FsckReport, anyhow::Error> {
+    // Listed before reading the database so every listed object of a finished upload has its row
+    let listed = state.storage.list_objects("").await?;
+    let existing: HashSet<&str> = listed.iter().map(|object| object.name.as_str()).collect();
+
+    let files = files::Entity::find().all(&state.database).await?;
+    let files_by_id: HashMap<&str, &files::Model> =
+        files.iter().map(|file| (file.id.as_str(), file)).collect();
+
+    let mut report = FsckReport {
+        objects: listed.len(),
+        files: files.len(),
+        ..Default::default()
+    };
+
+    // Files sharing an object are checked once
+    let mut objects: BTreeMap<&str, Vec<&files::Model>> = BTreeMap::new();
+    for file in &files {
+        objects.entry(file.object.as_str()).or_default().push(file);
+    }
+
+    for (object, object_files) in &objects {
+        let intact = if !existing.contains(object) {
+            report.missing_objects.push(object.to_string());
+            false
+        } else if params.verify_hashes {
+            match hash_object(state.storage.as_ref(), object).await {
+                Ok(stored) if stored.hash == object_files[0].hash => true,
+                _ => {
+                    report.corrupted_objects.push(object.to_string());
+                    false
+                }
+            }
+        } else {
+            true
+        };
+
+        if !intact {
+            report
+                .broken_files
+                .extend(object_files.iter().map(|file| file.id.to_owned()));
+        }
+    }
+
+    let mut referenced: HashSet<String> = objects.keys().map(|object| object.to_string()).collect();
+    let mut missing_thumbnails = BTreeSet::new();
+
+    for variant in file_thumbnails::Entity::find().all(&state.database).await? {
+        if let Some(file) = files_by_id.get(variant.file_id.as_str()) {
+            let name = thumbnail_name(&file.name, variant.size as u32, &variant.format);
+            if !existing.contains(name.as_str()) {
+                missing_thumbnails.insert(file.id.to_owned());
+            }
+
+            referenced.insert(name);
+        }
+    }
+
+    referenced.extend(
+        file_transforms::Entity::find()
+            .all(&state.database)
+            .await?
+            .into_iter()
+            .map(|transform| transform.name),
+    );
+
+    // Released blobs are deleted with their object by the purge worker
+    referenced.extend(
+        blobs::Entity::find()
+            .all(&state.database)
+            .await?
+            .into_iter()
+            .map(|blob| blob.name),
+    );
+
+    let cutoff = Utc::now() - Duration::hours(1);
+    for object in &listed {
+        // Chunks are deleted when their upload expires, interrupted uploads are recovered by the purge worker
+        if referenced.contains(&object.name)
+            || object.name.starts_with("chunks/")
+            || object.name.starts_with(PENDING_PREFIX)
+            || object.modified.map_or(true, |modified| modified > cutoff)
+        {
+            continue;
+        }
+
+        match object.name.starts_with("thumb/") {
+            true => report.orphaned_thumbnails.push(object.name.to_owned()),
+            false => report.orphaned_objects.push(object.name.to_owned()),
+        }
+    }
+
+    report.missing_thumbnails = missing_thumbnails.into_iter().collect();
+    report.orphaned_objects.sort();
+    report.orphaned_thumbnails.sort();
+
+    if params.repair {
+        repair(state, params, &report, &files_by_id).await?;
+        report.repaired = true;
+    }
+
+    Ok(report)
+}
+
+/// Delete orphans, regenerate missing thumbnails and flag files whose contents are gone
+async fn repair(
+    state: &State,
+    params: &FsckParams,
+    report: &FsckReport,
+    files_by_id: &HashMap<&str, &files::Model>,
+) -> Result<(), DbErr> {
+    for name in report
+        .orphaned_objects
+        .iter()
+        .chain(report.orphaned_thumbnails.iter())
+    {
+        if let Err(err) = state.storage.delete_object(name).await {
+            log::error!("Unable to delete orphaned object {}: {}", name, err);
+        }
+    }
+
+    for file_id in &report.missing_thumbnails {
+        if let Some(file) = files_by_id.get(file_id.as_str()) {
+            match regenerate_thumbnails(state, file).await {
+                Ok(true) => {}
+                Ok(false) => log::error!("Unable to create thumbnails for {}", file.name),
+                Err(err) => log::error!("Unable to create thumbnails for {}: {}", file.name, err),
+            }
+        }
+    }
+
+    files::Entity::update_many()
+        .col_expr(files::Column::Broken, Expr::value(true))
+        .filter(files::Column::Id.is_in(report.broken_files.to_owned()))
+        .exec(&state.database)
+        .await?;
+
+    // Corrupted contents are only noticed when hashes are verified, so only then is the flag cleared
+    if params.verify_hashes {
+        files::Entity::update_many()
+            .col_expr(files::Column::Broken, Expr::value(false))
+            .filter(files::Column::Broken.eq(true))
+            .filter(files::Column::Id.is_not_in(report.broken_files.to_owned()))
+            .exec(&state.database)
+            .await?;
+    }
+
+    Ok(())
+}
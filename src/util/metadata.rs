@@ -0,0 +1,187 @@
+use std::{
+    env,
+    ffi::OsStr,
+    io::Cursor,
+    path::{Path, PathBuf},
+    process::Output,
+    time::Duration,
+};
+
+use actix_files::file_extension_to_mime;
+use futures::StreamExt;
+use image::{io::Reader, ImageFormat};
+use nanoid::nanoid;
+use tokio::{fs, io::AsyncWriteExt, process::Command};
+
+use crate::storage::{ObjectRange, StorageProvider};
+
+/// Bytes read from the start of an object to detect its type and dimensions
+const HEADER_SIZE: u64 = 256 * 1024;
+
+/// External tools are killed after this long, crafted files can keep them busy indefinitely
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Metadata detected from the contents of a file
+pub struct FileMetadata {
+    pub mime_type: String,
+    pub width: Option<i32>,
+    pub height: Option<i32>,
+    pub duration: Option<f64>,
+}
+
+/// Object copied to a temporary file for tools which need to seek through it.
+/// The file is removed when dropped.
+pub struct TempFile {
+    pub path: PathBuf,
+}
+
+impl TempFile {
+    pub async fn download(
+        storage: &dyn StorageProvider,
+        name: &str,
+    ) -> Result<Self, anyhow::Error> {
+        let temp_file = Self {
+            path: env::temp_dir().join(format!("backpack-{}", nanoid!())),
+        };
+
+        let mut file = fs::File::create(&temp_file.path).await?;
+        let mut stream = storage.get_object(name, None).await?;
+
+        while let Some(chunk) = stream.next().await {
+            file.write_all(&chunk?).await?;
+        }
+
+        file.flush().await?;
+        Ok(temp_file)
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// MIME type guessed from the extension, used when the contents are not recognized
+pub fn mime_from_name(name: &str) -> String {
+    let extension = Path::new(name)
+        .extension()
+        .and_then(OsStr::to_str)
+        .unwrap_or("");
+
+    file_extension_to_mime(extension).essence_str().to_string()
+}
+
+/// Can a thumbnail be generated by decoding the file as an image
+pub fn is_thumbnailable(mime_type: &str) -> bool {
+    ImageFormat::from_mime_type(mime_type).is_some()
+}
+
+/// Detect the type, dimensions and duration of an object.
+/// The type falls back to the extension of the file name, which may differ from the object's own.
+/// Detection never fails, anything which can't be detected is left out.
+pub async fn detect_metadata(
+    storage: &dyn StorageProvider,
+    name: &str,
+    file_name: &str,
+) -> FileMetadata {
+    let header = read_header(storage, name, HEADER_SIZE)
+        .await
+        .unwrap_or_default();
+
+    let mime_type = match infer::get(&header) {
+        Some(kind) => kind.mime_type().to_string(),
+        None => mime_from_name(file_name),
+    };
+
+    let (width, height) = match ImageFormat::from_mime_type(&mime_type) {
+        Some(format) => match Reader::with_format(Cursor::new(&header), format).into_dimensions() {
+            Ok((width, height)) => (Some(width as i32), Some(height as i32)),
+            Err(_) => (None, None),
+        },
+        None => (None, None),
+    };
+
+    let duration = if mime_type.starts_with("video/") || mime_type.starts_with("audio/") {
+        probe_duration(storage, name).await
+    } else {
+        None
+    };
+
+    FileMetadata {
+        mime_type,
+        width,
+        height,
+        duration,
+    }
+}
+
+/// Read the first `size` bytes of an object, or all of it if it is smaller
+pub async fn read_header(
+    storage: &dyn StorageProvider,
+    name: &str,
+    size: u64,
+) -> Result<Vec<u8>, anyhow::Error> {
+    let mut stream = storage
+        .get_object(
+            name,
+            Some(ObjectRange {
+                start: 0,
+                end: Some(size - 1),
+            }),
+        )
+        .await?;
+
+    let mut header = Vec::new();
+    while let Some(chunk) = stream.next().await {
+        header.extend_from_slice(&chunk?);
+    }
+
+    Ok(header)
+}
+
+/// Check if an external tool can be run
+pub async fn command_available(command: &str) -> bool {
+    run_command(Command::new(command).arg("-version"))
+        .await
+        .is_some()
+}
+
+/// Run an external tool and collect its output, `None` if it could not be run or timed out.
+/// The process is killed once it is no longer awaited.
+pub async fn run_command(command: &mut Command) -> Option<Output> {
+    tokio::time::timeout(COMMAND_TIMEOUT, command.kill_on_drop(true).output())
+        .await
+        .ok()?
+        .ok()
+}
+
+/// Media duration in seconds using ffprobe, `None` if ffprobe is not installed
+async fn probe_duration(storage: &dyn StorageProvider, name: &str) -> Option<f64> {
+    // Avoid copying the object if there is nothing to run
+    if !command_available("ffprobe").await {
+        return None;
+    }
+
+    let temp_file = TempFile::download(storage, name).await.ok()?;
+
+    let output = run_command(
+        Command::new("ffprobe")
+            .args(&[
+                "-v",
+                "error",
+                "-show_entries",
+                "format=duration",
+                "-of",
+                "default=noprint_wrappers=1:nokey=1",
+            ])
+            .arg(&temp_file.path),
+    )
+    .await?;
+
+    if !output.status.success() {
+        return None;
+    }
+
+    String::from_utf8_lossy(&output.stdout).trim().parse().ok()
+}
@@ -0,0 +1,165 @@
+use std::collections::BTreeMap;
+
+use sea_orm::{ConnectionTrait, DatabaseConnection, DbBackend, DbErr, EntityTrait, Statement};
+
+use crate::{
+    database::entity::{file_thumbnails, file_transforms, files},
+    models::FileVisibility,
+    storage::StorageProvider,
+    util::{file::hash_object, thumbnail::thumbnail_name},
+};
+
+/// Object which is copied when moving to another storage provider
+pub struct MigratedObject {
+    pub name: String,
+
+    // SHA-256 of the contents as uploaded, derived objects have none
+    pub hash: Option<String>,
+
+    // Objects are written private, ones of directly readable files are made public after copying
+    pub public: bool,
+
+    // Thumbnails from before there were variants may have been deleted already
+    pub optional: bool,
+}
+
+/// Result of copying a single object
+pub enum CopyOutcome {
+    Copied,
+
+    // The destination already holds a verified copy, from a previous interrupted run
+    Skipped,
+
+    // The object does not exist on the source provider
+    Missing,
+}
+
+/// Every object referenced by a file, its thumbnails and cached transformations
+pub async fn referenced_objects(
+    database: &DatabaseConnection,
+) -> Result<Vec<MigratedObject>, DbErr> {
+    let mut thumbnails: BTreeMap<String, Vec<file_thumbnails::Model>> = BTreeMap::new();
+    for variant in file_thumbnails::Entity::find().all(database).await? {
+        thumbnails
+            .entry(variant.file_id.to_owned())
+            .or_default()
+            .push(variant);
+    }
+
+    let mut transforms: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for transform in file_transforms::Entity::find().all(database).await? {
+        transforms
+            .entry(transform.file_id)
+            .or_default()
+            .push(transform.name);
+    }
+
+    // Files sharing a blob are merged, the object is readable if the file it was uploaded as is direct
+    let mut objects: BTreeMap<String, MigratedObject> = BTreeMap::new();
+    for file in files::Entity::find().all(database).await? {
+        let public = FileVisibility::from(file.visibility.clone()).is_direct();
+        let owner = file.object == file.name;
+
+        objects
+            .entry(file.object.to_owned())
+            .and_modify(|object| object.public |= owner && public)
+            .or_insert_with(|| MigratedObject {
+                name: file.object.to_owned(),
+                hash: Some(file.hash.to_owned()),
+                public: owner && public,
+                optional: false,
+            });
+
+        let derived: Vec<(String, bool)> = match thumbnails.get(&file.id) {
+            Some(variants) => variants
+                .iter()
+                .map(|variant| {
+                    (
+                        thumbnail_name(&file.name, variant.size as u32, &variant.format),
+                        false,
+                    )
+                })
+                .collect(),
+            None => vec![(format!("thumb/{}", file.name), true)],
+        };
+
+        for (name, optional) in derived.into_iter().chain(
+            transforms
+                .remove(&file.id)
+                .unwrap_or_default()
+                .into_iter()
+                .map(|name| (name, false)),
+        ) {
+            objects.insert(
+                name.to_owned(),
+                MigratedObject {
+                    name,
+                    hash: None,
+                    public,
+                    optional,
+                },
+            );
+        }
+    }
+
+    Ok(objects.into_iter().map(|(_, object)| object).collect())
+}
+
+/// Blobs which no file points at anymore, their objects are not copied
+pub async fn orphaned_blobs(database: &DatabaseConnection) -> Result<Vec<String>, DbErr> {
+    database
+        .query_all(Statement::from_sql(
+            DbBackend::Postgres,
+            r#"SELECT name FROM blobs WHERE refs <= 0 ORDER BY name"#,
+        ))
+        .await?
+        .iter()
+        .map(|row| row.try_get("", "name"))
+        .collect()
+}
+
+/// Copy an object to the destination provider unless a verified copy is already there.
+/// `verify` reads the destination the way the application would, decrypting objects encrypted at rest.
+pub async fn copy_object(
+    source: &dyn StorageProvider,
+    destination: &dyn StorageProvider,
+    verify: &dyn StorageProvider,
+    object: &MigratedObject,
+) -> Result<CopyOutcome, anyhow::Error> {
+    // Derived objects are compared against the source since no hash is stored for them
+    let expected = match &object.hash {
+        Some(hash) => hash.to_owned(),
+        None => match hash_object(source, &object.name).await {
+            Ok(v) => v.hash,
+            Err(_) => return Ok(CopyOutcome::Missing),
+        },
+    };
+
+    let outcome = match hash_object(verify, &object.name).await {
+        Ok(copy) if copy.hash == expected => CopyOutcome::Skipped,
+        _ => {
+            let data = match source.get_object(&object.name, None).await {
+                Ok(v) => v,
+                Err(_) => return Ok(CopyOutcome::Missing),
+            };
+
+            destination.put_object(&object.name, data).await?;
+
+            let copy = hash_object(verify, &object.name).await?;
+            if copy.hash != expected {
+                // A corrupt copy must not be mistaken for a finished one when resuming
+                let _ = destination.delete_object(&object.name).await;
+                anyhow::bail!("Copy has hash {} instead of {}", copy.hash, expected);
+            }
+
+            CopyOutcome::Copied
+        }
+    };
+
+    // Also done for skipped objects in case the previous run stopped before it
+    if object.public {
+        destination.set_object_public(&object.name, true).await?;
+    }
+
+    Ok(outcome)
+}
@@ -5,8 +5,25 @@ use regex::Regex;
 
 use crate::models::MessageResponse;
 
+pub mod access;
+pub mod analytics;
 pub mod auth;
+pub mod blob;
+pub mod encryption;
+pub mod expiry;
 pub mod file;
+pub mod fsck;
+pub mod metadata;
+pub mod migrate;
+pub mod quota;
+pub mod replicas;
+pub mod serve;
+pub mod signature;
+pub mod strip;
+pub mod tags;
+pub mod thumbnail;
+pub mod tiering;
+pub mod transform;
 pub mod user;
 
 pub const GIT_VERSION: &str = git_version!();
@@ -0,0 +1,60 @@
+use actix_web::http::StatusCode;
+use sea_orm::{ConnectionTrait, DatabaseConnection, DbBackend, DbErr, EntityTrait, Statement};
+
+use crate::{
+    database::entity::{settings, users},
+    models::MessageResponse,
+};
+
+/// Total size in bytes of every file uploaded by a user
+pub async fn get_usage(database: &DatabaseConnection, user_id: &str) -> Result<i64, DbErr> {
+    // Im not using an ORM for this query
+    let usage = database
+        .query_one(Statement::from_sql_and_values(
+            DbBackend::Postgres,
+            r#"SELECT COALESCE(CAST(SUM(size) AS BIGINT), 0) FROM files WHERE uploader = $1"#,
+            vec![user_id.into()],
+        ))
+        .await?;
+
+    Ok(match usage {
+        Some(v) => v.try_get("", "coalesce")?,
+        None => 0,
+    })
+}
+
+/// Quota of a user in bytes, falls back to the instance default.
+/// `None` if the user may upload without limit.
+pub async fn get_quota(database: &DatabaseConnection, user_id: &str) -> Result<Option<i64>, DbErr> {
+    let user = users::Entity::find_by_id(user_id.to_string())
+        .one(database)
+        .await?
+        .ok_or(DbErr::Custom(format!("user {} was not found", user_id)))?;
+
+    if user.quota.is_some() {
+        return Ok(user.quota);
+    }
+
+    Ok(settings::Entity::find_by_id(true)
+        .one(database)
+        .await?
+        .and_then(|settings| settings.default_quota))
+}
+
+/// Bytes a user can still upload, `None` if the user has no quota
+pub async fn remaining_quota(
+    database: &DatabaseConnection,
+    user_id: &str,
+) -> Result<Option<i64>, DbErr> {
+    Ok(match get_quota(database, user_id).await? {
+        Some(quota) => Some((quota - get_usage(database, user_id).await?).max(0)),
+        None => None,
+    })
+}
+
+pub fn quota_exceeded() -> MessageResponse {
+    MessageResponse::new(
+        StatusCode::INSUFFICIENT_STORAGE,
+        "This upload would exceed your storage quota",
+    )
+}
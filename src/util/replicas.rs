@@ -0,0 +1,222 @@
+use std::collections::HashMap;
+
+use async_trait::async_trait;
+use sea_orm::{
+    sea_query::Expr, ColumnTrait, ConnectionTrait, DatabaseConnection, DbBackend, DbErr,
+    EntityTrait, QueryFilter, QueryOrder, QuerySelect, Statement,
+};
+
+use crate::{
+    database::entity::{files, object_replicas, sea_orm_active_enums::ReplicaOperation},
+    models::{admin::replica::ReplicaStatusData, FileVisibility},
+    state::State,
+    storage::mirrored::{MissedOperation, ReplicaLog},
+    util::blob::is_object_public,
+};
+
+/// Missed operations resynced per run, the oldest first
+const RESYNC_BATCH: u64 = 100;
+
+impl From<MissedOperation> for ReplicaOperation {
+    fn from(operation: MissedOperation) -> Self {
+        match operation {
+            MissedOperation::Write => ReplicaOperation::Write,
+            MissedOperation::Delete => ReplicaOperation::Delete,
+        }
+    }
+}
+
+impl From<ReplicaOperation> for MissedOperation {
+    fn from(operation: ReplicaOperation) -> Self {
+        match operation {
+            ReplicaOperation::Write => MissedOperation::Write,
+            ReplicaOperation::Delete => MissedOperation::Delete,
+        }
+    }
+}
+
+/// Operations replicas missed, stored in the database until they were resynced
+pub struct ReplicaStatus {
+    database: DatabaseConnection,
+}
+
+impl ReplicaStatus {
+    pub fn new(database: DatabaseConnection) -> Self {
+        ReplicaStatus { database }
+    }
+}
+
+#[async_trait]
+impl ReplicaLog for ReplicaStatus {
+    async fn missed(
+        &self,
+        name: &str,
+        replica: &str,
+        operation: MissedOperation,
+        error: &str,
+    ) -> Result<(), anyhow::Error> {
+        self.database
+            .execute(Statement::from_sql_and_values(
+                DbBackend::Postgres,
+                r#"INSERT INTO object_replicas (object, replica, operation, error)
+                   VALUES ($1, $2, CAST($3 AS replica_operation), $4)
+                   ON CONFLICT (object, replica) DO UPDATE
+                   SET operation = EXCLUDED.operation, error = EXCLUDED.error, attempts = 0, updated = now()"#,
+                vec![
+                    name.into(),
+                    replica.into(),
+                    ReplicaOperation::from(operation).into(),
+                    error.into(),
+                ],
+            ))
+            .await?;
+
+        Ok(())
+    }
+
+    async fn forget(&self, name: &str) -> Result<(), anyhow::Error> {
+        object_replicas::Entity::delete_many()
+            .filter(object_replicas::Column::Object.eq(name))
+            .exec(&self.database)
+            .await?;
+
+        Ok(())
+    }
+}
+
+/// Every missed operation with the files pointing at its object
+pub async fn missed_operations(
+    database: &DatabaseConnection,
+) -> Result<Vec<ReplicaStatusData>, DbErr> {
+    let missed = object_replicas::Entity::find()
+        .order_by_asc(object_replicas::Column::Updated)
+        .all(database)
+        .await?;
+
+    let mut files_by_object: HashMap<String, Vec<String>> = HashMap::new();
+    for file in files::Entity::find()
+        .filter(
+            files::Column::Object.is_in(
+                missed
+                    .iter()
+                    .map(|status| status.object.to_owned())
+                    .collect::<Vec<String>>(),
+            ),
+        )
+        .all(database)
+        .await?
+    {
+        files_by_object
+            .entry(file.object)
+            .or_default()
+            .push(file.id);
+    }
+
+    Ok(missed
+        .into_iter()
+        .map(|status| {
+            let files = files_by_object
+                .get(&status.object)
+                .cloned()
+                .unwrap_or_default();
+            ReplicaStatusData::new(status, files)
+        })
+        .collect())
+}
+
+/// Repeat the oldest operations replicas missed, returns how many were resynced.
+/// Failed resyncs are tried again after every other missed operation.
+pub async fn resync_replicas(state: &State) -> Result<usize, anyhow::Error> {
+    let missed = object_replicas::Entity::find()
+        .order_by_asc(object_replicas::Column::Updated)
+        .limit(RESYNC_BATCH)
+        .all(&state.database)
+        .await?;
+
+    let mut resynced = 0;
+    for status in missed {
+        let operation = MissedOperation::from(status.operation.clone());
+        let mut result = state
+            .storage
+            .resync_object(&status.object, &status.replica, operation)
+            .await;
+
+        // Objects are written private, readable ones are made public again on every replica
+        if result.is_ok()
+            && operation == MissedOperation::Write
+            && object_public(state, &status.object).await?
+        {
+            result = state.storage.set_object_public(&status.object, true).await;
+        }
+
+        // The row is left alone if the replica missed another operation in the meantime
+        let unchanged = object_replicas::Column::Object
+            .eq(status.object.to_owned())
+            .and(object_replicas::Column::Replica.eq(status.replica.to_owned()))
+            .and(object_replicas::Column::Updated.eq(status.updated));
+
+        match result {
+            Ok(_) => {
+                object_replicas::Entity::delete_many()
+                    .filter(unchanged)
+                    .exec(&state.database)
+                    .await?;
+                resynced += 1;
+            }
+            Err(err) => {
+                log::warn!(
+                    "Unable to resync {} on replica {}: {}",
+                    status.object,
+                    status.replica,
+                    err
+                );
+
+                object_replicas::Entity::update_many()
+                    .col_expr(object_replicas::Column::Error, Expr::value(err.to_string()))
+                    .col_expr(
+                        object_replicas::Column::Attempts,
+                        Expr::col(object_replicas::Column::Attempts).add(1),
+                    )
+                    .col_expr(object_replicas::Column::Updated, Expr::cust("now()"))
+                    .filter(unchanged)
+                    .exec(&state.database)
+                    .await?;
+            }
+        }
+    }
+
+    Ok(resynced)
+}
+
+/// Can an object be read directly, like the file it belongs to.
+/// Objects which belong to no file are left as they were written.
+async fn object_public(state: &State, name: &str) -> Result<bool, DbErr> {
+    if files::Entity::find()
+        .filter(files::Column::Object.eq(name))
+        .one(&state.database)
+        .await?
+        .is_some()
+    {
+        return is_object_public(&state.database, name).await;
+    }
+
+    // Derived objects are stored under the name of their file
+    // thumb/{size}/{name}.{format}, thumb/{name} and transform/{name}/{variant}.{format}
+    let file_name = if let Some(path) = name.strip_prefix("thumb/") {
+        path.split_once('/')
+            .and_then(|(_, path)| path.rsplit_once('.'))
+            .map_or(path, |(name, _)| name)
+    } else if let Some(path) = name.strip_prefix("transform/") {
+        path.split_once('/').map_or(path, |(name, _)| name)
+    } else {
+        return Ok(true);
+    };
+
+    Ok(files::Entity::find()
+        .filter(files::Column::Name.eq(file_name))
+        .one(&state.database)
+        .await?
+        .map_or(true, |file| {
+            FileVisibility::from(file.visibility).is_direct()
+        }))
+}
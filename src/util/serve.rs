@@ -0,0 +1,377 @@
+use std::{collections::HashMap, path::Path, time::SystemTime};
+
+use actix_files::{HttpRange, NamedFile};
+use actix_web::{
+    http::{
+        header::{
+            self, CacheControl, CacheDirective, ContentDisposition, DispositionParam,
+            DispositionType, EntityTag, HttpDate, IfModifiedSince, IfNoneMatch, IfRange,
+            LastModified,
+        },
+        StatusCode,
+    },
+    web, HttpMessage, HttpRequest, HttpResponse, ResponseError,
+};
+use sea_orm::{ColumnTrait, EntityTrait, QueryFilter};
+
+use crate::{
+    database::entity::{files, sea_orm_active_enums::StorageTier},
+    models::{FileVisibility, MessageResponse, Response},
+    state::State,
+    storage::{sharded::shard_name, ObjectRange},
+    util::{
+        analytics::record_view,
+        expiry::{count_download, is_expired},
+        metadata::mime_from_name,
+        signature::verify_object_token,
+        thumbnail::ThumbnailFormat,
+    },
+};
+
+/// Strong entity tag of a file, the contents never change so the hash identifies them
+pub fn file_etag(file: &files::Model) -> EntityTag {
+    EntityTag::new_strong(file.hash.to_owned())
+}
+
+/// Upload time truncated to the second precision of HTTP dates
+fn last_modified(file: &files::Model) -> HttpDate {
+    HttpDate::from(SystemTime::from(file.uploaded))
+}
+
+/// Does the client already have the current version of the file
+pub fn is_not_modified(req: &HttpRequest, file: &files::Model) -> bool {
+    // If-Modified-Since is ignored when If-None-Match is present
+    if let Some(if_none_match) = req.get_header::<IfNoneMatch>() {
+        let etag = file_etag(file);
+        return match if_none_match {
+            IfNoneMatch::Any => true,
+            IfNoneMatch::Items(items) => items.iter().any(|item| item.weak_eq(&etag)),
+        };
+    }
+
+    match req.get_header::<IfModifiedSince>() {
+        Some(IfModifiedSince(since)) => {
+            SystemTime::from(last_modified(file)) <= SystemTime::from(since)
+        }
+        None => false,
+    }
+}
+
+/// Is this a range request continuing a download which already started, such as video seeking
+fn is_continuation(req: &HttpRequest) -> bool {
+    req.headers()
+        .get(header::RANGE)
+        .and_then(|range| range.to_str().ok())
+        .map_or(false, |range| !range.trim().starts_with("bytes=0-"))
+}
+
+/// Does a request for the contents of a file use up one of its downloads.
+/// Revalidations never do, continued downloads only do when the number of downloads is limited.
+pub fn counts_as_download(req: &HttpRequest, file: &files::Model) -> bool {
+    !is_not_modified(req, file) && (file.max_downloads.is_some() || !is_continuation(req))
+}
+
+/// Files flagged by fsck because their object is missing or corrupted are not served
+pub fn check_intact(file: &files::Model) -> Result<(), MessageResponse> {
+    match file.broken {
+        true => Err(MessageResponse::new(
+            StatusCode::GONE,
+            "The contents of that file are missing or damaged",
+        )),
+        false => Ok(()),
+    }
+}
+
+/// Amount of bytes a request for an object of `size` bytes reads, the first range if there is one
+pub fn requested_length(req: &HttpRequest, size: u64) -> u64 {
+    req.headers()
+        .get(header::RANGE)
+        .and_then(|range| range.to_str().ok())
+        .and_then(|range| HttpRange::parse(range, size).ok())
+        .and_then(|ranges| ranges.first().map(|range| range.length))
+        .unwrap_or(size)
+}
+
+/// Respond with the contents of a file which the requester was allowed to read.
+/// The object is streamed from the storage provider with support for ranges and conditional requests.
+pub async fn serve_file(
+    req: &HttpRequest,
+    state: &State,
+    file: files::Model,
+    disposition: DispositionType,
+) -> Response<HttpResponse> {
+    let etag = file_etag(&file);
+    let last_modified = last_modified(&file);
+
+    if is_not_modified(req, &file) {
+        return Ok(HttpResponse::NotModified()
+            .insert_header(header::ETag(etag))
+            .insert_header(LastModified(last_modified))
+            .finish());
+    }
+
+    let size = file.size as u64;
+
+    // A range is only served if the client's copy is still the current one
+    let range_allowed = match req.get_header::<IfRange>() {
+        Some(IfRange::EntityTag(tag)) => tag.strong_eq(&etag),
+        Some(IfRange::Date(date)) => SystemTime::from(last_modified) <= SystemTime::from(date),
+        None => true,
+    };
+
+    let range = match req.headers().get(header::RANGE) {
+        Some(range) if range_allowed => {
+            match range
+                .to_str()
+                .ok()
+                .and_then(|range| HttpRange::parse(range, size).ok())
+            {
+                // Multiple ranges are served as the first one
+                Some(ranges) if !ranges.is_empty() => Some(ranges[0]),
+                _ => {
+                    return Ok(HttpResponse::build(StatusCode::RANGE_NOT_SATISFIABLE)
+                        .insert_header((header::CONTENT_RANGE, format!("bytes */{}", size)))
+                        .json(MessageResponse::new(
+                            StatusCode::RANGE_NOT_SATISFIABLE,
+                            "Requested range is not satisfiable",
+                        )))
+                }
+            }
+        }
+        _ => None,
+    };
+
+    let content_type = file
+        .mime_type
+        .clone()
+        .unwrap_or_else(|| mime_from_name(&file.name));
+
+    let mut response = match range {
+        Some(_) => HttpResponse::PartialContent(),
+        None => HttpResponse::Ok(),
+    };
+
+    response
+        .content_type(content_type)
+        .insert_header(ContentDisposition {
+            disposition,
+            parameters: vec![DispositionParam::Filename(file.original_name.to_owned())],
+        })
+        .insert_header(header::ETag(etag))
+        .insert_header(LastModified(last_modified))
+        .insert_header((header::ACCEPT_RANGES, "bytes"));
+
+    let (object_range, length) = match range {
+        Some(range) => {
+            let end = range.start + range.length - 1;
+            response.insert_header((
+                header::CONTENT_RANGE,
+                format!("bytes {}-{}/{}", range.start, end, size),
+            ));
+
+            (
+                Some(ObjectRange {
+                    start: range.start,
+                    end: Some(end),
+                }),
+                range.length,
+            )
+        }
+        None => (None, size),
+    };
+
+    let stream = state.storage.get_object(&file.object, object_range).await?;
+
+    record_view(&state.database, &file.id, req, length).await;
+
+    Ok(response.no_chunking(length).streaming(stream))
+}
+
+/// Respond with an object derived from a file which the requester was allowed to read, such as a thumbnail.
+/// The object is streamed from the storage provider, which decrypts it if it is encrypted at rest.
+pub async fn serve_derived(
+    state: &State,
+    name: &str,
+    content_type: &str,
+    public: bool,
+) -> HttpResponse {
+    let stream = match state.storage.get_object(name, None).await {
+        Ok(v) => v,
+        Err(_) => {
+            return MessageResponse::new(StatusCode::NOT_FOUND, "Resource was not found!")
+                .http_response()
+        }
+    };
+
+    let cache_control = if public {
+        CacheDirective::Public
+    } else {
+        CacheDirective::Private
+    };
+
+    HttpResponse::Ok()
+        .content_type(content_type)
+        .insert_header(CacheControl(vec![
+            cache_control,
+            CacheDirective::MaxAge(24 * 60 * 60),
+        ]))
+        .streaming(stream)
+}
+
+/// Name of the file an object derived from it belongs to, and the format it was encoded in.
+/// Derived objects are stored under thumb/{size}/{name}.{format} and transform/{name}/{variant}.{format}
+fn derived_object(path: &str) -> Option<(&str, Option<ThumbnailFormat>)> {
+    if let Some(path) = path.strip_prefix("thumb/") {
+        Some(
+            match path
+                .split_once('/')
+                .and_then(|(_, path)| path.rsplit_once('.'))
+            {
+                Some((name, extension)) => (name, extension.parse::<ThumbnailFormat>().ok()),
+                // Thumbnails from before there were variants
+                None => (path, None),
+            },
+        )
+    } else if let Some(path) = path.strip_prefix("transform/") {
+        path.split_once('/').map(|(name, variant)| {
+            (
+                name,
+                variant
+                    .rsplit_once('.')
+                    .and_then(|(_, extension)| extension.parse().ok()),
+            )
+        })
+    } else {
+        None
+    }
+}
+
+/// Respond with an object from the local storage directory at `root`, if the requester may read the file it belongs to.
+/// Objects which don't belong to a file are never served, such as pending uploads.
+pub async fn serve_local_object(
+    req: &HttpRequest,
+    state: &State,
+    root: &Path,
+    sharded: bool,
+    path: &str,
+) -> HttpResponse {
+    let derived = derived_object(path);
+
+    // Shared contents are only served at the URL of the file they were uploaded as,
+    // the other files pointing at them are served by the application
+    let condition = match derived {
+        Some((name, _)) => files::Column::Name.eq(name),
+        None => files::Column::Object
+            .eq(path)
+            .and(files::Column::Name.eq(path)),
+    };
+
+    let file = match files::Entity::find()
+        .filter(condition)
+        .one(&state.database)
+        .await
+    {
+        Ok(Some(v)) => v,
+        _ => {
+            return MessageResponse::new(StatusCode::NOT_FOUND, "Resource was not found!")
+                .http_response()
+        }
+    };
+
+    if is_expired(&file) {
+        return MessageResponse::new(StatusCode::GONE, "That file has expired").http_response();
+    }
+
+    // Derived objects are stored separately from the contents of their file
+    if derived.is_none() {
+        if let Err(err) = check_intact(&file) {
+            return err.http_response();
+        }
+    }
+
+    // Files which aren't directly readable need a signed token
+    if !FileVisibility::from(file.visibility.clone()).is_direct() {
+        let token = web::Query::<HashMap<String, String>>::from_query(req.query_string())
+            .ok()
+            .and_then(|query| query.get("token").cloned())
+            .unwrap_or_default();
+
+        if !verify_object_token(&state.jwt_key, path, &token) {
+            return MessageResponse::new(StatusCode::NOT_FOUND, "Resource was not found!")
+                .http_response();
+        }
+    }
+
+    let content_type = match derived {
+        // Derived objects are named after the format they were encoded in
+        Some((_, format)) => {
+            let content_type = format.unwrap_or(ThumbnailFormat::Png).content_type();
+
+            // Encrypted objects are decrypted while they are streamed
+            if state.encrypted_at_rest {
+                return serve_derived(
+                    state,
+                    path,
+                    content_type,
+                    FileVisibility::from(file.visibility).is_direct(),
+                )
+                .await;
+            }
+
+            Some(content_type.to_string())
+        }
+        // Thumbnails and transformed images are not counted as downloads
+        None => {
+            if counts_as_download(req, &file) {
+                if let Err(err) = count_download(&state.database, &file.id).await {
+                    log::warn!("Unable to count a download of file {}: {}", file.id, err);
+                }
+            }
+
+            // Encrypted objects are decrypted while they are streamed, cold ones aren't stored locally
+            if state.encrypted_at_rest || file.tier == StorageTier::Cold {
+                return match serve_file(req, state, file, DispositionType::Inline).await {
+                    Ok(v) => v,
+                    Err(err) => err.error_response(),
+                };
+            }
+
+            // Revalidations are most likely answered without the contents
+            if !req.headers().contains_key(header::IF_NONE_MATCH)
+                && !req.headers().contains_key(header::IF_MODIFIED_SINCE)
+            {
+                record_view(
+                    &state.database,
+                    &file.id,
+                    req,
+                    requested_length(req, file.size as u64),
+                )
+                .await;
+            }
+
+            // Detected type is more accurate than the extension
+            file.mime_type
+        }
+    };
+
+    // Sanitize the path to prevent walking to another directory
+    let object = path.replace("..", "");
+    let file_path = root.join(match sharded {
+        true => shard_name(&object),
+        false => object,
+    });
+
+    match NamedFile::open(&file_path) {
+        Ok(v) => {
+            let v = match content_type.and_then(|mime| mime.parse().ok()) {
+                Some(mime) => v.set_content_type(mime),
+                None => v,
+            };
+
+            v.into_response(req)
+        }
+        Err(_) => {
+            MessageResponse::new(StatusCode::NOT_FOUND, "Resource was not found!").http_response()
+        }
+    }
+}
@@ -0,0 +1,98 @@
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+type HmacSha256 = Hmac<Sha256>;
+
+fn new_mac(key: &str, message: &str) -> HmacSha256 {
+    let mut mac =
+        HmacSha256::new_from_slice(key.as_bytes()).expect("HMAC can take a key of any size");
+    mac.update(message.as_bytes());
+    mac
+}
+
+/// Sign a message with HMAC-SHA256, the signature is URL safe base64
+pub fn sign(key: &str, message: &str) -> String {
+    base64::encode_config(
+        new_mac(key, message).finalize().into_bytes(),
+        base64::URL_SAFE_NO_PAD,
+    )
+}
+
+/// Check a signature created by [`sign`] in constant time
+pub fn verify(key: &str, message: &str, signature: &str) -> bool {
+    match base64::decode_config(signature, base64::URL_SAFE_NO_PAD) {
+        Ok(signature) => new_mac(key, message).verify_slice(&signature).is_ok(),
+        Err(_) => false,
+    }
+}
+
+/// Token which allows reading an object from the storage URL until the expiry timestamp
+pub fn object_token(key: &str, name: &str, expires: i64) -> String {
+    format!(
+        "{}.{}",
+        expires,
+        sign(key, &format!("object:{}:{}", name, expires))
+    )
+}
+
+/// Check a token created by [`object_token`] for an object
+pub fn verify_object_token(key: &str, name: &str, token: &str) -> bool {
+    let (expires, signature) = match token.split_once('.') {
+        Some(v) => v,
+        None => return false,
+    };
+
+    match expires.parse::<i64>() {
+        Ok(expires) if expires > Utc::now().timestamp() => {
+            verify(key, &format!("object:{}:{}", name, expires), signature)
+        }
+        _ => false,
+    }
+}
+
+fn share_message(
+    share_id: &str,
+    file_id: &str,
+    expires: i64,
+    max_downloads: Option<i32>,
+) -> String {
+    format!(
+        "share:{}:{}:{}:{}",
+        share_id,
+        file_id,
+        expires,
+        max_downloads.map(|max| max.to_string()).unwrap_or_default()
+    )
+}
+
+/// Sign the parameters of a share link
+pub fn share_signature(
+    key: &str,
+    share_id: &str,
+    file_id: &str,
+    expires: i64,
+    max_downloads: Option<i32>,
+) -> String {
+    sign(
+        key,
+        &share_message(share_id, file_id, expires, max_downloads),
+    )
+}
+
+/// Check a share link signature and that the link has not expired
+pub fn verify_share(
+    key: &str,
+    share_id: &str,
+    file_id: &str,
+    expires: i64,
+    max_downloads: Option<i32>,
+    signature: &str,
+) -> bool {
+    expires > Utc::now().timestamp()
+        && verify(
+            key,
+            &share_message(share_id, file_id, expires, max_downloads),
+            signature,
+        )
+}
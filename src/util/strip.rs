@@ -0,0 +1,352 @@
+use std::fmt::Display;
+
+use bytes::Bytes;
+use futures::{Stream, StreamExt};
+use image::DynamicImage;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    storage::StorageProvider,
+    util::{
+        file::{encode_image, store_stream, MultipartError, StoredObject},
+        metadata::read_header,
+        thumbnail::ThumbnailFormat,
+    },
+};
+
+/// Largest image which is read into memory to have its metadata removed
+const MAX_STRIP_SIZE: usize = 64 * 1000 * 1000;
+
+/// Bytes read from the start of a file to detect whether metadata can be removed from it
+pub const SNIFF_SIZE: usize = 64;
+
+/// Quality used when an image has to be encoded again to apply its orientation
+const REENCODE_QUALITY: u8 = 90;
+
+/// JPEG segments holding EXIF/XMP (APP1), ICC profiles (APP2), IPTC (APP13) and comments
+const JPEG_REMOVED_MARKERS: [u8; 4] = [0xE1, 0xE2, 0xED, 0xFE];
+
+/// PNG chunks holding EXIF, ICC profiles, text (including XMP) and modification time
+const PNG_REMOVED_CHUNKS: [&[u8; 4]; 6] = [b"eXIf", b"iCCP", b"tEXt", b"zTXt", b"iTXt", b"tIME"];
+
+/// WebP chunks holding EXIF, XMP and ICC profiles
+const WEBP_REMOVED_CHUNKS: [&[u8; 4]; 3] = [b"EXIF", b"XMP ", b"ICCP"];
+
+/// Can metadata be removed from a file starting with these bytes.
+/// The type is detected from the contents, the name of an upload may not match them.
+pub fn supports_stripping(header: &[u8]) -> bool {
+    matches!(
+        infer::get(header).map(|kind| kind.mime_type()),
+        Some("image/jpeg" | "image/png" | "image/webp")
+    )
+}
+
+/// Remove EXIF, XMP and ICC metadata from a JPEG, PNG or WebP image.
+/// The EXIF orientation is applied to the pixels first so the image still displays upright.
+/// `None` if the image had no metadata or is not one of these formats.
+pub fn strip_metadata(bytes: &[u8]) -> Result<Option<Vec<u8>>, anyhow::Error> {
+    let (format, (stripped, exif)) = match infer::get(bytes).map(|kind| kind.mime_type()) {
+        Some("image/jpeg") => (ThumbnailFormat::Jpeg, strip_jpeg(bytes)?),
+        Some("image/png") => (ThumbnailFormat::Png, strip_png(bytes)?),
+        Some("image/webp") => (ThumbnailFormat::Webp, strip_webp(bytes)?),
+        _ => return Ok(None),
+    };
+
+    let orientation = exif.and_then(|exif| {
+        exif::Reader::new()
+            .read_raw(exif)
+            .ok()?
+            .get_field(exif::Tag::Orientation, exif::In::PRIMARY)?
+            .value
+            .get_uint(0)
+    });
+
+    // Encoded images never carry metadata
+    if let Some(orientation @ 2..=8) = orientation {
+        let image = apply_orientation(image::load_from_memory(&stripped)?, orientation);
+        return Ok(Some(encode_image(&image, format, REENCODE_QUALITY)?));
+    }
+
+    Ok(if stripped.len() < bytes.len() {
+        Some(stripped)
+    } else {
+        None
+    })
+}
+
+/// Rotate and flip an image the way its EXIF orientation describes
+fn apply_orientation(image: DynamicImage, orientation: u32) -> DynamicImage {
+    match orientation {
+        2 => image.fliph(),
+        3 => image.rotate180(),
+        4 => image.flipv(),
+        5 => image.rotate90().fliph(),
+        6 => image.rotate90(),
+        7 => image.rotate270().fliph(),
+        8 => image.rotate270(),
+        _ => image,
+    }
+}
+
+/// Remove metadata segments from a JPEG, returns the raw EXIF data if there was any
+fn strip_jpeg(bytes: &[u8]) -> Result<(Vec<u8>, Option<Vec<u8>>), anyhow::Error> {
+    if !bytes.starts_with(&[0xFF, 0xD8]) {
+        anyhow::bail!("missing JPEG start of image");
+    }
+
+    let mut output = bytes[..2].to_vec();
+    let mut exif = None;
+    let mut position = 2;
+
+    loop {
+        if position + 2 > bytes.len() || bytes[position] != 0xFF {
+            anyhow::bail!("invalid JPEG segment at {}", position);
+        }
+
+        let marker = bytes[position + 1];
+        match marker {
+            // Fill byte before a marker
+            0xFF => {
+                position += 1;
+                continue;
+            }
+            // Start of scan, everything after is image data
+            0xDA | 0xD9 => {
+                output.extend_from_slice(&bytes[position..]);
+                break;
+            }
+            // Markers without a length
+            0x01 | 0xD0..=0xD7 => {
+                output.extend_from_slice(&bytes[position..position + 2]);
+                position += 2;
+                continue;
+            }
+            _ => {}
+        }
+
+        if position + 4 > bytes.len() {
+            anyhow::bail!("truncated JPEG segment at {}", position);
+        }
+
+        let length = u16::from_be_bytes([bytes[position + 2], bytes[position + 3]]) as usize;
+        let end = position + 2 + length;
+        if length < 2 || end > bytes.len() {
+            anyhow::bail!("truncated JPEG segment at {}", position);
+        }
+
+        let data = &bytes[position + 4..end];
+        if marker == 0xE1 && data.starts_with(b"Exif\0\0") {
+            exif = Some(data[6..].to_vec());
+        }
+
+        if !JPEG_REMOVED_MARKERS.contains(&marker) {
+            output.extend_from_slice(&bytes[position..end]);
+        }
+
+        position = end;
+    }
+
+    Ok((output, exif))
+}
+
+/// Remove metadata chunks from a PNG, returns the raw EXIF data if there was any
+fn strip_png(bytes: &[u8]) -> Result<(Vec<u8>, Option<Vec<u8>>), anyhow::Error> {
+    if bytes.len() < 8 {
+        anyhow::bail!("missing PNG signature");
+    }
+
+    // Signature
+    let mut output = bytes[..8].to_vec();
+    let mut exif = None;
+    let mut position = 8;
+
+    while position + 12 <= bytes.len() {
+        let length = u32::from_be_bytes([
+            bytes[position],
+            bytes[position + 1],
+            bytes[position + 2],
+            bytes[position + 3],
+        ]) as usize;
+        let chunk_type = &bytes[position + 4..position + 8];
+
+        // Length, type, data and CRC
+        let end = position + 12 + length;
+        if end > bytes.len() {
+            anyhow::bail!("truncated PNG chunk at {}", position);
+        }
+
+        if chunk_type == b"eXIf" {
+            exif = Some(bytes[position + 8..end - 4].to_vec());
+        }
+
+        if !PNG_REMOVED_CHUNKS
+            .iter()
+            .any(|removed| chunk_type == &removed[..])
+        {
+            output.extend_from_slice(&bytes[position..end]);
+        }
+
+        position = end;
+
+        if chunk_type == b"IEND" {
+            break;
+        }
+    }
+
+    Ok((output, exif))
+}
+
+/// Remove metadata chunks from a WebP, returns the raw EXIF data if there was any
+fn strip_webp(bytes: &[u8]) -> Result<(Vec<u8>, Option<Vec<u8>>), anyhow::Error> {
+    if bytes.len() < 12 || &bytes[..4] != b"RIFF" || &bytes[8..12] != b"WEBP" {
+        anyhow::bail!("missing WebP header");
+    }
+
+    // RIFF header, the size is written once the chunks are known
+    let mut output = bytes[..12].to_vec();
+    let mut exif = None;
+    let mut position = 12;
+
+    while position + 8 <= bytes.len() {
+        let fourcc = &bytes[position..position + 4];
+        let length = u32::from_le_bytes([
+            bytes[position + 4],
+            bytes[position + 5],
+            bytes[position + 6],
+            bytes[position + 7],
+        ]) as usize;
+
+        // Chunks are padded to an even size
+        let end = (position + 8 + length + (length & 1)).min(bytes.len());
+        if position + 8 + length > bytes.len() {
+            anyhow::bail!("truncated WebP chunk at {}", position);
+        }
+
+        if fourcc == b"EXIF" {
+            let data = &bytes[position + 8..position + 8 + length];
+            exif = Some(data.strip_prefix(b"Exif\0\0").unwrap_or(data).to_vec());
+        }
+
+        if !WEBP_REMOVED_CHUNKS
+            .iter()
+            .any(|removed| fourcc == &removed[..])
+        {
+            let start = output.len();
+            output.extend_from_slice(&bytes[position..end]);
+
+            // Extended format flags announcing ICC, EXIF and XMP chunks
+            if fourcc == b"VP8X" && length > 0 {
+                output[start + 8] &= !(0x20 | 0x08 | 0x04);
+            }
+        }
+
+        position = end;
+    }
+
+    let riff_size = (output.len() - 8) as u32;
+    output[4..8].copy_from_slice(&riff_size.to_le_bytes());
+
+    Ok((output, exif))
+}
+
+/// Remove metadata from bytes which may not be an image, leaving them unchanged if it fails
+async fn strip_bytes(bytes: Vec<u8>) -> Result<(Vec<u8>, bool), anyhow::Error> {
+    // Decoding and encoding is slow, keep it off the async workers
+    Ok(
+        tokio::task::spawn_blocking(move || match strip_metadata(&bytes) {
+            Ok(Some(stripped)) => (stripped, true),
+            Ok(None) => (bytes, false),
+            Err(err) => {
+                log::warn!("Unable to remove image metadata: {}", err);
+                (bytes, false)
+            }
+        })
+        .await?,
+    )
+}
+
+/// Read an image payload into memory and remove its metadata before it is hashed and written.
+/// Images too large to hold in memory are written as they are received instead.
+pub async fn store_stripped_stream<S, E>(
+    mut stream: S,
+    storage: &dyn StorageProvider,
+    name: &str,
+    size_limit: usize,
+) -> Result<StoredObject, MultipartError>
+where
+    S: Stream<Item = Result<Bytes, E>> + Unpin,
+    E: Display,
+{
+    let mut bytes = Vec::new();
+
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk.map_err(|err| {
+            MultipartError::WriteError(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                err.to_string(),
+            ))
+        })?;
+
+        bytes.extend_from_slice(&chunk);
+
+        if bytes.len() > size_limit {
+            return Err(MultipartError::PayloadTooLarge(size_limit));
+        }
+
+        if bytes.len() > MAX_STRIP_SIZE {
+            return store_stream(
+                futures::stream::iter(vec![Ok::<Bytes, E>(Bytes::from(bytes))]).chain(stream),
+                storage,
+                name,
+                size_limit,
+            )
+            .await;
+        }
+    }
+
+    let (bytes, metadata_removed) = strip_bytes(bytes)
+        .await
+        .map_err(MultipartError::StorageError)?;
+
+    let mut stored = store_stream(
+        futures::stream::iter(vec![Ok::<Bytes, E>(Bytes::from(bytes))]),
+        storage,
+        name,
+        size_limit,
+    )
+    .await?;
+
+    stored.metadata_removed = metadata_removed;
+    Ok(stored)
+}
+
+/// Remove metadata from an image which was already written to the storage provider.
+/// Objects which are not JPEG, PNG or WebP images are left alone without being read entirely.
+/// The object is replaced and hashed again if anything was removed.
+pub async fn strip_stored_object(
+    storage: &dyn StorageProvider,
+    name: &str,
+    stored: StoredObject,
+) -> Result<StoredObject, anyhow::Error> {
+    if stored.size == 0
+        || stored.size > MAX_STRIP_SIZE
+        || !supports_stripping(&read_header(storage, name, SNIFF_SIZE as u64).await?)
+    {
+        return Ok(stored);
+    }
+
+    let (bytes, metadata_removed) = strip_bytes(storage.get_object_bytes(name).await?).await?;
+    if !metadata_removed {
+        return Ok(stored);
+    }
+
+    let stripped = StoredObject {
+        hash: format!("{:x}", Sha256::digest(&bytes)),
+        size: bytes.len(),
+        metadata_removed,
+    };
+
+    storage.put_object_bytes(name, bytes).await?;
+
+    Ok(stripped)
+}
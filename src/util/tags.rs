@@ -0,0 +1,90 @@
+use std::collections::HashMap;
+
+use actix_web::http::StatusCode;
+use sea_orm::{ColumnTrait, DatabaseConnection, DbErr, EntityTrait, QueryFilter, QueryOrder};
+
+use crate::{
+    database::entity::file_tags,
+    models::{FileData, MessageResponse},
+};
+
+/// Most tags a single file can have
+const MAX_TAGS: usize = 20;
+
+/// Lowercase, deduplicate and validate tags
+pub fn normalize_tags(tags: &[String]) -> Result<Vec<String>, MessageResponse> {
+    let mut normalized: Vec<String> = Vec::new();
+
+    for tag in tags {
+        let tag = tag.trim().to_lowercase();
+
+        if tag.is_empty() || tag.len() > 32 {
+            return Err(MessageResponse::new(
+                StatusCode::BAD_REQUEST,
+                "Tags must be between 1 and 32 characters",
+            ));
+        }
+
+        if !tag
+            .chars()
+            .all(|c| c.is_alphanumeric() || c == '-' || c == '_')
+        {
+            return Err(MessageResponse::new(
+                StatusCode::BAD_REQUEST,
+                "Tags can only contain letters, numbers, dashes and underscores",
+            ));
+        }
+
+        if !normalized.contains(&tag) {
+            normalized.push(tag);
+        }
+    }
+
+    if normalized.len() > MAX_TAGS {
+        return Err(MessageResponse::new(
+            StatusCode::BAD_REQUEST,
+            &format!("A file can have at most {} tags", MAX_TAGS),
+        ));
+    }
+
+    Ok(normalized)
+}
+
+/// Fill in the tags of every file with a single query
+pub async fn load_tags(database: &DatabaseConnection, files: &mut [FileData]) -> Result<(), DbErr> {
+    if files.is_empty() {
+        return Ok(());
+    }
+
+    let mut tags: HashMap<String, Vec<String>> = HashMap::new();
+    for file_tag in file_tags::Entity::find()
+        .filter(file_tags::Column::FileId.is_in(files.iter().map(|file| file.id.to_owned())))
+        .order_by_asc(file_tags::Column::Tag)
+        .all(database)
+        .await?
+    {
+        tags.entry(file_tag.file_id).or_default().push(file_tag.tag);
+    }
+
+    for file in files {
+        file.tags = tags.remove(&file.id).unwrap_or_default();
+    }
+
+    Ok(())
+}
+
+/// Build a prefix matching `tsquery` from user input where every word must match.
+/// Words are split the same way names are so the input can't contain query operators.
+pub fn search_query(query: &str) -> Option<String> {
+    let words: Vec<String> = query
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(|word| format!("{}:*", word.to_lowercase()))
+        .collect();
+
+    if words.is_empty() {
+        None
+    } else {
+        Some(words.join(" & "))
+    }
+}
@@ -0,0 +1,396 @@
+use std::{collections::BTreeMap, path::PathBuf, process::Output, str::FromStr};
+
+use sea_orm::{
+    ColumnTrait, ConnectionTrait, DatabaseConnection, DbErr, EntityTrait, QueryFilter, QueryOrder,
+    Set,
+};
+use serde::Deserialize;
+use tokio::process::Command;
+
+use crate::{
+    database::entity::{file_thumbnails, files},
+    models::{FileData, FileVisibility},
+    state::State,
+    storage::StorageProvider,
+    util::{
+        access::{signed_object_url, signed_route_url},
+        file::get_thumbnail_image,
+        metadata::{command_available, is_thumbnailable, mime_from_name, run_command, TempFile},
+    },
+};
+
+/// Format an image can be encoded in
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum ThumbnailFormat {
+    Png,
+    #[serde(alias = "jpg")]
+    Jpeg,
+    Webp,
+    Avif,
+}
+
+impl ThumbnailFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ThumbnailFormat::Png => "png",
+            ThumbnailFormat::Jpeg => "jpg",
+            ThumbnailFormat::Webp => "webp",
+            ThumbnailFormat::Avif => "avif",
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            ThumbnailFormat::Png => "image/png",
+            ThumbnailFormat::Jpeg => "image/jpeg",
+            ThumbnailFormat::Webp => "image/webp",
+            ThumbnailFormat::Avif => "image/avif",
+        }
+    }
+}
+
+impl FromStr for ThumbnailFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "png" => Ok(ThumbnailFormat::Png),
+            "jpg" | "jpeg" => Ok(ThumbnailFormat::Jpeg),
+            "webp" => Ok(ThumbnailFormat::Webp),
+            "avif" => Ok(ThumbnailFormat::Avif),
+            _ => Err(format!("unknown image format `{}`", s)),
+        }
+    }
+}
+
+/// Thumbnail encoded at one size and format
+pub struct Thumbnail {
+    pub size: u32,
+    pub format: ThumbnailFormat,
+    pub bytes: Vec<u8>,
+}
+
+/// Size recorded for the single PNG thumbnail files had before there were variants
+pub const LEGACY_THUMBNAIL: u32 = 0;
+
+/// Largest side of legacy thumbnails in pixels, they are listed under it
+const LEGACY_THUMBNAIL_SIZE: u32 = 500;
+
+/// Storage key of a thumbnail variant, the extension is the format it was encoded in
+pub fn thumbnail_name(name: &str, size: u32, extension: &str) -> String {
+    match size {
+        LEGACY_THUMBNAIL => format!("thumb/{}", name),
+        _ => format!("thumb/{}/{}.{}", size, name, extension),
+    }
+}
+
+/// Can a thumbnail possibly be made for a file of this type.
+/// Videos and PDFs also depend on ffmpeg and pdftoppm being installed.
+pub fn supports_thumbnail(mime_type: &str) -> bool {
+    is_thumbnailable(mime_type) || mime_type.starts_with("video/") || mime_type == "application/pdf"
+}
+
+/// Create thumbnails of an object in every size, each in the preferred format with a PNG fallback.
+/// Empty if no thumbnail can be made.
+pub async fn create_thumbnails(
+    storage: &dyn StorageProvider,
+    name: &str,
+    mime_type: &str,
+    duration: Option<f64>,
+    sizes: &[u32],
+    format: ThumbnailFormat,
+) -> Vec<Thumbnail> {
+    let source = if is_thumbnailable(mime_type) {
+        storage.get_object_bytes(name).await.ok()
+    } else if mime_type.starts_with("video/") {
+        video_frame(storage, name, duration).await
+    } else if mime_type == "application/pdf" {
+        pdf_page(storage, name).await
+    } else {
+        None
+    };
+
+    let source = match source {
+        Some(v) => v,
+        None => return Vec::new(),
+    };
+
+    let mut formats = vec![format];
+    if format != ThumbnailFormat::Png {
+        formats.push(ThumbnailFormat::Png);
+    }
+
+    let sizes = sizes.to_vec();
+
+    // Decoding and encoding large images is slow, keep it off the async workers
+    tokio::task::spawn_blocking(move || {
+        let image = match image::load_from_memory(&source) {
+            Ok(v) => v,
+            Err(_) => return Vec::new(),
+        };
+
+        let mut thumbnails = Vec::new();
+        for size in sizes {
+            for format in &formats {
+                match get_thumbnail_image(&image, size, *format) {
+                    Ok(bytes) => thumbnails.push(Thumbnail {
+                        size,
+                        format: *format,
+                        bytes,
+                    }),
+                    Err(err) => log::warn!(
+                        "Unable to encode {} thumbnail as {:?}: {}",
+                        size,
+                        format,
+                        err
+                    ),
+                }
+            }
+        }
+
+        thumbnails
+    })
+    .await
+    .unwrap_or_default()
+}
+
+/// Write thumbnails to the storage provider, returns the size and format of the ones which were stored
+pub async fn put_thumbnails(
+    storage: &dyn StorageProvider,
+    name: &str,
+    thumbnails: Vec<Thumbnail>,
+) -> Vec<(u32, ThumbnailFormat)> {
+    let mut stored = Vec::new();
+
+    for thumbnail in thumbnails {
+        if storage
+            .put_object_bytes(
+                &thumbnail_name(name, thumbnail.size, thumbnail.format.extension()),
+                thumbnail.bytes,
+            )
+            .await
+            .is_ok()
+        {
+            stored.push((thumbnail.size, thumbnail.format));
+        }
+    }
+
+    stored
+}
+
+/// Replace the recorded thumbnail variants of a file
+pub async fn record_thumbnails<C: ConnectionTrait>(
+    database: &C,
+    file_id: &str,
+    variants: &[(u32, ThumbnailFormat)],
+) -> Result<(), DbErr> {
+    file_thumbnails::Entity::delete_many()
+        .filter(file_thumbnails::Column::FileId.eq(file_id))
+        .exec(database)
+        .await?;
+
+    if !variants.is_empty() {
+        file_thumbnails::Entity::insert_many(variants.iter().map(|(size, format)| {
+            file_thumbnails::ActiveModel {
+                file_id: Set(file_id.to_owned()),
+                size: Set(*size as i32),
+                format: Set(format.extension().to_string()),
+            }
+        }))
+        .exec(database)
+        .await?;
+    }
+
+    Ok(())
+}
+
+/// Storage keys of every thumbnail variant of a file
+pub async fn thumbnail_names(
+    database: &DatabaseConnection,
+    file_id: &str,
+    name: &str,
+) -> Result<Vec<String>, DbErr> {
+    Ok(file_thumbnails::Entity::find()
+        .filter(file_thumbnails::Column::FileId.eq(file_id))
+        .all(database)
+        .await?
+        .iter()
+        .map(|variant| thumbnail_name(name, variant.size as u32, &variant.format))
+        .collect())
+}
+
+/// Fill in the thumbnail URLs of every file with a single query.
+/// Files which are not directly readable get signed URLs.
+pub async fn load_thumbnails(state: &State, files: &mut [FileData]) -> Result<(), DbErr> {
+    if files.is_empty() {
+        return Ok(());
+    }
+
+    let mut variants: BTreeMap<String, Vec<file_thumbnails::Model>> = BTreeMap::new();
+    for variant in file_thumbnails::Entity::find()
+        .filter(file_thumbnails::Column::FileId.is_in(files.iter().map(|file| file.id.to_owned())))
+        .order_by_asc(file_thumbnails::Column::Size)
+        .all(&state.database)
+        .await?
+    {
+        variants
+            .entry(variant.file_id.to_owned())
+            .or_default()
+            .push(variant);
+    }
+
+    for file in files {
+        file.thumbnails = BTreeMap::new();
+
+        for variant in variants.remove(&file.id).unwrap_or_default() {
+            let name = thumbnail_name(&file.name, variant.size as u32, &variant.format);
+
+            let url = if state.encrypted_at_rest {
+                // Thumbnails are decrypted by the application like the file they belong to
+                let url = format!(
+                    "{}api/file/{}/thumbnail/{}/{}",
+                    state.base_url, file.id, variant.size, variant.format
+                );
+
+                match file.visibility.is_direct() {
+                    true => url,
+                    false => signed_route_url(state, url, &name),
+                }
+            } else if file.visibility.is_direct() {
+                let mut url = PathBuf::from(&state.storage_url);
+                url.push(&name);
+                url.as_path().display().to_string().replace("\\", "/")
+            } else {
+                signed_object_url(state, &name)
+            };
+
+            let size = match variant.size as u32 {
+                LEGACY_THUMBNAIL => LEGACY_THUMBNAIL_SIZE,
+                size => size,
+            };
+
+            file.thumbnails
+                .entry(size)
+                .or_default()
+                .insert(variant.format, url);
+        }
+    }
+
+    Ok(())
+}
+
+/// Create every configured thumbnail variant of a file again, replacing the previous ones.
+/// Returns false if no thumbnail could be created from the contents.
+pub async fn regenerate_thumbnails(
+    state: &State,
+    file: &files::Model,
+) -> Result<bool, anyhow::Error> {
+    let mime_type = file
+        .mime_type
+        .clone()
+        .unwrap_or_else(|| mime_from_name(&file.name));
+
+    let thumbnails = create_thumbnails(
+        state.storage.as_ref(),
+        &file.object,
+        &mime_type,
+        file.duration,
+        &state.thumbnail_sizes,
+        state.thumbnail_format,
+    )
+    .await;
+
+    if thumbnails.is_empty() {
+        return Ok(false);
+    }
+
+    let previous = thumbnail_names(&state.database, &file.id, &file.name).await?;
+    let variants = put_thumbnails(state.storage.as_ref(), &file.name, thumbnails).await;
+    record_thumbnails(&state.database, &file.id, &variants).await?;
+
+    let current: Vec<String> = variants
+        .iter()
+        .map(|(size, format)| thumbnail_name(&file.name, *size, format.extension()))
+        .collect();
+
+    // Remove variants which are no longer configured and the old single thumbnail
+    for name in previous
+        .into_iter()
+        .chain(std::iter::once(format!("thumb/{}", file.name)))
+        .filter(|name| !current.contains(name))
+    {
+        let _ = state.storage.delete_object(&name).await;
+    }
+
+    if FileVisibility::from(file.visibility.clone()).is_direct() {
+        // Thumbnails are written private like every other object
+        for name in &current {
+            let _ = state.storage.set_object_public(name, true).await;
+        }
+    }
+
+    Ok(true)
+}
+
+/// Output of a successful command, `None` if it failed or printed nothing
+fn command_output(output: Option<Output>) -> Option<Vec<u8>> {
+    match output {
+        Some(output) if output.status.success() && !output.stdout.is_empty() => Some(output.stdout),
+        _ => None,
+    }
+}
+
+/// Extract a frame near the start of a video as PNG using ffmpeg
+async fn video_frame(
+    storage: &dyn StorageProvider,
+    name: &str,
+    duration: Option<f64>,
+) -> Option<Vec<u8>> {
+    if !command_available("ffmpeg").await {
+        return None;
+    }
+
+    let temp_file = TempFile::download(storage, name).await.ok()?;
+
+    // The first frame is often black, skip a little into the video
+    let seek = duration.map_or(0.0, |duration| (duration / 10.0).min(5.0));
+
+    command_output(
+        run_command(
+            Command::new("ffmpeg")
+                .args(&["-v", "error", "-ss", &seek.to_string(), "-i"])
+                .arg(&temp_file.path)
+                .args(&["-frames:v", "1", "-f", "image2pipe", "-vcodec", "png", "-"]),
+        )
+        .await,
+    )
+}
+
+/// Render the first page of a PDF as PNG using pdftoppm
+async fn pdf_page(storage: &dyn StorageProvider, name: &str) -> Option<Vec<u8>> {
+    if !command_available("pdftoppm").await {
+        return None;
+    }
+
+    let temp_file = TempFile::download(storage, name).await.ok()?;
+
+    // Without an output name the page is written to stdout
+    command_output(
+        run_command(
+            Command::new("pdftoppm")
+                .args(&[
+                    "-f",
+                    "1",
+                    "-l",
+                    "1",
+                    "-png",
+                    "-singlefile",
+                    "-scale-to",
+                    "1000",
+                ])
+                .arg(&temp_file.path),
+        )
+        .await,
+    )
+}
@@ -0,0 +1,110 @@
+use sea_orm::{ConnectionTrait, DbBackend, DbErr, Statement};
+
+use crate::{
+    config::TieringConfig, database::entity::sea_orm_active_enums::StorageTier, state::State,
+    storage::tiered::Tier, util::blob::is_object_public,
+};
+
+/// Objects moved per query, the worker continues until every object is on its tier
+const TIER_BATCH: usize = 100;
+
+impl From<Tier> for StorageTier {
+    fn from(tier: Tier) -> Self {
+        match tier {
+            Tier::Hot => StorageTier::Hot,
+            Tier::Cold => StorageTier::Cold,
+        }
+    }
+}
+
+/// Move objects whose files were not read recently to cold storage, and objects which were read again back.
+/// Objects are moved together with every file sharing them, so an object is cold only if all of its files are idle.
+/// Objects read directly from the storage URL never record their reads and always stay hot,
+/// moving them would break every URL which was handed out.
+/// Returns how many objects were moved.
+pub async fn tier_objects(state: &State, config: &TieringConfig) -> Result<usize, anyhow::Error> {
+    let mut moved = 0;
+    let mut failed = 0;
+
+    loop {
+        // Objects which failed to move stay first in the order, they are skipped until the next run
+        let batch = objects_to_move(state, config, failed).await?;
+
+        for (object, tier) in &batch {
+            match move_object(state, object, *tier).await {
+                Ok(_) => moved += 1,
+                Err(err) => {
+                    log::error!("Unable to move {} to the {:?} tier: {}", object, tier, err);
+                    failed += 1;
+                }
+            }
+        }
+
+        if batch.len() < TIER_BATCH {
+            return Ok(moved);
+        }
+    }
+}
+
+/// Objects whose tier doesn't match how recently their files were read
+async fn objects_to_move(
+    state: &State,
+    config: &TieringConfig,
+    offset: usize,
+) -> Result<Vec<(String, Tier)>, DbErr> {
+    state
+        .database
+        .query_all(Statement::from_sql_and_values(
+            DbBackend::Postgres,
+            r#"SELECT object, bool_and(idle) AS cold FROM (
+                   SELECT object, tier,
+                          uploaded < now() - make_interval(days => $1)
+                              AND COALESCE(last_accessed, uploaded) < now() - make_interval(days => $2)
+                              AND NOT ($5 AND name = object AND visibility IN ('public', 'unlisted')) AS idle
+                   FROM files
+               ) AS files
+               GROUP BY object
+               HAVING bool_and(idle) <> bool_or(tier = 'cold')
+               ORDER BY object
+               LIMIT $3 OFFSET $4"#,
+            vec![
+                config.min_age_days.into(),
+                config.idle_days.into(),
+                (TIER_BATCH as i64).into(),
+                (offset as i64).into(),
+                // Everything is served by the application when objects are encrypted at rest
+                (!state.encrypted_at_rest).into(),
+            ],
+        ))
+        .await?
+        .iter()
+        .map(|row| {
+            let cold: bool = row.try_get("", "cold")?;
+            Ok((
+                row.try_get("", "object")?,
+                if cold { Tier::Cold } else { Tier::Hot },
+            ))
+        })
+        .collect()
+}
+
+/// Move an object and record its tier on every file sharing it
+async fn move_object(state: &State, object: &str, tier: Tier) -> Result<(), anyhow::Error> {
+    state.storage.move_object(object, tier).await?;
+
+    state
+        .database
+        .execute(Statement::from_sql_and_values(
+            DbBackend::Postgres,
+            r#"UPDATE files SET tier = CAST($1 AS storage_tier) WHERE object = $2"#,
+            vec![StorageTier::from(tier).into(), object.into()],
+        ))
+        .await?;
+
+    // Objects are written private, promoted ones are made readable again
+    if tier == Tier::Hot && is_object_public(&state.database, object).await? {
+        state.storage.set_object_public(object, true).await?;
+    }
+
+    Ok(())
+}
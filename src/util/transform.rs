@@ -0,0 +1,166 @@
+use std::io::Cursor;
+
+use image::{imageops::FilterType, GenericImageView};
+use sea_orm::{
+    ColumnTrait, ConnectionTrait, DatabaseConnection, DbBackend, DbErr, EntityTrait,
+    PaginatorTrait, QueryFilter, Statement,
+};
+
+use crate::{
+    database::entity::{file_transforms, files},
+    models::{FileVisibility, ImageFit, ImageTransformQuery, MAX_TRANSFORM_DIMENSION},
+    state::State,
+    util::{file::encode_image, thumbnail::ThumbnailFormat},
+};
+
+/// Most transformed images cached for a single file, others are encoded on every request
+const MAX_CACHED_TRANSFORMS: usize = 32;
+
+/// Largest image in pixels which will be decoded to be transformed
+pub const MAX_SOURCE_PIXELS: i64 = 50_000_000;
+
+/// Quality used when none was requested
+const DEFAULT_QUALITY: u8 = 80;
+
+/// Validated transformation with every default filled in
+pub struct ImageTransform {
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub fit: ImageFit,
+    pub rotate: u16,
+    pub format: ThumbnailFormat,
+    pub quality: u8,
+}
+
+impl ImageTransform {
+    /// Images are converted to PNG if their own format can't be encoded
+    pub fn new(query: &ImageTransformQuery, mime_type: &str) -> Self {
+        let format = query.format.unwrap_or(match mime_type {
+            "image/jpeg" => ThumbnailFormat::Jpeg,
+            "image/webp" => ThumbnailFormat::Webp,
+            "image/avif" => ThumbnailFormat::Avif,
+            _ => ThumbnailFormat::Png,
+        });
+
+        Self {
+            width: query.w,
+            height: query.h,
+            fit: query.fit,
+            rotate: query.rotate,
+            format,
+            quality: query.quality.unwrap_or(DEFAULT_QUALITY),
+        }
+    }
+
+    /// Storage key the result is cached under, equal transformations of a file share it
+    pub fn name(&self, file_name: &str) -> String {
+        let size = |size: Option<u32>| size.map_or("auto".to_string(), |size| size.to_string());
+
+        format!(
+            "transform/{}/{}x{}-{}-r{}-q{}.{}",
+            file_name,
+            size(self.width),
+            size(self.height),
+            format!("{:?}", self.fit).to_lowercase(),
+            self.rotate,
+            self.quality,
+            self.format.extension()
+        )
+    }
+
+    /// Decode, transform and encode an image
+    pub fn apply(&self, bytes: &[u8]) -> Result<Vec<u8>, anyhow::Error> {
+        let image = image::load_from_memory(bytes)?;
+
+        // Rotated first so the requested size applies to the output
+        let image = match self.rotate {
+            90 => image.rotate90(),
+            180 => image.rotate180(),
+            270 => image.rotate270(),
+            _ => image,
+        };
+
+        // A missing side follows the aspect ratio of the image
+        let (source_width, source_height) = image.dimensions();
+        let (width, height) = match (self.width, self.height) {
+            (Some(width), Some(height)) => (width, height),
+            (Some(width), None) => (width, scale(source_height, width, source_width)),
+            (None, Some(height)) => (scale(source_width, height, source_height), height),
+            (None, None) => (source_width, source_height),
+        };
+
+        let image = if (width, height) == (source_width, source_height) {
+            image
+        } else {
+            match self.fit {
+                ImageFit::Contain => image.resize(width, height, FilterType::Lanczos3),
+                ImageFit::Cover => image.resize_to_fill(width, height, FilterType::Lanczos3),
+                ImageFit::Fill => image.resize_exact(width, height, FilterType::Lanczos3),
+            }
+        };
+
+        encode_image(&image, self.format, self.quality)
+    }
+}
+
+/// Dimensions of an image read from its header without decoding it
+pub fn image_dimensions(bytes: &[u8]) -> Result<(u32, u32), anyhow::Error> {
+    Ok(image::io::Reader::new(Cursor::new(bytes))
+        .with_guessed_format()?
+        .into_dimensions()?)
+}
+
+/// Scale `value` by `to / from`, kept within the output limits
+fn scale(value: u32, to: u32, from: u32) -> u32 {
+    (value as u64 * to as u64 / from.max(1) as u64).clamp(1, MAX_TRANSFORM_DIMENSION as u64) as u32
+}
+
+/// Write a transformed image to the storage provider and record it so it is deleted with the file.
+/// Nothing is written once a file has too many cached transformations.
+pub async fn cache_transform(
+    state: &State,
+    file: &files::Model,
+    name: &str,
+    bytes: Vec<u8>,
+) -> Result<(), anyhow::Error> {
+    let cached = file_transforms::Entity::find()
+        .filter(file_transforms::Column::FileId.eq(file.id.to_owned()))
+        .count(&state.database)
+        .await?;
+
+    if cached >= MAX_CACHED_TRANSFORMS {
+        return Ok(());
+    }
+
+    state.storage.put_object_bytes(name, bytes).await?;
+
+    if FileVisibility::from(file.visibility.clone()).is_direct() {
+        state.storage.set_object_public(name, true).await?;
+    }
+
+    // Concurrent requests for the same transformation write the same object
+    state
+        .database
+        .execute(Statement::from_sql_and_values(
+            DbBackend::Postgres,
+            r#"INSERT INTO file_transforms (file_id, name) VALUES ($1, $2) ON CONFLICT DO NOTHING"#,
+            vec![file.id.to_owned().into(), name.into()],
+        ))
+        .await?;
+
+    Ok(())
+}
+
+/// Storage keys of every cached transformation of a file
+pub async fn transform_names(
+    database: &DatabaseConnection,
+    file_id: &str,
+) -> Result<Vec<String>, DbErr> {
+    Ok(file_transforms::Entity::find()
+        .filter(file_transforms::Column::FileId.eq(file_id))
+        .all(database)
+        .await?
+        .into_iter()
+        .map(|transform| transform.name)
+        .collect())
+}